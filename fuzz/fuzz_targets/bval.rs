@@ -0,0 +1,35 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use torrent::bencode::{self, BValue, Encode, Mode, Options, StrKeys};
+use torrent::bencode::{json, pretty};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = bencode::bval(data);
+    let _ = pretty::dump(data, &pretty::DumpOptions::default());
+    for &mode in &[Mode::Lenient, Mode::Strict] {
+        let bv = match bencode::decode(data, Options::new(mode).with_big_ints()) {
+            Ok(bv) => bv,
+            Err(e) => {
+                assert!(e.offset <= data.len());
+                continue;
+            },
+        };
+        // canonical input re-encodes to itself, anything else to something
+        // that decodes the same
+        let encoded = bv.encode();
+        if mode == Mode::Strict {
+            assert_eq!(encoded, data);
+        }
+        let again = bencode::decode(&encoded, Options::new(Mode::Strict).with_big_ints()).unwrap();
+        assert_eq!(BValue::from(&again), BValue::from(&bv));
+        assert_eq!(bv.span(data), Some(0..data.len()));
+
+        let value = BValue::from(&bv);
+        assert_eq!(json::from_json(&json::to_json(&bv)).unwrap(), value);
+        let _ = pretty::pretty(&bv);
+        if let Ok(d) = bv.as_bdict_ref() {
+            let _ = (d.duplicates(), d.get_str("info"), bv.at("info.files[0].length"));
+        }
+    }
+});
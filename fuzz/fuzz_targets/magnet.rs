@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use torrent::magnet;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(s) = std::str::from_utf8(data) {
+        if let Ok(m) = magnet::parse(s) {
+            // whatever parses must survive being written back out
+            assert_eq!(magnet::parse(&m.to_uri()), Ok(m));
+        }
+    }
+});
@@ -0,0 +1,21 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use torrent::bencode::Mode;
+use torrent::metainfo;
+
+fuzz_target!(|data: &[u8]| {
+    for &mode in &[Mode::Lenient, Mode::Strict] {
+        if let Ok(m) = metainfo::parse_with(data, mode.into()) {
+            assert!(m.total_size() >= 0);
+            let _ = m.tiers();
+            let _ = m.files();
+            for i in 0..m.info.pieces().len() {
+                if let Some(size) = m.info.piece_size(i) {
+                    let spans = m.info.piece_spans(i).unwrap();
+                    assert_eq!(spans.iter().map(|s| s.length).sum::<u64>(), size);
+                }
+            }
+        }
+    }
+});
@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use torrent::bencode::{self, BValue, Mode, Options};
+use torrent::bencode::stream::Decoder;
+
+// The first byte says where to split the rest, which must make no
+// difference to what comes out.
+fuzz_target!(|data: &[u8]| {
+    let (split, data) = match data.split_first() {
+        Some((&split, data)) => (split as usize, data),
+        None => return,
+    };
+    let opts = Options::new(Mode::Lenient).with_big_ints();
+    let mut d = Decoder::new(opts);
+    for chunk in data.chunks(split.max(1)) {
+        d.push(chunk);
+    }
+    let streamed = d.next_value().and_then(|v| d.finish().map(|_| v));
+    if let Ok(bv) = bencode::decode(data, opts) {
+        assert_eq!(streamed, Ok(Some(BValue::from(&bv))));
+    }
+});
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use torrent::tracker;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = tracker::parse(data);
+});
@@ -0,0 +1,60 @@
+//! proptest strategies for bencode values, shared by the property tests.
+
+use std::collections::BTreeMap;
+use proptest::prelude::*;
+use proptest::collection::{btree_map, vec};
+
+use super::{BValue, Encode};
+
+/// Integers that don't fit in an i64, as the parser's `BBigInt` text.
+pub fn big_int() -> BoxedStrategy<String> {
+    "-?[1-9][0-9]{19,30}".boxed()
+}
+
+/// Bytes that are usually, but not always, valid UTF-8.
+pub fn bytes() -> BoxedStrategy<Vec<u8>> {
+    prop_oneof![
+        "[a-z ./:]{0,12}".prop_map(String::into_bytes),
+        vec(any::<u8>(), 0..24),
+    ].boxed()
+}
+
+pub fn bvalue() -> BoxedStrategy<BValue> {
+    let leaf = prop_oneof![
+        any::<i64>().prop_map(BValue::BInt),
+        big_int().prop_map(BValue::BBigInt),
+        bytes().prop_map(BValue::BString),
+    ];
+    leaf.prop_recursive(4, 64, 8, |inner| prop_oneof![
+        vec(inner.clone(), 0..8).prop_map(BValue::BList),
+        btree_map(bytes(), inner, 0..8).prop_map(BValue::BDict),
+    ]).boxed()
+}
+
+/// Encoded values with a few bytes changed, cut short or tacked on: mostly
+/// broken, but close enough to valid to get deep into the parser.
+pub fn mangled() -> BoxedStrategy<Vec<u8>> {
+    (bvalue(), vec((any::<prop::sample::Index>(), any::<u8>()), 0..4), any::<prop::sample::Index>(), 0..3u8)
+        .prop_map(|(bv, edits, cut, how)| {
+            let mut bs = bv.encode();
+            if bs.is_empty() {
+                return bs;
+            }
+            for (at, b) in edits {
+                let i = at.index(bs.len());
+                bs[i] = b;
+            }
+            match how {
+                0 => bs.truncate(cut.index(bs.len())),
+                1 => bs.extend_from_slice(b"e"),
+                _ => (),
+            }
+            bs
+        })
+        .boxed()
+}
+
+/// A dict from `(key, value)` pairs, for building documents by hand.
+pub fn dict(pairs: Vec<(&str, BValue)>) -> BValue {
+    BValue::BDict(pairs.into_iter().map(|(k, v)| (k.as_bytes().to_vec(), v)).collect::<BTreeMap<_, _>>())
+}
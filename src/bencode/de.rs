@@ -0,0 +1,394 @@
+//! A serde `Deserializer` reading bencode.
+//!
+//! Input is parsed into a `BVal` first, and every `BVal` is itself a
+//! `Deserializer`, so `&str` and `&[u8]` fields (with
+//! `#[serde(with = "serde_bytes")]`) borrow straight from the input buffer.
+//! Integers are range checked by serde when read into narrower types, and
+//! `bool`s are read from `i0e`/`i1e`.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::str;
+use std::str::FromStr;
+use std::error;
+use std::result::Result;
+use std::vec;
+
+use serde::de;
+use serde::de::{Deserialize, Visitor, IntoDeserializer, Unexpected};
+use serde::de::value::BorrowedStrDeserializer;
+
+use super::{BVal, BencodeError, Mode, Options, decode};
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Custom(String),
+    Bencode(BencodeError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Custom(ref msg) => write!(f, "{}", msg),
+            Error::Bencode(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::Custom(msg.to_string())
+    }
+}
+
+pub fn from_bytes<'de, T: Deserialize<'de>>(input: &'de [u8]) -> Result<T, Error> {
+    decode(input, Options::new(Mode::Lenient).with_big_ints())
+        .map_err(Error::Bencode)
+        .and_then(from_bval)
+}
+
+pub fn from_bval<'de, T: Deserialize<'de>>(bv: BVal<'de>) -> Result<T, Error> {
+    T::deserialize(bv)
+}
+
+impl <'de> BVal<'de> {
+    fn unexpected(&self) -> Unexpected {
+        match *self {
+            BVal::BString(_, bs) => Unexpected::Bytes(bs),
+            BVal::BInt(_, i) => Unexpected::Signed(i),
+            BVal::BBigInt(..) => Unexpected::Other("big integer"),
+            BVal::BList(..) => Unexpected::Seq,
+            BVal::BDict(..) => Unexpected::Map,
+        }
+    }
+}
+
+impl <'de> de::Deserializer<'de> for BVal<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            BVal::BString(_, bs) => visitor.visit_borrowed_bytes(bs),
+            BVal::BInt(_, i) => visitor.visit_i64(i),
+            // a u64 or i128 field can still take it
+            BVal::BBigInt(_, s) => match (u64::from_str(&s), i128::from_str(&s)) {
+                (Ok(n), _) => visitor.visit_u64(n),
+                (_, Ok(n)) => visitor.visit_i128(n),
+                _ => match s {
+                    Cow::Borrowed(s) => visitor.visit_borrowed_str(s),
+                    Cow::Owned(s) => visitor.visit_string(s),
+                },
+            },
+            BVal::BList(_, bvs) => visitor.visit_seq(List{ iter: bvs.into_iter() }),
+            BVal::BDict(_, m) => visitor.visit_map(Dict{ iter: m.into_unique().into_iter(), value: None }),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            BVal::BInt(_, 0) => visitor.visit_bool(false),
+            BVal::BInt(_, 1) => visitor.visit_bool(true),
+            _ => Err(de::Error::invalid_value(self.unexpected(), &"0 or 1")),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            BVal::BString(_, bs) => match str::from_utf8(bs) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(_) => Err(de::Error::invalid_value(Unexpected::Bytes(bs), &visitor)),
+            },
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        // absent keys are handled by serde; anything present is `Some`
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V)
+        -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str,
+                                         _variants: &'static [&'static str], visitor: V)
+        -> Result<V::Value, Error> {
+        match self {
+            BVal::BString(_, bs) => match str::from_utf8(bs) {
+                Ok(s) => visitor.visit_enum(BorrowedStrDeserializer::new(s)),
+                Err(_) => Err(de::Error::invalid_value(Unexpected::Bytes(bs), &"a variant name")),
+            },
+            BVal::BDict(_, m) => {
+                if m.len() != 1 {
+                    return Err(de::Error::invalid_length(m.len(), &"a dict with one variant key"));
+                }
+                let (variant, value) = m.into_iter().next().unwrap();
+                visitor.visit_enum(Enum{ variant: variant, value: value })
+            },
+            _ => Err(de::Error::invalid_type(self.unexpected(), &"a string or dict")),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct ignored_any
+    }
+}
+
+impl <'de> IntoDeserializer<'de, Error> for BVal<'de> {
+    type Deserializer = BVal<'de>;
+
+    fn into_deserializer(self) -> BVal<'de> {
+        self
+    }
+}
+
+struct List<'de> {
+    iter: vec::IntoIter<BVal<'de>>,
+}
+
+impl <'de> de::SeqAccess<'de> for List<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T)
+        -> Result<Option<T::Value>, Error> {
+        match self.iter.next() {
+            Some(bv) => seed.deserialize(bv).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct Dict<'de> {
+    iter: vec::IntoIter<(&'de [u8], BVal<'de>)>,
+    value: Option<BVal<'de>>,
+}
+
+impl <'de> de::MapAccess<'de> for Dict<'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K)
+        -> Result<Option<K::Value>, Error> {
+        match self.iter.next() {
+            Some((k, v)) => {
+                self.value = Some(v);
+                seed.deserialize(BVal::BString(&b""[..], k)).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V)
+        -> Result<V::Value, Error> {
+        let v = self.value.take().expect("next_value_seed called before next_key_seed");
+        seed.deserialize(v)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+/// An externally tagged `{variant: value}` dict.
+struct Enum<'de> {
+    variant: &'de [u8],
+    value: BVal<'de>,
+}
+
+impl <'de> de::EnumAccess<'de> for Enum<'de> {
+    type Error = Error;
+    type Variant = BVal<'de>;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V)
+        -> Result<(V::Value, BVal<'de>), Error> {
+        let variant = seed.deserialize(BVal::BString(&b""[..], self.variant))?;
+        Ok((variant, self.value))
+    }
+}
+
+impl <'de> de::VariantAccess<'de> for BVal<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Err(de::Error::invalid_type(self.unexpected(), &"a unit variant name"))
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V)
+        -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::ParseError;
+    use bencode::ser::to_bytes;
+    use serde_bytes;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct File<'a> {
+        length: i64,
+        #[serde(borrow)]
+        path: Vec<&'a str>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Info<'a> {
+        name: &'a str,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        #[serde(with = "serde_bytes")]
+        pieces: &'a [u8],
+        #[serde(borrow)]
+        files: Vec<File<'a>>,
+        private: Option<bool>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Event {
+        Started,
+        Stopped,
+        Progress(i64),
+        Moved{ from: String, to: String },
+    }
+
+    #[test]
+    fn derive_round_trip() {
+        let pieces = [7u8; 40];
+        let info = Info{
+            name: "dir",
+            piece_length: 16384,
+            pieces: &pieces[..],
+            files: vec![File{ length: 3, path: vec!["a", "b.txt"] }],
+            private: None,
+        };
+
+        let bytes = to_bytes(&info).unwrap();
+        assert!(bytes.starts_with(b"d5:filesld6:lengthi3e4:pathl1:a5:b.txteee4:name3:dir12:piece lengthi16384e6:pieces40:"));
+
+        let back: Info = from_bytes(&bytes[..]).unwrap();
+        assert_eq!(back, info);
+        // zero-copy: the borrowed fields point into `bytes`
+        let range = bytes.as_ptr() as usize..bytes.as_ptr() as usize + bytes.len();
+        assert!(range.contains(&(back.pieces.as_ptr() as usize)));
+        assert!(range.contains(&(back.name.as_ptr() as usize)));
+    }
+
+    #[test]
+    fn enums() {
+        let events = vec![
+            Event::Started,
+            Event::Progress(3),
+            Event::Moved{ from: "a".to_string(), to: "b".to_string() },
+            Event::Stopped,
+        ];
+        let bytes = to_bytes(&events).unwrap();
+        assert_eq!(&bytes[..], &b"l7:Startedd8:Progressi3eed5:Movedd4:from1:a2:to1:bee7:Stoppede"[..]);
+        assert_eq!(from_bytes::<Vec<Event>>(&bytes[..]).unwrap(), events);
+    }
+
+    #[test]
+    fn maps_and_options() {
+        let mut m: BTreeMap<String, Option<i32>> = BTreeMap::new();
+        m.insert("b".to_string(), Some(2));
+        m.insert("a".to_string(), None);
+        assert_eq!(&to_bytes(&m).unwrap()[..], &b"d1:bi2ee"[..]);
+        assert_eq!(from_bytes::<BTreeMap<String, bool>>(b"d1:xi1ee").unwrap().get("x"), Some(&true));
+    }
+
+    #[test]
+    fn none_outside_dicts() {
+        #[derive(Serialize)]
+        enum Wrap {
+            Value(Option<i32>),
+        }
+        assert_eq!(&to_bytes(&vec![Some(1), Some(2)]).unwrap()[..], &b"li1ei2ee"[..]);
+        assert!(to_bytes(&vec![Some(1), None]).is_err());
+        assert_eq!(&to_bytes(&Wrap::Value(Some(1))).unwrap()[..], &b"d5:Valuei1ee"[..]);
+        assert_eq!(to_bytes(&Wrap::Value(None)).err().map(|e| e.to_string()),
+                   Some("bencode can't represent None outside a dict".to_string()));
+        assert!(to_bytes(&None::<i32>).is_err());
+        // only the field itself can be left out
+        let mut m: BTreeMap<&str, Vec<Option<i32>>> = BTreeMap::new();
+        m.insert("a", vec![None]);
+        assert!(to_bytes(&m).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        match from_bytes::<i64>(b"i1ee") {
+            Err(Error::Bencode(e)) => assert_eq!((e.kind, e.offset), (ParseError::TrailingBytes, 3)),
+            other => panic!("unexpected {:?}", other),
+        }
+        match from_bytes::<i64>(b"i1") {
+            Err(Error::Bencode(e)) => assert_eq!(e.kind, ParseError::UnexpectedEof),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(from_bytes::<u8>(b"i256e").is_err());
+        assert!(from_bytes::<u64>(b"i18446744073709551616e").is_err());
+        assert!(from_bytes::<String>(b"2:\xff\xfe").is_err());
+        assert!(to_bytes(&1.5f64).is_err());
+    }
+
+    #[test]
+    fn big_ints() {
+        assert_eq!(from_bytes::<u64>(b"i18446744073709551615e").unwrap(), u64::max_value());
+        assert_eq!(from_bytes::<i128>(b"i-18446744073709551616e").unwrap(), -18446744073709551616);
+        assert_eq!(to_bytes(&u64::max_value()).err().map(|e| e.to_string()),
+                   Some("18446744073709551615 doesn't fit in a bencode integer".to_string()));
+    }
+
+    #[test]
+    fn sample_torrent() {
+        #[derive(Deserialize)]
+        struct Metainfo<'a> {
+            announce: &'a str,
+            #[serde(borrow)]
+            info: SingleInfo<'a>,
+        }
+
+        #[derive(Deserialize)]
+        struct SingleInfo<'a> {
+            name: &'a str,
+            length: i64,
+            #[serde(rename = "piece length")]
+            piece_length: i64,
+            #[serde(with = "serde_bytes")]
+            pieces: &'a [u8],
+        }
+
+        let bs = include_bytes!("../../sample.mp4.torrent");
+        let mi: Metainfo = from_bytes(&bs[..]).unwrap();
+        assert_eq!(mi.announce, "http://v4.torrent.speedpartner.de:6969/announce");
+        assert_eq!(mi.info.name, "32c3-7570-en-de-Plunge_into_Proxy_Politics_hd.mp4");
+        assert_eq!(mi.info.length, 612369367);
+        assert_eq!(mi.info.piece_length, 20971520);
+        assert_eq!(mi.info.pieces.len() % 20, 0);
+    }
+}
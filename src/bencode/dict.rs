@@ -0,0 +1,204 @@
+//! Dicts that keep exactly what the input said.
+//!
+//! A `Dict` holds its entries in input order, duplicate keys and all, so a
+//! malformed file can be inspected or re-emitted as it really is. Lookups see
+//! the last entry for a key, as the lenient parser always has.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::Write;
+use std::iter::FromIterator;
+use std::slice;
+use std::vec;
+
+use super::{BVal, Encode, StrKeys, write_bdict};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Dict<'a> {
+    entries: Vec<(&'a [u8], BVal<'a>)>,
+    // where the last entry for each key is
+    index: HashMap<&'a [u8], usize>,
+}
+
+impl <'a> Dict<'a> {
+    pub fn new() -> Dict<'a> {
+        Dict{ entries: Vec::new(), index: HashMap::new() }
+    }
+
+    /// Adds an entry at the end, even if `key` is already there.
+    pub fn push(&mut self, key: &'a [u8], value: BVal<'a>) {
+        self.index.insert(key, self.entries.len());
+        self.entries.push((key, value));
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&BVal<'a>> {
+        self.index.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    /// Every value for `key`, in input order.
+    pub fn get_all(&self, key: &[u8]) -> Vec<&BVal<'a>> {
+        self.entries.iter().filter(|e| e.0 == key).map(|e| &e.1).collect()
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// The number of entries, counting duplicates.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// All entries in input order, duplicates included.
+    pub fn iter<'d>(&'d self) -> Iter<'d, 'a> {
+        Iter{ iter: self.entries.iter() }
+    }
+
+    pub fn keys(&self) -> Vec<&'a [u8]> {
+        self.entries.iter().map(|e| e.0).collect()
+    }
+
+    /// Keys that appear more than once, in the order they were repeated.
+    pub fn duplicates(&self) -> Vec<&'a [u8]> {
+        let mut seen = HashSet::new();
+        let mut dups = Vec::new();
+        for &(k, _) in &self.entries {
+            if !seen.insert(k) && !dups.contains(&k) {
+                dups.push(k);
+            }
+        }
+        dups
+    }
+
+    /// Just the entries lookups see, the last one for each key, in input order.
+    pub fn unique<'d>(&'d self) -> Vec<(&'a [u8], &'d BVal<'a>)> {
+        self.iter().enumerate()
+            .filter(|&(i, (k, _))| self.index[k] == i)
+            .map(|(_, e)| e)
+            .collect()
+    }
+
+    /// Drops all but the last entry for each key.
+    pub fn into_unique(self) -> Dict<'a> {
+        let index = self.index;
+        self.entries.into_iter().enumerate()
+            .filter(|&(i, (k, _))| index[k] == i)
+            .map(|(_, e)| e)
+            .collect()
+    }
+}
+
+pub struct Iter<'d, 'a: 'd> {
+    iter: slice::Iter<'d, (&'a [u8], BVal<'a>)>,
+}
+
+impl <'d, 'a> Iterator for Iter<'d, 'a> {
+    type Item = (&'a [u8], &'d BVal<'a>);
+
+    fn next(&mut self) -> Option<(&'a [u8], &'d BVal<'a>)> {
+        self.iter.next().map(|e| (e.0, &e.1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl <'d, 'a> ExactSizeIterator for Iter<'d, 'a> {}
+
+impl <'d, 'a> IntoIterator for &'d Dict<'a> {
+    type Item = (&'a [u8], &'d BVal<'a>);
+    type IntoIter = Iter<'d, 'a>;
+
+    fn into_iter(self) -> Iter<'d, 'a> {
+        self.iter()
+    }
+}
+
+impl <'a> IntoIterator for Dict<'a> {
+    type Item = (&'a [u8], BVal<'a>);
+    type IntoIter = vec::IntoIter<(&'a [u8], BVal<'a>)>;
+
+    fn into_iter(self) -> vec::IntoIter<(&'a [u8], BVal<'a>)> {
+        self.entries.into_iter()
+    }
+}
+
+impl <'a> FromIterator<(&'a [u8], BVal<'a>)> for Dict<'a> {
+    fn from_iter<I: IntoIterator<Item=(&'a [u8], BVal<'a>)>>(iter: I) -> Dict<'a> {
+        let mut dict = Dict::new();
+        for (k, v) in iter {
+            dict.push(k, v);
+        }
+        dict
+    }
+}
+
+impl <'a> StrKeys<BVal<'a>> for Dict<'a> {
+    fn get_str(&self, key: &str) -> Option<&BVal<'a>> {
+        self.get(key.as_bytes())
+    }
+}
+
+/// Encodes canonically: sorted, and only the entries lookups see. Use the
+/// dict's raw input to re-emit it as it was.
+impl <'a> Encode for Dict<'a> {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_bdict(w, self.unique())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::{BVal, Encode, StrKeys, bval};
+    use nom::IResult;
+
+    fn parse_dict(input: &[u8]) -> Dict {
+        match bval(input) {
+            IResult::Done(_, bv) => bv.as_bdict().unwrap(),
+            _ => panic!("should parse"),
+        }
+    }
+
+    #[test]
+    fn keeps_input_order() {
+        let d = parse_dict(b"d1:zi1e1:ai2e1:mi3ee");
+        assert_eq!(d.keys(), vec![&b"z"[..], &b"a"[..], &b"m"[..]]);
+        assert!(d.duplicates().is_empty());
+        assert_eq!(d.get_str("a").and_then(|v| v.as_bint().ok()), Some(2));
+    }
+
+    #[test]
+    fn keeps_duplicates() {
+        let input = &b"d1:ai1e1:bi2e1:ai3e1:bi4e1:ai5ee"[..];
+        let d = parse_dict(input);
+        assert_eq!(d.len(), 5);
+        assert_eq!(d.duplicates(), vec![&b"a"[..], &b"b"[..]]);
+        let ints = |vs: Vec<&BVal>| vs.iter().map(|v| v.as_bint().unwrap()).collect::<Vec<_>>();
+        assert_eq!(ints(d.get_all(b"a")), vec![1, 3, 5]);
+        // lookups see the last one
+        assert_eq!(d.get(b"a").unwrap().as_bint(), Ok(5));
+        assert_eq!(d.get(b"b").unwrap().as_bint(), Ok(4));
+        let unique: Vec<&[u8]> = d.unique().iter().map(|e| e.0).collect();
+        assert_eq!(unique, vec![&b"b"[..], &b"a"[..]]);
+        assert_eq!(d.into_unique().len(), 2);
+    }
+
+    #[test]
+    fn re_emit() {
+        let input = &b"d1:bi1e1:ai2e1:bi3ee"[..];
+        match bval(input) {
+            IResult::Done(_, bv) => {
+                // as it was, or canonically
+                assert_eq!(bv.raw(), input);
+                assert_eq!(bv.encode(), b"d1:ai2e1:bi3ee".to_vec());
+            },
+            _ => panic!("should parse"),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::io;
+use std::io::Write;
+use std::collections::{BTreeMap, HashMap};
+
+use super::BVal;
+
+/// Anything that can be written out as bencode.
+///
+/// Dictionaries are always emitted with their keys sorted by raw bytes, so
+/// encoding a value parsed from a canonical file gives back the same bytes.
+pub trait Encode {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()>;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_to(&mut out).expect("writing to a Vec never fails");
+        out
+    }
+}
+
+pub fn write_bstring<W: Write>(w: &mut W, bs: &[u8]) -> io::Result<()> {
+    write!(w, "{}:", bs.len())?;
+    w.write_all(bs)
+}
+
+pub fn write_bint<W: Write>(w: &mut W, i: i64) -> io::Result<()> {
+    write!(w, "i{}e", i)
+}
+
+pub fn write_blist<'b, W, T, I>(w: &mut W, items: I) -> io::Result<()>
+    where W: Write, T: Encode + ?Sized + 'b, I: IntoIterator<Item=&'b T>
+{
+    w.write_all(b"l")?;
+    for item in items {
+        item.encode_to(w)?;
+    }
+    w.write_all(b"e")
+}
+
+/// Writes a dictionary, sorting `pairs` by key first.
+pub fn write_bdict<'b, W, T, I>(w: &mut W, pairs: I) -> io::Result<()>
+    where W: Write, T: Encode + ?Sized + 'b, I: IntoIterator<Item=(&'b [u8], &'b T)>
+{
+    let mut sorted: Vec<(&[u8], &T)> = pairs.into_iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(b.0));
+
+    w.write_all(b"d")?;
+    for (k, v) in sorted {
+        write_bstring(w, k)?;
+        v.encode_to(w)?;
+    }
+    w.write_all(b"e")
+}
+
+impl <'a> Encode for BVal<'a> {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match *self {
+            BVal::BString(_, bs) => write_bstring(w, bs),
+            BVal::BInt(_, i) => write_bint(w, i),
+            BVal::BBigInt(_, ref s) => write!(w, "i{}e", s),
+            BVal::BList(_, ref bvs) => write_blist(w, bvs),
+            BVal::BDict(_, ref m) => m.encode_to(w),
+        }
+    }
+}
+
+impl Encode for [u8] {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_bstring(w, self)
+    }
+}
+
+impl Encode for str {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_bstring(w, self.as_bytes())
+    }
+}
+
+impl Encode for String {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_bstring(w, self.as_bytes())
+    }
+}
+
+impl Encode for i64 {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_bint(w, *self)
+    }
+}
+
+impl <T: Encode> Encode for Vec<T> {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_blist(w, self)
+    }
+}
+
+impl <K: AsRef<[u8]>, V: Encode> Encode for BTreeMap<K, V> {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_bdict(w, self.iter().map(|(k, v)| (k.as_ref(), v)))
+    }
+}
+
+impl <K: AsRef<[u8]> + Eq + ::std::hash::Hash, V: Encode> Encode for HashMap<K, V> {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_bdict(w, self.iter().map(|(k, v)| (k.as_ref(), v)))
+    }
+}
+
+impl <'b, T: Encode + ?Sized> Encode for &'b T {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (**self).encode_to(w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::{BVal, bval};
+    use nom::IResult;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn encode_scalars() {
+        assert_eq!(BVal::BString(&b""[..], &b"abcde"[..]).encode(), b"5:abcde".to_vec());
+        assert_eq!(BVal::BString(&b""[..], &b""[..]).encode(), b"0:".to_vec());
+        assert_eq!(BVal::BInt(&b""[..], 53).encode(), b"i53e".to_vec());
+        assert_eq!(BVal::BInt(&b""[..], -13).encode(), b"i-13e".to_vec());
+        assert_eq!(BVal::BInt(&b""[..], 0).encode(), b"i0e".to_vec());
+    }
+
+    #[test]
+    fn encode_list() {
+        let bv = BVal::BList(&b""[..], vec![BVal::BInt(&b""[..], 53), BVal::BString(&b""[..], &b"x"[..])]);
+        assert_eq!(bv.encode(), b"li53e1:xe".to_vec());
+    }
+
+    #[test]
+    fn encode_dict_sorts_keys() {
+        let bv = BVal::BDict(
+            &b""[..],
+            vec![
+                (&b"zz"[..], BVal::BInt(&b""[..], 1)),
+                (&b"a"[..], BVal::BInt(&b""[..], 2)),
+                (&b"ab"[..], BVal::BInt(&b""[..], 3)),
+            ].into_iter().collect()
+        );
+        assert_eq!(bv.encode(), b"d1:ai2e2:abi3e2:zzi1ee".to_vec());
+    }
+
+    #[test]
+    fn encode_native_types() {
+        let mut m: BTreeMap<&str, Vec<i64>> = BTreeMap::new();
+        m.insert("interval", vec![1800]);
+        m.insert("complete", vec![]);
+        assert_eq!(m.encode(), b"d8:completele8:intervalli1800eee".to_vec());
+    }
+
+    #[test]
+    fn encode_big_int() {
+        assert_eq!(BVal::BBigInt(&b""[..], "-99999999999999999999".into()).encode(), b"i-99999999999999999999e".to_vec());
+    }
+
+    #[test]
+    fn round_trip_sample_torrent() {
+        let bs = &include_bytes!("../../sample.mp4.torrent")[..];
+        match bval(bs) {
+            IResult::Done(rest, bv) => {
+                assert_eq!(rest, &b""[..]);
+                assert_eq!(bv.encode(), bs.to_vec());
+            },
+            _ => panic!("sample torrent should parse"),
+        }
+    }
+}
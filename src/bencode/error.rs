@@ -0,0 +1,219 @@
+use std::fmt;
+use std::error;
+use std::str;
+use nom::{IResult, Err, ErrorKind};
+
+use super::{ParseError, ParseLimits, Mode, Options, bval_with, bstring_bytes};
+
+/// One step from a container to a value inside it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+    Key(Vec<u8>),
+    Index(usize),
+}
+
+/// Where a value sits in a document, displayed like `info.files[3].path[1]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path(pub Vec<PathSegment>);
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let &Path(ref segments) = self;
+        for (i, segment) in segments.iter().enumerate() {
+            match *segment {
+                PathSegment::Key(ref k) => {
+                    if i > 0 {
+                        write!(f, ".")?;
+                    }
+                    match str::from_utf8(k) {
+                        Ok(s) => write!(f, "{}", s)?,
+                        Err(_) => {
+                            write!(f, "0x")?;
+                            for b in k {
+                                write!(f, "{:02x}", b)?;
+                            }
+                        },
+                    }
+                },
+                PathSegment::Index(n) => write!(f, "[{}]", n)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A parse failure located in the input: the byte offset it happened at,
+/// what was expected there, and the path of the value being parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BencodeError {
+    pub offset: usize,
+    pub kind: ParseError,
+    pub path: Path,
+}
+
+impl BencodeError {
+    pub fn at(input: &[u8], offset: usize, kind: ParseError) -> BencodeError {
+        BencodeError{ offset: offset, kind: kind, path: path_to(input, offset) }
+    }
+
+    /// Locates the innermost positioned error in a nom error chain.
+    pub fn from_err(input: &[u8], err: &Err<&[u8]>) -> BencodeError {
+        let mut err = err;
+        let mut found = (ErrorKind::Custom(ParseError::ExpectedValue as u32), input);
+        loop {
+            match *err {
+                Err::Code(_) => break,
+                Err::Node(_, ref next) => err = next,
+                Err::Position(ref k, p) => { found = (k.clone(), p); break },
+                Err::NodePosition(ref k, p, ref next) => { found = (k.clone(), p); err = next },
+            }
+        }
+        let kind = match found.0 {
+            ErrorKind::Custom(code) => ParseError::from_code(code).unwrap_or(ParseError::ExpectedValue),
+            _ => ParseError::ExpectedValue,
+        };
+        BencodeError::at(input, input.len() - found.1.len(), kind)
+    }
+
+    pub fn expected(&self) -> &'static str {
+        self.kind.expected()
+    }
+}
+
+impl fmt::Display for BencodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected {} at byte {}", self.expected(), self.offset)?;
+        if !self.path.0.is_empty() {
+            write!(f, " in {}", self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for BencodeError {}
+
+/// Works out the path of the innermost value at `offset`.
+///
+/// Everything before a parse error is well formed, so this walks down from
+/// the root, skipping siblings that end before `offset`, until it reaches a
+/// scalar or the spot where the container itself is broken.
+pub fn path_to(input: &[u8], offset: usize) -> Path {
+    let mut path = Vec::new();
+    let mut pos = 0;
+    loop {
+        let next = match input.get(pos) {
+            Some(&b'l') => list_child(input, offset, pos + 1),
+            Some(&b'd') => dict_child(input, offset, pos + 1, &mut path),
+            _ => None,
+        };
+        match next {
+            Some((segment, child_pos)) => {
+                path.push(segment);
+                pos = child_pos;
+            },
+            None => return Path(path),
+        }
+    }
+}
+
+// As permissive as possible, so nothing the real parse accepted looks broken,
+// but still bounded in depth since this recurses too.
+fn walk_options() -> Options {
+    let limits = ParseLimits{ max_depth: ParseLimits::default().max_depth, ..ParseLimits::none() };
+    Options::new(Mode::Lenient).with_big_ints().with_limits(limits)
+}
+
+// The end of the value at `pos`, or `None` if it doesn't parse.
+fn value_end(input: &[u8], pos: usize) -> Option<usize> {
+    match bval_with(&input[pos..], walk_options()) {
+        IResult::Done(rest, _) => Some(input.len() - rest.len()),
+        _ => None,
+    }
+}
+
+fn list_child(input: &[u8], offset: usize, mut pos: usize) -> Option<(PathSegment, usize)> {
+    let mut index = 0;
+    while pos < input.len() && input[pos] != b'e' && pos <= offset {
+        match value_end(input, pos) {
+            Some(end) if end <= offset => pos = end,
+            _ => return Some((PathSegment::Index(index), pos)),
+        }
+        index += 1;
+    }
+    None
+}
+
+// A failure on a key itself stops at the dict, naming the key if it parsed.
+fn dict_child(input: &[u8], offset: usize, mut pos: usize, path: &mut Vec<PathSegment>)
+    -> Option<(PathSegment, usize)> {
+    while pos < input.len() && input[pos] != b'e' && pos <= offset {
+        let (key, val_pos) = match bstring_bytes(&input[pos..], walk_options()) {
+            IResult::Done(rest, key) => (key, input.len() - rest.len()),
+            _ => return None,
+        };
+        if offset < val_pos {
+            path.push(PathSegment::Key(key.to_vec()));
+            return None;
+        }
+        match value_end(input, val_pos) {
+            Some(end) if end <= offset => pos = end,
+            _ => return Some((PathSegment::Key(key.to_vec()), val_pos)),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::{ParseError, Mode, Options, decode};
+
+    fn err(input: &[u8], mode: Mode) -> BencodeError {
+        decode(input, Options::new(mode)).err().expect("should fail")
+    }
+
+    #[test]
+    fn locates_nested_failure() {
+        let input = &b"d4:infod5:filesld6:lengthi1e4:pathl1:aeed6:lengthi2e4:pathl1:bxeeeee"[..];
+        let e = err(input, Mode::Lenient);
+        assert_eq!(e.kind, ParseError::ExpectedValue);
+        assert_eq!(e.offset, 62);
+        assert_eq!(e.path.to_string(), "info.files[1].path[1]");
+        assert_eq!(e.to_string(), "expected 'i', 'l', 'd' or a digit at byte 62 in info.files[1].path[1]");
+    }
+
+    #[test]
+    fn locates_strict_failures() {
+        let e = err(&b"d1:ali1ei02eee"[..], Mode::Strict);
+        assert_eq!((e.kind, e.offset), (ParseError::LeadingZero, 9));
+        assert_eq!(e.path.to_string(), "a[1]");
+
+        let e = err(&b"d1:bi1e1:ai2ee"[..], Mode::Strict);
+        assert_eq!((e.kind, e.offset), (ParseError::UnsortedKey, 7));
+        assert_eq!(e.path.to_string(), "a");
+    }
+
+    #[test]
+    fn eof_and_trailing() {
+        let e = err(&b"d1:al4:spam"[..], Mode::Lenient);
+        assert_eq!((e.kind, e.offset), (ParseError::UnexpectedEof, 11));
+        assert_eq!(e.path.to_string(), "a");
+
+        let e = err(&b"i1ei2e"[..], Mode::Lenient);
+        assert_eq!((e.kind, e.offset), (ParseError::TrailingBytes, 3));
+        assert_eq!(e.path, Path(vec![]));
+    }
+
+    #[test]
+    fn expected_tokens() {
+        assert_eq!(err(&b"3;abc"[..], Mode::Lenient).expected(), "':'");
+        assert_eq!(err(&b"i12x"[..], Mode::Lenient).expected(), "'e'");
+        assert_eq!(err(&b"di1ei2ee"[..], Mode::Lenient).kind, ParseError::ExpectedKey);
+    }
+
+    #[test]
+    fn binary_key_display() {
+        let path = Path(vec![PathSegment::Key(vec![0xff, 0x01]), PathSegment::Index(0)]);
+        assert_eq!(path.to_string(), "0xff01[0]");
+    }
+}
@@ -0,0 +1,258 @@
+//! Converts bencode to JSON and back without losing anything.
+//!
+//! Strings that are valid UTF-8 become JSON strings. Anything else needs an
+//! escape, written as a one-key object:
+//!
+//! * `{"$hex": "ff00"}` for a binary string, like `pieces` or compact `peers`
+//! * `{"$int": "123456789012345678901234"}` for a `BBigInt`
+//!
+//! Dict keys that aren't UTF-8 become `"$hex:ff00"`. So that none of this is
+//! ambiguous, a real dict key starting with `$` gets an extra `$` in front.
+
+use std::fmt;
+use std::error;
+use std::str;
+use std::collections::BTreeMap;
+use serde_json;
+use serde_json::{Value, Map};
+
+use super::{BVal, BValue, Path, PathSegment, to_hex, from_hex};
+
+static HEX_KEY: &'static str = "$hex";
+static INT_KEY: &'static str = "$int";
+static HEX_PREFIX: &'static str = "$hex:";
+
+#[derive(Debug)]
+pub enum JsonError {
+    Syntax(serde_json::Error),
+    /// A JSON value with no bencode equivalent: `null`, a bool or a float.
+    Unrepresentable{ found: &'static str, path: Path },
+    /// A `$hex` or `$int` escape that doesn't hold what it should.
+    BadEscape{ path: Path },
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JsonError::Syntax(ref e) => write!(f, "{}", e),
+            JsonError::Unrepresentable{ found, ref path } =>
+                write!(f, "{} at {} can't be bencoded", found, path),
+            JsonError::BadEscape{ ref path } => write!(f, "bad escape at {}", path),
+        }
+    }
+}
+
+impl error::Error for JsonError {}
+
+pub fn to_json(bv: &BVal) -> Value {
+    match *bv {
+        BVal::BString(_, bs) => match bv.as_bstring_str() {
+            Ok(s) => Value::String(s.to_string()),
+            Err(_) => escape(HEX_KEY, to_hex(bs)),
+        },
+        BVal::BInt(_, i) => Value::from(i),
+        BVal::BBigInt(_, ref s) => escape(INT_KEY, s.to_string()),
+        BVal::BList(_, ref bvs) => Value::Array(bvs.iter().map(to_json).collect()),
+        BVal::BDict(_, ref m) => {
+            let mut obj = Map::new();
+            for (k, v) in m {
+                obj.insert(key_to_json(k), to_json(v));
+            }
+            Value::Object(obj)
+        },
+    }
+}
+
+pub fn to_json_string(bv: &BVal) -> String {
+    serde_json::to_string_pretty(&to_json(bv)).expect("a Value always serializes")
+}
+
+pub fn from_json(v: &Value) -> Result<BValue, JsonError> {
+    from_json_at(v, &mut Vec::new())
+}
+
+pub fn from_json_str(s: &str) -> Result<BValue, JsonError> {
+    serde_json::from_str(s)
+        .or_else(|e| Err(JsonError::Syntax(e)))
+        .and_then(|v| from_json(&v))
+}
+
+fn escape(key: &str, text: String) -> Value {
+    let mut obj = Map::new();
+    obj.insert(key.to_string(), Value::String(text));
+    Value::Object(obj)
+}
+
+fn key_to_json(k: &[u8]) -> String {
+    match str::from_utf8(k) {
+        Ok(s) if s.starts_with('$') => format!("${}", s),
+        Ok(s) => s.to_string(),
+        Err(_) => format!("{}{}", HEX_PREFIX, to_hex(k)),
+    }
+}
+
+fn key_from_json(k: &str) -> Option<Vec<u8>> {
+    if k.starts_with("$$") {
+        Some(k[1..].as_bytes().to_vec())
+    } else if k.starts_with(HEX_PREFIX) {
+        from_hex(&k[HEX_PREFIX.len()..])
+    } else if k.starts_with('$') {
+        None
+    } else {
+        Some(k.as_bytes().to_vec())
+    }
+}
+
+fn from_json_at(v: &Value, path: &mut Vec<PathSegment>) -> Result<BValue, JsonError> {
+    let unrepresentable = |found, path: &Vec<PathSegment>| {
+        Err(JsonError::Unrepresentable{ found: found, path: Path(path.clone()) })
+    };
+    match *v {
+        Value::Null => unrepresentable("null", path),
+        Value::Bool(_) => unrepresentable("a bool", path),
+        Value::Number(ref n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => Ok(BValue::BInt(i)),
+            (None, Some(u)) => Ok(BValue::BBigInt(u.to_string())),
+            _ => unrepresentable("a float", path),
+        },
+        Value::String(ref s) => Ok(BValue::BString(s.as_bytes().to_vec())),
+        Value::Array(ref vs) => {
+            let mut bvs = Vec::new();
+            for (i, v) in vs.iter().enumerate() {
+                path.push(PathSegment::Index(i));
+                bvs.push(from_json_at(v, path)?);
+                path.pop();
+            }
+            Ok(BValue::BList(bvs))
+        },
+        Value::Object(ref obj) => {
+            if let Some(escaped) = unescape(obj) {
+                return escaped.ok_or_else(|| JsonError::BadEscape{ path: Path(path.clone()) });
+            }
+            let mut m = BTreeMap::new();
+            for (k, v) in obj {
+                let key = match key_from_json(k) {
+                    Some(key) => key,
+                    None => {
+                        path.push(PathSegment::Key(k.as_bytes().to_vec()));
+                        return Err(JsonError::BadEscape{ path: Path(path.clone()) });
+                    },
+                };
+                path.push(PathSegment::Key(key.clone()));
+                let bv = from_json_at(v, path)?;
+                path.pop();
+                m.insert(key, bv);
+            }
+            Ok(BValue::BDict(m))
+        },
+    }
+}
+
+// `None` if this isn't an escape at all, `Some(None)` if it's a broken one.
+fn unescape(obj: &Map<String, Value>) -> Option<Option<BValue>> {
+    if obj.len() != 1 {
+        return None;
+    }
+    let text = |key: &str| obj.get(key).map(|v| v.as_str());
+    if let Some(hex) = text(HEX_KEY) {
+        return Some(hex.and_then(from_hex).map(BValue::BString));
+    }
+    if let Some(digits) = text(INT_KEY) {
+        return Some(digits.and_then(big_int).map(BValue::BBigInt));
+    }
+    None
+}
+
+// Just the canonical form, as the parser would hand out.
+fn big_int(s: &str) -> Option<String> {
+    let digits = if s.starts_with('-') { &s[1..] } else { s };
+    let canonical = !digits.is_empty()
+        && digits.bytes().all(|b| b.is_ascii_digit())
+        && (digits == "0" || !digits.starts_with('0'))
+        && s != "-0";
+    if canonical { Some(s.to_string()) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::{BVal, BValue, Encode, Mode, Options, arbitrary, bval, decode};
+    use nom::IResult;
+    use proptest::prelude::*;
+
+    fn round_trip(input: &[u8]) -> (Value, BValue) {
+        match bval(input) {
+            IResult::Done(_, bv) => {
+                let json = to_json(&bv);
+                let back = from_json_str(&json.to_string()).unwrap();
+                assert_eq!(back, BValue::from(&bv));
+                (json, back)
+            },
+            _ => panic!("should parse"),
+        }
+    }
+
+    #[test]
+    fn plain_values() {
+        let (json, _) = round_trip(b"d8:intervali1800e5:peersl4:spami-3eee");
+        assert_eq!(json.to_string(), r#"{"interval":1800,"peers":["spam",-3]}"#);
+    }
+
+    #[test]
+    fn binary_strings_and_keys() {
+        let (json, _) = round_trip(b"d2:\xff\x01i1e5:peers6:\x7f\x00\x00\x01\x1a\xe1e");
+        assert_eq!(json.to_string(), r#"{"$hex:ff01":1,"peers":{"$hex":"7f0000011ae1"}}"#);
+    }
+
+    #[test]
+    fn dollar_keys_are_escaped() {
+        let (json, _) = round_trip(b"d4:$$ab1:y4:$hex1:x1:zd4:$hex4:abcdee");
+        assert_eq!(json.to_string(), r#"{"$$$ab":"y","$$hex":"x","z":{"$$hex":"abcd"}}"#);
+    }
+
+    #[test]
+    fn big_ints() {
+        let bv = BVal::BList(&b""[..], vec![BVal::BBigInt(&b""[..], "-123456789012345678901234567890".into())]);
+        let json = to_json(&bv);
+        assert_eq!(json.to_string(), r#"[{"$int":"-123456789012345678901234567890"}]"#);
+        assert_eq!(from_json(&json).unwrap(), BValue::from(&bv));
+        assert_eq!(from_json_str("18446744073709551615").unwrap(), BValue::BBigInt("18446744073709551615".to_string()));
+    }
+
+    #[test]
+    fn sample_torrent() {
+        let bs = &include_bytes!("../../sample.mp4.torrent")[..];
+        let (json, _) = round_trip(bs);
+        assert!(json["info"]["pieces"]["$hex"].is_string());
+        assert_eq!(json["announce"], Value::from("http://v4.torrent.speedpartner.de:6969/announce"));
+    }
+
+    #[test]
+    fn errors() {
+        match from_json_str(r#"{"a": [1, true]}"#) {
+            Err(JsonError::Unrepresentable{ found, path }) => {
+                assert_eq!(found, "a bool");
+                assert_eq!(path.to_string(), "a[1]");
+            },
+            other => panic!("expected unrepresentable, got {:?}", other),
+        }
+        assert!(from_json_str("1.5").is_err());
+        assert!(from_json_str("null").is_err());
+        assert!(from_json_str("{").is_err());
+        for bad in &[r#"{"$hex": "abc"}"#, r#"{"$hex": 12}"#, r#"{"$int": "01"}"#, r#"{"$x": 1}"#] {
+            match from_json_str(bad) {
+                Err(JsonError::BadEscape{ .. }) => (),
+                other => panic!("{} should be a bad escape, got {:?}", bad, other),
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn json_round_trip(v in arbitrary::bvalue()) {
+            let bs = v.encode();
+            let bv = decode(&bs, Options::new(Mode::Strict).with_big_ints()).unwrap();
+            prop_assert_eq!(from_json_str(&to_json_string(&bv)).unwrap(), v);
+        }
+    }
+}
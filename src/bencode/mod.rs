@@ -1,43 +1,108 @@
+use std::borrow::Cow;
+use std::fmt;
 use std::str;
-use std::str::{FromStr, Utf8Error};
+use std::str::Utf8Error;
+use std::cell::Cell;
 use std::result::Result;
-use std::collections::HashMap;
-use nom::{IResult, Needed, is_digit, Err};
-
-// TODO: Annotate all AST nodes with raw input?
+use std::collections::BTreeMap;
+use nom::{IResult, Needed, ErrorKind, is_digit, Err};
+
+mod encode;
+mod value;
+mod error;
+mod query;
+mod dict;
+mod span;
+pub mod ser;
+pub mod de;
+pub mod stream;
+pub mod json;
+pub mod pretty;
+#[cfg(test)] pub mod arbitrary;
+
+pub use self::encode::{Encode, write_bstring, write_bint, write_blist, write_bdict};
+pub use self::value::BValue;
+pub use self::error::{BencodeError, Path, PathSegment};
+pub use self::query::Cursor;
+pub use self::dict::Dict;
+pub use self::span::rewrite;
+
+/// A parsed value. The first field of every variant is the raw input the
+/// value was parsed from, `i42e` and all; see `raw` and `span`.
 #[derive(Debug, PartialEq)]
 pub enum BVal<'a> {
-    BString(&'a [u8]),
-    BInt(i64),
-    BList(Vec<BVal<'a>>),
-    // raw input as well
-    BDict(&'a [u8], HashMap<&'a str, BVal<'a>>),
+    BString(&'a [u8], &'a [u8]),
+    BInt(&'a [u8], i64),
+    // the decimal text of an integer too big for an i64, without leading
+    // zeros even if the input had them
+    BBigInt(&'a [u8], Cow<'a, str>),
+    BList(&'a [u8], Vec<BVal<'a>>),
+    BDict(&'a [u8], Dict<'a>),
 }
 
 #[derive(Debug, PartialEq)]
 pub enum ReadError {
     WrongType{found: &'static str, expected: &'static str},
     BadString(Utf8Error),
+    /// Nothing at this path in the document.
+    Missing(Path),
+    /// `err` happened reading the value at `path`.
+    At{path: Path, err: Box<ReadError>},
+    /// A `Cursor` query that doesn't parse as a path.
+    BadQuery(String),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReadError::WrongType{found, expected} => write!(f, "expected {} but found {}", expected, found),
+            ReadError::BadString(ref e) => write!(f, "{}", e),
+            ReadError::Missing(ref path) => write!(f, "missing {}", path),
+            ReadError::At{ref path, ref err} if path.0.is_empty() => write!(f, "{}", err),
+            ReadError::At{ref path, ref err} => write!(f, "{} at {}", err, path),
+            ReadError::BadQuery(ref q) => write!(f, "bad query {:?}", q),
+        }
+    }
+}
+
+impl ::std::error::Error for ReadError {}
+
+/// Dict keys are raw bytes; this layers `&str` lookups on top for the common
+/// case of ASCII keys like `"info"` or `"piece length"`.
+pub trait StrKeys<V> {
+    fn get_str(&self, key: &str) -> Option<&V>;
+
+    fn contains_str(&self, key: &str) -> bool {
+        self.get_str(key).is_some()
+    }
+}
+
+impl StrKeys<BValue> for BTreeMap<Vec<u8>, BValue> {
+    fn get_str(&self, key: &str) -> Option<&BValue> {
+        self.get(key.as_bytes())
+    }
 }
 
 pub static BSTRING_TYPE_NAME: &'static str = "BString";
 pub static BINT_TYPE_NAME: &'static str = "BInt";
+pub static BBIGINT_TYPE_NAME: &'static str = "BBigInt";
 pub static BLIST_TYPE_NAME: &'static str = "BList";
 pub static BDICT_TYPE_NAME: &'static str = "BDict";
 
 impl <'a, 'b: 'a> BVal<'a> {
     fn report<T>(&self, expected: &'static str) -> Result<T, ReadError> {
         match self {
-            &BVal::BString(_) => Err(ReadError::WrongType{found: BSTRING_TYPE_NAME, expected: expected}),
-            &BVal::BInt(_) => Err(ReadError::WrongType{found: BINT_TYPE_NAME, expected: expected}),
-            &BVal::BList(_) => Err(ReadError::WrongType{found: BLIST_TYPE_NAME, expected: expected}),
+            &BVal::BString(..) => Err(ReadError::WrongType{found: BSTRING_TYPE_NAME, expected: expected}),
+            &BVal::BInt(..) => Err(ReadError::WrongType{found: BINT_TYPE_NAME, expected: expected}),
+            &BVal::BBigInt(..) => Err(ReadError::WrongType{found: BBIGINT_TYPE_NAME, expected: expected}),
+            &BVal::BList(..) => Err(ReadError::WrongType{found: BLIST_TYPE_NAME, expected: expected}),
             &BVal::BDict(..) => Err(ReadError::WrongType{found: BDICT_TYPE_NAME, expected: expected}),
         }
     }
 
     pub fn as_bstring_str(&self) -> Result<&'a str, ReadError> {
         match self {
-            &BVal::BString(bs) => str::from_utf8(bs)
+            &BVal::BString(_, bs) => str::from_utf8(bs)
                 .or_else(|e| Err(ReadError::BadString(e))),
             _ => self.report(BSTRING_TYPE_NAME),
         }
@@ -45,37 +110,44 @@ impl <'a, 'b: 'a> BVal<'a> {
 
     pub fn as_bstring_bytes(&self) -> Result<&'a [u8], ReadError> {
         match self {
-            &BVal::BString(s) => Result::Ok(s),
+            &BVal::BString(_, s) => Result::Ok(s),
             _ => self.report(BSTRING_TYPE_NAME),
         }
     }
 
     pub fn as_bint(&self) -> Result<i64, ReadError> {
         match self {
-            &BVal::BInt(i) => Result::Ok(i),
+            &BVal::BInt(_, i) => Result::Ok(i),
             _ => self.report(BINT_TYPE_NAME),
         }
     }
 
+    pub fn as_bbigint_str(&self) -> Result<&str, ReadError> {
+        match self {
+            &BVal::BBigInt(_, ref s) => Result::Ok(s),
+            _ => self.report(BBIGINT_TYPE_NAME),
+        }
+    }
+
     pub fn as_blist(&self) -> Result<&Vec<BVal<'a>>, ReadError> {
         match *self {
-            BVal::BList(ref v) => Result::Ok(v),
+            BVal::BList(_, ref v) => Result::Ok(v),
             _ => self.report(BLIST_TYPE_NAME),
         }
     }
 
-    pub fn as_bdict_ref(&self) -> Result<&HashMap<&'a str, BVal<'a>>, ReadError> {
+    pub fn as_bdict_ref(&self) -> Result<&Dict<'a>, ReadError> {
         self.as_bdict_ref_with_input().map(|(_, dict)| dict)
     }
 
-    pub fn as_bdict_ref_with_input(&self) -> Result<(&[u8], &HashMap<&'a str, BVal<'a>>), ReadError> {
+    pub fn as_bdict_ref_with_input(&self) -> Result<(&[u8], &Dict<'a>), ReadError> {
         match *self {
             BVal::BDict(input, ref m) => Result::Ok((input, m)),
             _ => self.report(BDICT_TYPE_NAME),
         }
     }
 
-    pub fn as_bdict(self) -> Result<HashMap<&'a str, BVal<'a>>, ReadError> {
+    pub fn as_bdict(self) -> Result<Dict<'a>, ReadError> {
         match self {
             BVal::BDict(_, m) => Result::Ok(m),
             _ => self.report(BDICT_TYPE_NAME),
@@ -102,26 +174,296 @@ impl <'a, 'b: 'a> BVal<'a> {
  *
  * posnum ::= <the number in ascii (bounds check for i64)>
  *
+ * Integers outside the i64 range fail with `IntegerOverflow`, unless
+ * `Options::big_ints` is set, in which case they're kept as `BBigInt` text.
+ *
  * num ::= "-" posnum | posnum
+ *
+ * In `Mode::Strict` only canonical bencode is accepted: no leading zeros in
+ * `posnum`, no "-0", and dict keys must be unique and sorted by raw bytes.
+ *
+ * Every value keeps the slice of input it was parsed from, so for a
+ * `bint` that's the whole "i" num "e", not just the digits.
+ *
+ * `blist` and `bdict` recurse, so `ParseLimits` bounds the nesting depth (and
+ * the number of values and string lengths) before hostile input can blow the
+ * stack or memory.
  */
 
-named!(pub bval<BVal>, alt!(bstring | bint | blist | bdict));
+/// How forgiving the parser is about non-canonical input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Accepts anything the grammar allows. Duplicate dict keys are kept, and
+    /// lookups see the last one.
+    Lenient,
+    /// Rejects anything that wouldn't re-encode to the same bytes.
+    Strict,
+}
 
-named!(bstring_prelude<usize>,
-       chain!(
-           n: posnum ~
-           tag!(":") ,
-           ||{ n }
-       )
-);
+/// Bounds on how much a single document may ask of the parser.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParseLimits {
+    /// How many lists and dicts may be nested inside each other.
+    pub max_depth: usize,
+    /// How many values, counting containers, the whole document may hold.
+    pub max_items: usize,
+    /// The longest string, in bytes, including dict keys.
+    pub max_string_len: usize,
+}
+
+impl ParseLimits {
+    /// No limits at all; only for input that's already trusted, since deep
+    /// enough nesting will overflow the stack.
+    pub fn none() -> ParseLimits {
+        ParseLimits{
+            max_depth: usize::max_value(),
+            max_items: usize::max_value(),
+            max_string_len: usize::max_value(),
+        }
+    }
+}
+
+impl Default for ParseLimits {
+    // roomy enough for a torrent with hundreds of thousands of files
+    fn default() -> ParseLimits {
+        ParseLimits{
+            max_depth: 64,
+            max_items: 4 * 1024 * 1024,
+            max_string_len: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Everything that tunes how input is parsed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub mode: Mode,
+    /// Keep integers that don't fit in an `i64` as `BBigInt`s rather than
+    /// failing with `IntegerOverflow`.
+    pub big_ints: bool,
+    pub limits: ParseLimits,
+}
+
+impl Options {
+    pub fn new(mode: Mode) -> Options {
+        Options{ mode: mode, big_ints: false, limits: ParseLimits::default() }
+    }
+
+    pub fn with_big_ints(self) -> Options {
+        Options{ big_ints: true, ..self }
+    }
+
+    pub fn with_limits(self, limits: ParseLimits) -> Options {
+        Options{ limits: limits, ..self }
+    }
+}
+
+impl From<Mode> for Options {
+    fn from(mode: Mode) -> Options {
+        Options::new(mode)
+    }
+}
+
+/// The specific reasons a parse can fail, carried as `ErrorKind::Custom` codes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseError {
+    EmptyInteger = 1,
+    LeadingZero,
+    NegativeZero,
+    UnsortedKey,
+    DuplicateKey,
+    ExpectedValue,
+    ExpectedKey,
+    ExpectedColon,
+    ExpectedEnd,
+    IntegerOverflow,
+    UnexpectedEof,
+    TrailingBytes,
+    TooDeep,
+    TooLarge,
+    TooManyItems,
+    StringTooLong,
+}
+
+static PARSE_ERRORS: [ParseError; 16] = [
+    ParseError::EmptyInteger,
+    ParseError::LeadingZero,
+    ParseError::NegativeZero,
+    ParseError::UnsortedKey,
+    ParseError::DuplicateKey,
+    ParseError::ExpectedValue,
+    ParseError::ExpectedKey,
+    ParseError::ExpectedColon,
+    ParseError::ExpectedEnd,
+    ParseError::IntegerOverflow,
+    ParseError::UnexpectedEof,
+    ParseError::TrailingBytes,
+    ParseError::TooDeep,
+    ParseError::TooLarge,
+    ParseError::TooManyItems,
+    ParseError::StringTooLong,
+];
+
+impl ParseError {
+    pub fn from_code(code: u32) -> Option<ParseError> {
+        PARSE_ERRORS.iter().cloned().find(|e| *e as u32 == code)
+    }
+
+    /// What the parser wanted to see where it failed.
+    pub fn expected(&self) -> &'static str {
+        match *self {
+            ParseError::EmptyInteger => "a digit",
+            ParseError::LeadingZero => "a number without leading zeros",
+            ParseError::NegativeZero => "a non-zero number after '-'",
+            ParseError::UnsortedKey => "keys in sorted order",
+            ParseError::DuplicateKey => "a key not already in the dict",
+            ParseError::ExpectedValue => "'i', 'l', 'd' or a digit",
+            ParseError::ExpectedKey => "a string key or 'e'",
+            ParseError::ExpectedColon => "':'",
+            ParseError::ExpectedEnd => "'e'",
+            ParseError::IntegerOverflow => "a number that fits in 64 bits",
+            ParseError::UnexpectedEof => "more input",
+            ParseError::TrailingBytes => "the end of input",
+            ParseError::TooDeep => "less deeply nested values",
+            ParseError::TooLarge => "a smaller value",
+            ParseError::TooManyItems => "fewer values",
+            ParseError::StringTooLong => "a shorter string",
+        }
+    }
+
+    /// Finds the innermost `ParseError` in a nom error chain, if there is one.
+    pub fn from_err<P>(err: &Err<P>) -> Option<ParseError> {
+        let (kind, next) = match *err {
+            Err::Code(ref k) => (k, None),
+            Err::Node(ref k, ref next) => (k, Some(next)),
+            Err::Position(ref k, _) => (k, None),
+            Err::NodePosition(ref k, _, ref next) => (k, Some(next)),
+        };
+        next.and_then(|n| ParseError::from_err(n)).or_else(|| match *kind {
+            ErrorKind::Custom(code) => ParseError::from_code(code),
+            _ => None,
+        })
+    }
+}
+
+fn to_hex(bs: &[u8]) -> String {
+    bs.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn fail<O>(at: &[u8], e: ParseError) -> IResult<&[u8], O> {
+    IResult::Error(Err::Position(ErrorKind::Custom(e as u32), at))
+}
+
+fn byte(i: &[u8], b: u8, e: ParseError) -> IResult<&[u8], ()> {
+    match i.first() {
+        None => IResult::Incomplete(Needed::Size(1)),
+        Some(&c) if c == b => IResult::Done(&i[1..], ()),
+        Some(_) => fail(i, e),
+    }
+}
+
+/// Parses exactly one complete value, with a located error on failure.
+pub fn decode(input: &[u8], opts: Options) -> Result<BVal, BencodeError> {
+    match bval_with(input, opts) {
+        IResult::Done(rest, bv) =>
+            if rest.is_empty() {
+                Ok(bv)
+            } else {
+                Err(BencodeError::at(input, input.len() - rest.len(), ParseError::TrailingBytes))
+            },
+        IResult::Incomplete(_) =>
+            Err(BencodeError::at(input, input.len(), ParseError::UnexpectedEof)),
+        IResult::Error(e) => Err(BencodeError::from_err(input, &e)),
+    }
+}
+
+pub fn bval(i: &[u8]) -> IResult<&[u8], BVal> {
+    bval_with(i, Options::new(Mode::Lenient))
+}
+
+pub fn bval_strict(i: &[u8]) -> IResult<&[u8], BVal> {
+    bval_with(i, Options::new(Mode::Strict))
+}
+
+pub fn bval_with(i: &[u8], opts: Options) -> IResult<&[u8], BVal> {
+    bval_within(i, opts, &Budget::new(opts.limits))
+}
+
+// What's left of the `ParseLimits` partway through a document.
+struct Budget {
+    depth: Cell<usize>,
+    items: Cell<usize>,
+}
+
+impl Budget {
+    fn new(limits: ParseLimits) -> Budget {
+        Budget{ depth: Cell::new(limits.max_depth), items: Cell::new(limits.max_items) }
+    }
+}
+
+// Dispatches on the first byte rather than `alt!`ing, so the specific error
+// from the branch that was taken isn't swallowed.
+fn bval_within<'a>(i: &'a [u8], opts: Options, budget: &Budget) -> IResult<&'a [u8], BVal<'a>> {
+    if i.is_empty() {
+        return IResult::Incomplete(Needed::Size(1));
+    }
+    if budget.items.get() == 0 {
+        return fail(i, ParseError::TooManyItems);
+    }
+    budget.items.set(budget.items.get() - 1);
+    match i[0] {
+        b'i' => bint_with(i, opts),
+        b'l' => blist_within(i, opts, budget),
+        b'd' => bdict_within(i, opts, budget),
+        c if is_digit(c) => bstring_with(i, opts),
+        _ => fail(i, ParseError::ExpectedValue),
+    }
+}
+
+// Runs `f` one level deeper, if the depth limit allows it.
+fn nested<'a, O, F>(i: &'a [u8], budget: &Budget, f: F) -> IResult<&'a [u8], O>
+    where F: FnOnce() -> IResult<&'a [u8], O>
+{
+    let depth = budget.depth.get();
+    if depth == 0 {
+        return fail(i, ParseError::TooDeep);
+    }
+    budget.depth.set(depth - 1);
+    let r = f();
+    budget.depth.set(depth);
+    r
+}
+
+fn bstring_prelude(i: &[u8], opts: Options) -> IResult<&[u8], usize> {
+    chain!(i,
+        n: call!(posnum, opts) ~
+        call!(byte, b':', ParseError::ExpectedColon) ,
+        ||{ n }
+    )
+}
 
-fn bstring_bytes(chars: &[u8]) -> IResult<&[u8], &[u8]> {
-    match bstring_prelude(chars) {
+fn bstring_bytes(chars: &[u8], opts: Options) -> IResult<&[u8], &[u8]> {
+    match bstring_prelude(chars, opts) {
+        IResult::Done(_, n) if n > opts.limits.max_string_len =>
+            fail(chars, ParseError::StringTooLong),
         IResult::Done(rest, n) => {
           if rest.len() >= n {
             IResult::Done(&rest[n..], &rest[..n])
           } else {
-            IResult::Incomplete(Needed::Size(n))
+            // nom wants the size counted from the start of our input
+            match (chars.len() - rest.len()).checked_add(n) {
+                Some(total) => IResult::Incomplete(Needed::Size(total)),
+                None => fail(chars, ParseError::IntegerOverflow),
+            }
           }
         },
         IResult::Error(e) => IResult::Error(e),
@@ -130,30 +472,66 @@ fn bstring_bytes(chars: &[u8]) -> IResult<&[u8], &[u8]> {
 }
 
 pub fn bstring(chars: &[u8]) -> IResult<&[u8], BVal> {
-    match bstring_bytes(chars) {
-        IResult::Done(rest, s) => IResult::Done(rest, BVal::BString(s)),
+    bstring_with(chars, Options::new(Mode::Lenient))
+}
+
+pub fn bstring_with(chars: &[u8], opts: Options) -> IResult<&[u8], BVal> {
+    match bstring_bytes(chars, opts) {
+        IResult::Done(rest, s) => IResult::Done(rest, BVal::BString(&chars[..chars.len()-rest.len()], s)),
         IResult::Error(e) => IResult::Error(e),
         IResult::Incomplete(n) => IResult::Incomplete(n),
     }
 }
 
-named!(pub bint<BVal>,
-       chain!(
-           tag!("i") ~
-           n: num ~
-           tag!("e") ,
-           ||{ BVal::BInt(n) }
-       )
-);
+pub fn bint(i: &[u8]) -> IResult<&[u8], BVal> {
+    bint_with(i, Options::new(Mode::Lenient))
+}
 
-named!(pub blist<BVal>,
-       chain!(
-           tag!("l") ~
-           bvs: many0!(bval) ~
-           tag!("e") ,
-           ||{ BVal::BList(bvs) }
-       )
-);
+pub fn bint_with(i: &[u8], opts: Options) -> IResult<&[u8], BVal> {
+    let (rest, n) = try_parse!(i, chain!(
+        call!(byte, b'i', ParseError::ExpectedValue) ~
+        n: call!(num, opts) ~
+        call!(byte, b'e', ParseError::ExpectedEnd) ,
+        ||{ n }
+    ));
+    let raw = &i[..i.len()-rest.len()];
+    IResult::Done(rest, match n {
+        BVal::BBigInt(_, s) => BVal::BBigInt(raw, s),
+        BVal::BInt(_, n) => BVal::BInt(raw, n),
+        _ => unreachable!(),
+    })
+}
+
+pub fn blist(i: &[u8]) -> IResult<&[u8], BVal> {
+    blist_with(i, Options::new(Mode::Lenient))
+}
+
+// Elements are parsed until the closing "e" by hand instead of with `many0!`,
+// which would swallow an element's error and just report a missing "e".
+pub fn blist_with(i: &[u8], opts: Options) -> IResult<&[u8], BVal> {
+    blist_within(i, opts, &Budget::new(opts.limits))
+}
+
+fn blist_within<'a>(i: &'a [u8], opts: Options, budget: &Budget) -> IResult<&'a [u8], BVal<'a>> {
+    let (mut rest, _) = try_parse!(i, call!(byte, b'l', ParseError::ExpectedValue));
+    nested(i, budget, || {
+        let mut bvs = Vec::new();
+        loop {
+            match rest.first() {
+                None => return IResult::Incomplete(Needed::Unknown),
+                Some(&b'e') => {
+                    let raw = &i[..i.len()-rest.len()+1];
+                    return IResult::Done(&rest[1..], BVal::BList(raw, bvs));
+                },
+                Some(_) => {
+                    let (r, bv) = try_parse!(rest, call!(bval_within, opts, budget));
+                    bvs.push(bv);
+                    rest = r;
+                },
+            }
+        }
+    })
+}
 
 // TODO: Make this work with a macro
 // `with_input_slice!(I->IResult<I,O>) -> I -> IResult<I,(I,O)> where I: Slice<T>`
@@ -170,68 +548,169 @@ named!(pub blist<BVal>,
 );
 */
 
-named!(bdict_hashmap< HashMap<&str, BVal> >,
-       chain!(
-           tag!("d") ~
-           kvs: many0!(keyvalpair) ~
-           tag!("e") ,
-           ||{ kvs.into_iter().collect() }
-       )
-);
+fn bdict_entries<'a>(i: &'a [u8], opts: Options, budget: &Budget)
+    -> IResult<&'a [u8], Dict<'a>> {
+    let (mut rest, _) = try_parse!(i, call!(byte, b'd', ParseError::ExpectedValue));
+    nested(i, budget, || {
+        let mut m = Dict::new();
+        let mut last_key: Option<&[u8]> = None;
+        loop {
+            match rest.first() {
+                None => return IResult::Incomplete(Needed::Unknown),
+                Some(&b'e') => return IResult::Done(&rest[1..], m),
+                Some(&c) if !is_digit(c) => return fail(rest, ParseError::ExpectedKey),
+                Some(_) => {
+                    let (r, (key, val)) = try_parse!(rest, call!(keyvalpair, opts, budget));
+                    if opts.mode == Mode::Strict {
+                        match last_key {
+                            Some(last) if last == key => return fail(rest, ParseError::DuplicateKey),
+                            Some(last) if last > key =>
+                                return fail(rest, ParseError::UnsortedKey),
+                            _ => (),
+                        }
+                    }
+                    last_key = Some(key);
+                    m.push(key, val);
+                    rest = r;
+                },
+            }
+        }
+    })
+}
 
 pub fn bdict(i: &[u8]) -> IResult<&[u8], BVal> {
-    let (rest, m) = try_parse!(i, bdict_hashmap);
+    bdict_with(i, Options::new(Mode::Lenient))
+}
+
+pub fn bdict_strict(i: &[u8]) -> IResult<&[u8], BVal> {
+    bdict_with(i, Options::new(Mode::Strict))
+}
+
+pub fn bdict_with(i: &[u8], opts: Options) -> IResult<&[u8], BVal> {
+    bdict_within(i, opts, &Budget::new(opts.limits))
+}
+
+fn bdict_within<'a>(i: &'a [u8], opts: Options, budget: &Budget) -> IResult<&'a [u8], BVal<'a>> {
+    let (rest, m) = try_parse!(i, call!(bdict_entries, opts, budget));
     IResult::Done(rest, BVal::BDict(&i[..i.len()-rest.len()], m))
 }
 
-named!(keyvalpair<(&str, BVal)>,
-       chain!(
-           key: map_res!(bstring_bytes, str::from_utf8) ~
-           val: bval ,
-           ||{ (key, val) }
-       )
-);
+fn keyvalpair<'a>(i: &'a [u8], opts: Options, budget: &Budget) -> IResult<&'a [u8], (&'a [u8], BVal<'a>)> {
+    chain!(i,
+        key: call!(bstring_bytes, opts) ~
+        val: call!(bval_within, opts, budget) ,
+        ||{ (key, val) }
+    )
+}
 
-named!(posnum<usize>,
-       map_res!(
-           map_res!(take_while!(is_digit), str::from_utf8),
-           FromStr::from_str
-       )
-);
+// The digits of a `posnum`, checked for canonical form but not yet converted.
+fn digits(i: &[u8], opts: Options) -> IResult<&[u8], &[u8]> {
+    let (rest, digits) = try_parse!(i, take_while!(is_digit));
+    if digits.is_empty() {
+        if rest.is_empty() {
+            return IResult::Incomplete(Needed::Unknown);
+        }
+        return fail(i, ParseError::EmptyInteger);
+    }
+    if opts.mode == Mode::Strict && digits.len() > 1 && digits[0] == b'0' {
+        return fail(i, ParseError::LeadingZero);
+    }
+    IResult::Done(rest, digits)
+}
 
-named!(num<i64>,
-       chain!(
-           neg: opt!(tag!("-")) ~
-           n: posnum ,
-           ||{ neg.map(|_| -1).unwrap_or(1) * (n as i64) }
-       )
-);
+fn magnitude(digits: &[u8]) -> Option<u64> {
+    digits.iter().fold(Some(0u64), |acc, &d| {
+        acc.and_then(|n| n.checked_mul(10))
+           .and_then(|n| n.checked_add((d - b'0') as u64))
+    })
+}
+
+// Lenient mode allows leading zeros, but encoding and JSON need the
+// canonical text, so they're dropped. Big ints are never zero, so some
+// digits are left.
+fn big_int_text<'a>(text: &'a str) -> Cow<'a, str> {
+    let (neg, digits) = if text.starts_with('-') { (true, &text[1..]) } else { (false, text) };
+    let trimmed = digits.trim_start_matches('0');
+    match (trimmed.len() == digits.len(), neg) {
+        (true, _) => Cow::Borrowed(text),
+        (false, false) => Cow::Borrowed(trimmed),
+        (false, true) => Cow::Owned(format!("-{}", trimmed)),
+    }
+}
+
+fn posnum(i: &[u8], opts: Options) -> IResult<&[u8], usize> {
+    let (rest, ds) = try_parse!(i, call!(digits, opts));
+    match magnitude(ds) {
+        Some(n) if n <= usize::max_value() as u64 => IResult::Done(rest, n as usize),
+        _ => fail(i, ParseError::IntegerOverflow),
+    }
+}
+
+// Produces a `BInt`, or a `BBigInt` if it's out of range and that's allowed,
+// with just the digits as raw input; `bint_with` widens it to the `i...e`.
+fn num(i: &[u8], opts: Options) -> IResult<&[u8], BVal> {
+    let (rest, (neg, ds)) = try_parse!(i, chain!(
+        neg: opt!(tag!("-")) ~
+        ds: call!(digits, opts) ,
+        ||{ (neg.is_some(), ds) }
+    ));
+    let n = magnitude(ds).and_then(|m| {
+        if !neg && m <= i64::max_value() as u64 {
+            Some(m as i64)
+        } else if neg && m <= i64::max_value() as u64 + 1 {
+            Some((m as i64).wrapping_neg())
+        } else {
+            None
+        }
+    });
+    match n {
+        Some(0) if neg && opts.mode == Mode::Strict => fail(i, ParseError::NegativeZero),
+        Some(n) => IResult::Done(rest, BVal::BInt(&i[..i.len()-rest.len()], n)),
+        None if opts.big_ints => {
+            let text = &i[..i.len() - rest.len()];
+            // only '-' and digits, so always valid UTF-8
+            IResult::Done(rest, BVal::BBigInt(text, big_int_text(str::from_utf8(text).unwrap())))
+        },
+        None => fail(i, ParseError::IntegerOverflow),
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bencode::arbitrary;
     use nom::IResult;
+    use proptest::prelude::*;
+    use proptest::collection::vec;
 
     fn done<'a, T>(t: T) -> IResult<&'a [u8], T> { IResult::Done(&b""[..], t) }
 
+    fn failed<'a, T>(r: IResult<&'a [u8], T>) -> Option<ParseError> {
+        match r {
+            IResult::Error(e) => ParseError::from_err(&e),
+            _ => None,
+        }
+    }
+
     #[test]
     fn bval_string() {
-        assert_eq!(bval(&b"5:abcde"[..]), done(BVal::BString(&b"abcde"[..])));
+        assert_eq!(bval(&b"5:abcde"[..]), done(BVal::BString(&b"5:abcde"[..], &b"abcde"[..])));
     }
 
     #[test]
     fn bval_int() {
         // positive
-        assert_eq!(bval(&b"i53e"[..]), done(BVal::BInt(53)));
+        assert_eq!(bval(&b"i53e"[..]), done(BVal::BInt(&b"i53e"[..], 53)));
         // negative
-        assert_eq!(bval(&b"i-13e"[..]), done(BVal::BInt(-13)));
+        assert_eq!(bval(&b"i-13e"[..]), done(BVal::BInt(&b"i-13e"[..], -13)));
     }
 
     #[test]
     fn bval_list() {
         assert_eq!(bval(&b"li53ei-10ee"[..]), done(
                 BVal::BList(
-                    vec![BVal::BInt(53), BVal::BInt(-10)]
+                    &b"li53ei-10ee"[..],
+                    vec![BVal::BInt(&b"i53e"[..], 53), BVal::BInt(&b"i-10e"[..], -10)]
                 ))
         );
     }
@@ -243,8 +722,8 @@ mod tests {
                 BVal::BDict(
                     input,
                     vec![
-                        ("key", BVal::BString(&b"value"[..])),
-                        ("key2", BVal::BInt(10)),
+                        (&b"key"[..], BVal::BString(&b"5:value"[..], &b"value"[..])),
+                        (&b"key2"[..], BVal::BInt(&b"i10e"[..], 10)),
                     ].into_iter().collect()
                 ))
         );
@@ -253,13 +732,165 @@ mod tests {
     #[test]
     fn option_variant() {
         assert_eq!(
-            BVal::BString(&b"hello"[..]).as_bstring_str(),
+            BVal::BString(&b""[..], &b"hello"[..]).as_bstring_str(),
             Ok("hello")
         );
 
         assert_eq!(
-            BVal::BInt(10).as_bstring_str(),
+            BVal::BInt(&b""[..], 10).as_bstring_str(),
             Err(ReadError::WrongType{found: BINT_TYPE_NAME, expected: BSTRING_TYPE_NAME})
         );
     }
+
+    #[test]
+    fn lenient_accepts_non_canonical() {
+        assert_eq!(bval(&b"i03e"[..]), done(BVal::BInt(&b"i03e"[..], 3)));
+        assert_eq!(bval(&b"i-0e"[..]), done(BVal::BInt(&b"i-0e"[..], 0)));
+        assert_eq!(bval(&b"02:ab"[..]), done(BVal::BString(&b"02:ab"[..], &b"ab"[..])));
+        match bval(&b"d1:bi1e1:ai2e1:bi3ee"[..]) {
+            IResult::Done(_, bv) => assert_eq!(bv.as_bdict().unwrap().get_str("b"), Some(&BVal::BInt(&b"i3e"[..], 3))),
+            _ => panic!("should parse"),
+        }
+    }
+
+    #[test]
+    fn strict_rejects_non_canonical() {
+        assert_eq!(failed(bval_strict(&b"i03e"[..])), Some(ParseError::LeadingZero));
+        assert_eq!(failed(bval_strict(&b"i-0e"[..])), Some(ParseError::NegativeZero));
+        assert_eq!(failed(bval_strict(&b"02:ab"[..])), Some(ParseError::LeadingZero));
+        assert_eq!(failed(bval_strict(&b"d1:bi1e1:ai2ee"[..])), Some(ParseError::UnsortedKey));
+        assert_eq!(failed(bval_strict(&b"d1:ai1e1:ai2ee"[..])), Some(ParseError::DuplicateKey));
+        // errors deep inside containers aren't swallowed
+        assert_eq!(failed(bval_strict(&b"ld1:xli1ei00eeee"[..])), Some(ParseError::LeadingZero));
+    }
+
+    #[test]
+    fn empty_integer() {
+        assert_eq!(failed(bval(&b"ie"[..])), Some(ParseError::EmptyInteger));
+        assert_eq!(failed(bval(&b"i-e"[..])), Some(ParseError::EmptyInteger));
+    }
+
+    #[test]
+    fn integer_overflow() {
+        assert_eq!(bval(&b"i9223372036854775807e"[..]), done(BVal::BInt(&b"i9223372036854775807e"[..], i64::max_value())));
+        assert_eq!(bval(&b"i-9223372036854775808e"[..]), done(BVal::BInt(&b"i-9223372036854775808e"[..], i64::min_value())));
+        assert_eq!(failed(bval(&b"i9223372036854775808e"[..])), Some(ParseError::IntegerOverflow));
+        assert_eq!(failed(bval(&b"i-99999999999999999999999e"[..])), Some(ParseError::IntegerOverflow));
+        // a length prefix that can't be a real string length
+        assert_eq!(failed(bval(&b"99999999999999999999999:x"[..])), Some(ParseError::IntegerOverflow));
+    }
+
+    #[test]
+    fn string_length_needed_from_start() {
+        assert_eq!(bval(&b"10:abc"[..]), IResult::Incomplete(Needed::Size(13)));
+    }
+
+    #[test]
+    fn big_ints() {
+        let opts = Options::new(Mode::Strict).with_big_ints();
+        assert_eq!(bval_with(&b"i9223372036854775808e"[..], opts), done(BVal::BBigInt(&b"i9223372036854775808e"[..], "9223372036854775808".into())));
+        assert_eq!(bval_with(&b"i-123456789012345678901234567890e"[..], opts),
+                   done(BVal::BBigInt(&b"i-123456789012345678901234567890e"[..], "-123456789012345678901234567890".into())));
+        // in range stays a plain BInt, and the canonical checks still apply
+        assert_eq!(bval_with(&b"i42e"[..], opts), done(BVal::BInt(&b"i42e"[..], 42)));
+        assert_eq!(failed(bval_with(&b"i0123456789012345678901e"[..], opts)), Some(ParseError::LeadingZero));
+        // lenient mode allows leading zeros, but not in the text, which
+        // has to encode back canonically
+        let lenient = Options::new(Mode::Lenient).with_big_ints();
+        for &(bs, text) in &[(&b"i099999999999999999999e"[..], "99999999999999999999"),
+                             (&b"i-099999999999999999999e"[..], "-99999999999999999999")] {
+            let bv = decode(bs, lenient).unwrap();
+            assert_eq!(bv, BVal::BBigInt(bs, text.into()));
+            let encoded = bv.encode();
+            assert_eq!(encoded, format!("i{}e", text).into_bytes());
+            assert!(decode(&encoded, opts).is_ok());
+            assert_eq!(json::from_json(&json::to_json(&bv)).unwrap(), BValue::from(&bv));
+        }
+        assert_eq!(
+            BVal::BBigInt(&b""[..], "1".into()).as_bint(),
+            Err(ReadError::WrongType{found: BBIGINT_TYPE_NAME, expected: BINT_TYPE_NAME})
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let limits = ParseLimits{ max_depth: 2, max_items: 4, max_string_len: 3 };
+        let opts = Options::new(Mode::Lenient).with_limits(limits);
+        assert_eq!(bval_with(&b"lli1eee"[..], opts), done(BVal::BList(&b"lli1eee"[..], vec![BVal::BList(&b"li1ee"[..], vec![BVal::BInt(&b"i1e"[..], 1)])])));
+        assert_eq!(failed(bval_with(&b"llleee"[..], opts)), Some(ParseError::TooDeep));
+        assert_eq!(failed(bval_with(&b"d1:ad1:bd1:ci1eeee"[..], opts)), Some(ParseError::TooDeep));
+        assert_eq!(failed(bval_with(&b"li1ei2ei3ei4ee"[..], opts)), Some(ParseError::TooManyItems));
+        assert_eq!(failed(bval_with(&b"4:spam"[..], opts)), Some(ParseError::StringTooLong));
+        assert_eq!(failed(bval_with(&b"d4:spami1ee"[..], opts)), Some(ParseError::StringTooLong));
+        // siblings share the depth, they don't add to it
+        assert!(bval_with(&b"llelelee"[..], opts).is_done());
+    }
+
+    #[test]
+    fn hostile_nesting_is_an_error() {
+        let input = vec![b'l'; 1000000];
+        assert_eq!(failed(bval(&input)), Some(ParseError::TooDeep));
+        let e = decode(&input, Mode::Strict.into()).err().unwrap();
+        assert_eq!((e.kind, e.offset), (ParseError::TooDeep, 64));
+    }
+
+    #[test]
+    fn strict_accepts_canonical() {
+        let bs = &include_bytes!("../../sample.mp4.torrent")[..];
+        match bval_strict(bs) {
+            IResult::Done(rest, _) => assert_eq!(rest, &b""[..]),
+            _ => panic!("sample torrent is canonical"),
+        }
+        assert_eq!(bval_strict(&b"i0e"[..]), done(BVal::BInt(&b"i0e"[..], 0)));
+        assert_eq!(bval_strict(&b"i-10e"[..]), done(BVal::BInt(&b"i-10e"[..], -10)));
+    }
+
+    #[test]
+    fn binary_keys() {
+        let input = &b"d2:\xff\x00i1e1:ai2ee"[..];
+        match bval(input) {
+            IResult::Done(_, bv) => {
+                let m = bv.as_bdict().unwrap();
+                assert_eq!(m.get(&b"\xff\x00"[..]), Some(&BVal::BInt(&b"i1e"[..], 1)));
+                assert_eq!(m.get_str("a"), Some(&BVal::BInt(&b"i2e"[..], 2)));
+                assert!(!m.contains_str("b"));
+            },
+            _ => panic!("binary keys should parse"),
+        }
+        // and sort by raw bytes in strict mode
+        assert_eq!(failed(bval_strict(input)), Some(ParseError::UnsortedKey));
+    }
+
+    // Decoding either fails at an offset inside the input, or gives a value
+    // that re-encodes to something decoding to the same thing.
+    fn check_decode(bs: &[u8]) {
+        // the nom parsers on their own too
+        let _ = (bval(bs), bval_strict(bs));
+        for &mode in &[Mode::Lenient, Mode::Strict] {
+            match decode(bs, Options::new(mode).with_big_ints()) {
+                Ok(bv) => {
+                    let encoded = bv.encode();
+                    if mode == Mode::Strict {
+                        assert_eq!(encoded, bs);
+                    }
+                    let again = decode(&encoded, Options::new(Mode::Strict).with_big_ints()).unwrap();
+                    assert_eq!(BValue::from(&again), BValue::from(&bv));
+                    assert_eq!(bv.raw(), bs);
+                },
+                Err(e) => assert!(e.offset <= bs.len(), "{:?} past the end of {:?}", e, bs),
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn any_bytes(bs in vec(any::<u8>(), 0..64)) {
+            check_decode(&bs);
+        }
+
+        #[test]
+        fn mangled_values(bs in arbitrary::mangled()) {
+            check_decode(&bs);
+        }
+    }
 }
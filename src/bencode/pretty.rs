@@ -0,0 +1,234 @@
+//! Human-readable, indented dumps of bencode.
+//!
+//! Dumps are driven by the streaming `Tokenizer`, so dicts keep the order
+//! (and any duplicates) of the input and every line can show the byte
+//! offset its value starts at.
+
+use std::str;
+use std::fmt::Write;
+
+use super::{BVal, BencodeError, Encode, Mode, Options, ParseLimits, to_hex};
+use super::stream::{Tokenizer, Token};
+
+static HASH_LEN: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DumpOptions {
+    /// Start each line with the byte offset of its value.
+    pub offsets: bool,
+    /// Binary strings longer than this are cut short.
+    pub max_bytes: usize,
+    /// Hash lists longer than this only show their first hashes.
+    pub max_hashes: usize,
+}
+
+impl Default for DumpOptions {
+    fn default() -> DumpOptions {
+        DumpOptions{ offsets: false, max_bytes: 32, max_hashes: 8 }
+    }
+}
+
+/// Dumps raw bencode, which may hold several values one after another.
+pub fn dump(input: &[u8], opts: &DumpOptions) -> Result<String, BencodeError> {
+    dump_with(input, Options::new(Mode::Lenient).with_big_ints(), opts)
+}
+
+/// Dumps an already parsed value as it was in the input, dict key order and
+/// duplicates included. Values built by hand have their dict keys sorted.
+pub fn pretty(bv: &BVal) -> String {
+    let parse = Options::new(Mode::Lenient).with_big_ints().with_limits(ParseLimits::none());
+    let encoded;
+    let input = if bv.raw().is_empty() {
+        encoded = bv.encode();
+        &encoded[..]
+    } else {
+        bv.raw()
+    };
+    dump_with(input, parse, &DumpOptions::default())
+        .expect("a parsed or encoded value always parses")
+}
+
+struct Printer<'d> {
+    out: String,
+    opts: &'d DumpOptions,
+    // digits in the largest offset, to line offsets up
+    width: usize,
+}
+
+impl <'d> Printer<'d> {
+    fn line(&mut self, offset: usize, depth: usize, text: &str) {
+        if self.opts.offsets {
+            write!(self.out, "{:>1$}  ", offset, self.width).unwrap();
+        }
+        for _ in 0..depth {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+}
+
+fn dump_with(input: &[u8], parse: Options, opts: &DumpOptions) -> Result<String, BencodeError> {
+    let mut tokens = Tokenizer::new(parse).with_max_size(usize::max_value());
+    tokens.push(input);
+    let mut p = Printer{ out: String::new(), opts: opts, width: input.len().to_string().len() };
+    let mut closers = Vec::new();
+    // a dict entry's line starts at its key
+    let mut key: Option<(usize, Vec<u8>)> = None;
+    loop {
+        let start = tokens.offset();
+        let token = match tokens.next_token()? {
+            Some(token) => token,
+            None => break,
+        };
+        let depth = closers.len();
+        let (start, label) = match key.take() {
+            Some((at, k)) => (at, format!("{}: ", key_name(&k))),
+            None => (start, String::new()),
+        };
+        match token {
+            Token::Key(k) => key = Some((start, k)),
+            Token::End => p.line(start, depth - 1, closers.pop().unwrap()),
+            Token::ListStart => {
+                p.line(start, depth, &format!("{}[", label));
+                closers.push("]");
+            },
+            Token::DictStart => {
+                p.line(start, depth, &format!("{}{{", label));
+                closers.push("}");
+            },
+            Token::Int(i) => p.line(start, depth, &format!("{}{}", label, i)),
+            Token::BigInt(s) => p.line(start, depth, &format!("{}{}", label, s)),
+            Token::Str(bs) => {
+                let data = tokens.offset() - bs.len();
+                let (head, rest) = describe(&bs, opts);
+                p.line(start, depth, &format!("{}{}", label, head));
+                for (at, text) in rest {
+                    p.line(data + at, depth + 1, &text);
+                }
+            },
+        }
+    }
+    tokens.finish()?;
+    Ok(p.out)
+}
+
+fn key_name(k: &[u8]) -> String {
+    match text(k) {
+        Some(s) => s.to_string(),
+        None => format!("0x{}", to_hex(k)),
+    }
+}
+
+// Strings that read as text, rather than binary that happens to be UTF-8.
+fn text(bs: &[u8]) -> Option<&str> {
+    str::from_utf8(bs).ok()
+        .and_then(|s| if s.chars().any(|c| c.is_control() && c != '\n' && c != '\t') { None } else { Some(s) })
+}
+
+// A one line summary, plus any lines to go under it with their offsets
+// into the string.
+fn describe(bs: &[u8], opts: &DumpOptions) -> (String, Vec<(usize, String)>) {
+    if let Some(s) = text(bs) {
+        return (format!("{:?}", s), Vec::new());
+    }
+    if !bs.is_empty() && bs.len() % HASH_LEN == 0 && bs.len() > opts.max_bytes {
+        let count = bs.len() / HASH_LEN;
+        let mut lines: Vec<(usize, String)> = bs.chunks(HASH_LEN)
+            .take(opts.max_hashes)
+            .enumerate()
+            .map(|(i, hash)| (i * HASH_LEN, format!("[{}] {}", i, to_hex(hash))))
+            .collect();
+        if count > opts.max_hashes {
+            lines.push((opts.max_hashes * HASH_LEN, format!("... {} more", count - opts.max_hashes)));
+        }
+        return (format!("<{} bytes, {} hashes>", bs.len(), count), lines);
+    }
+    if bs.len() > opts.max_bytes {
+        (format!("<{} bytes> {}...", bs.len(), to_hex(&bs[..opts.max_bytes])), Vec::new())
+    } else {
+        (format!("<{} bytes> {}", bs.len(), to_hex(bs)), Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::{ParseError, bval};
+    use nom::IResult;
+
+    #[test]
+    fn nested_values() {
+        let input = &b"d4:name4:spam5:filesld6:lengthi12eee2:\xff\x00i-1ee"[..];
+        assert_eq!(dump(input, &DumpOptions::default()).unwrap(), "\
+{
+  name: \"spam\"
+  files: [
+    {
+      length: 12
+    }
+  ]
+  0xff00: -1
+}
+");
+    }
+
+    #[test]
+    fn offsets() {
+        let input = &b"l4:spamli7eee"[..];
+        let opts = DumpOptions{ offsets: true, ..DumpOptions::default() };
+        assert_eq!(dump(input, &opts).unwrap(), concat!(
+            " 0  [\n",
+            " 1    \"spam\"\n",
+            " 7    [\n",
+            " 8      7\n",
+            "11    ]\n",
+            "12  ]\n"));
+    }
+
+    #[test]
+    fn binary_strings() {
+        let opts = DumpOptions{ offsets: true, max_bytes: 4, max_hashes: 2 };
+        let mut input = b"l6:\x7f\x00\x00\x01\x1a\xe13:\x01\x02\x0360:".to_vec();
+        for i in 0..60 {
+            input.push(i as u8);
+        }
+        input.push(b'e');
+        assert_eq!(dump(&input, &opts).unwrap(), concat!(
+            " 0  [\n",
+            " 1    <6 bytes> 7f000001...\n",
+            " 9    <3 bytes> 010203\n",
+            "14    <60 bytes, 3 hashes>\n",
+            "17      [0] 000102030405060708090a0b0c0d0e0f10111213\n",
+            "37      [1] 1415161718191a1b1c1d1e1f2021222324252627\n",
+            "57      ... 1 more\n",
+            "77  ]\n"));
+    }
+
+    #[test]
+    fn pretty_parsed_value() {
+        match bval(&b"d1:bi2e1:ali1ee1:bi3ee"[..]) {
+            IResult::Done(_, bv) => {
+                assert_eq!(pretty(&bv), "{\n  b: 2\n  a: [\n    1\n  ]\n  b: 3\n}\n");
+                let built = BVal::BDict(&b""[..], bv.as_bdict().unwrap().into_unique());
+                assert_eq!(pretty(&built), "{\n  a: [\n    1\n  ]\n  b: 3\n}\n");
+            },
+            _ => panic!("should parse"),
+        }
+    }
+
+    #[test]
+    fn sample_torrent() {
+        let bs = &include_bytes!("../../sample.mp4.torrent")[..];
+        let out = dump(bs, &DumpOptions::default()).unwrap();
+        assert!(out.contains("  announce: \"http://v4.torrent.speedpartner.de:6969/announce\"\n"));
+        assert!(out.contains("    pieces: <600 bytes, 30 hashes>\n      [0] 83bc0cc5c9a3eb4d86729d29a2f0e1e0158b7bd6\n"));
+        assert!(out.contains("      ... 22 more\n"));
+    }
+
+    #[test]
+    fn broken_input() {
+        let e = dump(&b"d1:ai1e"[..], &DumpOptions::default()).unwrap_err();
+        assert_eq!((e.kind, e.offset), (ParseError::UnexpectedEof, 7));
+    }
+}
@@ -0,0 +1,233 @@
+//! Navigating nested `BVal`s by path, like `info.files[0].length`.
+//!
+//! A `Cursor` is a value plus the path it was reached by, so a missing key
+//! or a value of the wrong type deep inside a document is reported with its
+//! full path instead of just the last key.
+
+use std::str::FromStr;
+use super::{BVal, Dict, ReadError, Path, PathSegment, StrKeys};
+
+/// Parses queries like `info.files[0].length` or `[2]`; the empty query is
+/// the root. Keys can't contain `.`, `[` or `]`.
+impl FromStr for Path {
+    type Err = ReadError;
+
+    fn from_str(query: &str) -> Result<Path, ReadError> {
+        let bad = || ReadError::BadQuery(query.to_string());
+        let mut segments = Vec::new();
+        if query.is_empty() {
+            return Ok(Path(segments));
+        }
+        for (i, part) in query.split('.').enumerate() {
+            let key_end = part.find('[').unwrap_or(part.len());
+            let (key, mut indices) = part.split_at(key_end);
+            if key.contains(']') || (key.is_empty() && (i > 0 || indices.is_empty())) {
+                return Err(bad());
+            }
+            if !key.is_empty() {
+                segments.push(PathSegment::Key(key.as_bytes().to_vec()));
+            }
+            while !indices.is_empty() {
+                let close = indices.find(']').ok_or_else(&bad)?;
+                let index: usize = indices[1..close].parse().or_else(|_| Err(bad()))?;
+                segments.push(PathSegment::Index(index));
+                indices = &indices[close + 1..];
+                if !indices.is_empty() && !indices.starts_with('[') {
+                    return Err(bad());
+                }
+            }
+        }
+        Ok(Path(segments))
+    }
+}
+
+impl Path {
+    pub fn child(&self, segment: PathSegment) -> Path {
+        let mut segments = self.0.clone();
+        segments.push(segment);
+        Path(segments)
+    }
+}
+
+/// A value inside a document, along with where it is.
+#[derive(Debug, Clone)]
+pub struct Cursor<'b, 'a: 'b> {
+    pub value: &'b BVal<'a>,
+    pub path: Path,
+}
+
+impl <'a> BVal<'a> {
+    /// A `Cursor` at this value, as the root.
+    pub fn cursor<'b>(&'b self) -> Cursor<'b, 'a> {
+        Cursor{ value: self, path: Path(Vec::new()) }
+    }
+
+    /// The value at `query`, relative to this one.
+    pub fn at<'b>(&'b self, query: &str) -> Result<Cursor<'b, 'a>, ReadError> {
+        self.cursor().at(query)
+    }
+}
+
+impl <'b, 'a: 'b> Cursor<'b, 'a> {
+    /// The value at `query`, relative to this one.
+    pub fn at(&self, query: &str) -> Result<Cursor<'b, 'a>, ReadError> {
+        self.get(query)?.ok_or_else(|| ReadError::Missing(self.path_to(query)))
+    }
+
+    /// Like `at`, but `None` if only the last key or index is missing.
+    pub fn get(&self, query: &str) -> Result<Option<Cursor<'b, 'a>>, ReadError> {
+        let Path(segments) = query.parse()?;
+        let mut cursor = self.clone();
+        let last = segments.len();
+        for (i, segment) in segments.into_iter().enumerate() {
+            let value: &'b BVal<'a> = cursor.value;
+            let child = match segment {
+                PathSegment::Key(ref k) => cursor.dict()?.get(&k[..]),
+                PathSegment::Index(n) => value.as_blist()
+                    .or_else(|e| Err(cursor.wrap(e)))?
+                    .get(n),
+            };
+            let path = cursor.path.child(segment);
+            cursor = match child {
+                Some(value) => Cursor{ value: value, path: path },
+                None if i + 1 == last => return Ok(None),
+                None => return Err(ReadError::Missing(path)),
+            };
+        }
+        Ok(Some(cursor))
+    }
+
+    fn path_to(&self, query: &str) -> Path {
+        let Path(mut segments) = self.path.clone();
+        if let Ok(Path(rest)) = query.parse() {
+            segments.extend(rest);
+        }
+        Path(segments)
+    }
+
+    fn wrap(&self, err: ReadError) -> ReadError {
+        ReadError::At{ path: self.path.clone(), err: Box::new(err) }
+    }
+
+    pub fn int(&self) -> Result<i64, ReadError> {
+        self.value.as_bint().or_else(|e| Err(self.wrap(e)))
+    }
+
+    pub fn str(&self) -> Result<&'a str, ReadError> {
+        self.value.as_bstring_str().or_else(|e| Err(self.wrap(e)))
+    }
+
+    pub fn bytes(&self) -> Result<&'a [u8], ReadError> {
+        self.value.as_bstring_bytes().or_else(|e| Err(self.wrap(e)))
+    }
+
+    /// The elements, each with its own path.
+    pub fn list(&self) -> Result<Vec<Cursor<'b, 'a>>, ReadError> {
+        let value: &'b BVal<'a> = self.value;
+        let bvs = value.as_blist().or_else(|e| Err(self.wrap(e)))?;
+        Ok(bvs.iter().enumerate()
+            .map(|(i, bv)| Cursor{ value: bv, path: self.path.child(PathSegment::Index(i)) })
+            .collect())
+    }
+
+    pub fn dict(&self) -> Result<&'b Dict<'a>, ReadError> {
+        let value: &'b BVal<'a> = self.value;
+        value.as_bdict_ref().or_else(|e| Err(self.wrap(e)))
+    }
+
+    /// The raw bytes of a dict, e.g. to hash `info`.
+    pub fn dict_input(&self) -> Result<&'a [u8], ReadError> {
+        match *self.value {
+            BVal::BDict(input, _) => Ok(input),
+            // not a dict, so this is just the error
+            _ => self.dict().map(|_| &b""[..]),
+        }
+    }
+
+    pub fn int_at(&self, query: &str) -> Result<i64, ReadError> {
+        self.at(query)?.int()
+    }
+
+    pub fn str_at(&self, query: &str) -> Result<&'a str, ReadError> {
+        self.at(query)?.str()
+    }
+
+    pub fn bytes_at(&self, query: &str) -> Result<&'a [u8], ReadError> {
+        self.at(query)?.bytes()
+    }
+
+    pub fn list_at(&self, query: &str) -> Result<Vec<Cursor<'b, 'a>>, ReadError> {
+        self.at(query)?.list()
+    }
+}
+
+impl <'b, 'a: 'b> StrKeys<BVal<'a>> for Cursor<'b, 'a> {
+    fn get_str(&self, key: &str) -> Option<&BVal<'a>> {
+        self.value.as_bdict_ref().ok().and_then(|m| m.get_str(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::{BVal, ReadError, Path, PathSegment, bval};
+    use bencode::{BINT_TYPE_NAME, BSTRING_TYPE_NAME, BDICT_TYPE_NAME};
+    use nom::IResult;
+
+    fn parse(input: &[u8]) -> BVal {
+        match bval(input) {
+            IResult::Done(_, bv) => bv,
+            _ => panic!("should parse"),
+        }
+    }
+
+    #[test]
+    fn parse_queries() {
+        let key = |k: &str| PathSegment::Key(k.as_bytes().to_vec());
+        assert_eq!("".parse(), Ok(Path(vec![])));
+        assert_eq!("info.files[0].length".parse(),
+                   Ok(Path(vec![key("info"), key("files"), PathSegment::Index(0), key("length")])));
+        assert_eq!("[2][10].piece length".parse(),
+                   Ok(Path(vec![PathSegment::Index(2), PathSegment::Index(10), key("piece length")])));
+        for bad in &["a..b", ".a", "a[", "a[x]", "a[1]b", "a]", "a.[0]"] {
+            assert_eq!(bad.parse::<Path>(), Err(ReadError::BadQuery(bad.to_string())));
+        }
+        let path: Path = "info.files[3].path[1]".parse().unwrap();
+        assert_eq!(path.to_string(), "info.files[3].path[1]");
+    }
+
+    #[test]
+    fn typed_getters() {
+        let bv = parse(b"d4:infod5:filesld6:lengthi12e4:pathl1:a1:beeeee");
+        assert_eq!(bv.at("info.files[0].length").and_then(|c| c.int()), Ok(12));
+        let file = bv.at("info.files[0]").unwrap();
+        assert_eq!(file.str_at("path[1]"), Ok("b"));
+        let parts: Vec<&str> = file.list_at("path").unwrap().iter().map(|c| c.str().unwrap()).collect();
+        assert_eq!(parts, vec!["a", "b"]);
+        assert_eq!(file.list_at("path").unwrap()[1].path.to_string(), "info.files[0].path[1]");
+    }
+
+    #[test]
+    fn errors_carry_full_path() {
+        let bv = parse(b"d4:infod5:filesld6:lengthi12e4:pathl1:a1:beeeee");
+        let file = bv.at("info.files[0]").unwrap();
+        assert_eq!(file.str_at("length"), Err(ReadError::At{
+            path: "info.files[0].length".parse().unwrap(),
+            err: Box::new(ReadError::WrongType{found: BINT_TYPE_NAME, expected: BSTRING_TYPE_NAME}),
+        }));
+        assert_eq!(file.int_at("name").unwrap_err().to_string(), "missing info.files[0].name");
+        assert_eq!(bv.at("info.files[1]").err(), Some(ReadError::Missing("info.files[1]".parse().unwrap())));
+        assert_eq!(bv.at("info.nope.x").err(), Some(ReadError::Missing("info.nope".parse().unwrap())));
+        assert_eq!(bv.at("info.files.x").unwrap_err().to_string(),
+                   format!("expected {} but found BList at info.files", BDICT_TYPE_NAME));
+    }
+
+    #[test]
+    fn optional_values() {
+        let bv = parse(b"d8:intervali1800ee");
+        assert_eq!(bv.cursor().get("interval").unwrap().map(|c| c.int()), Some(Ok(1800)));
+        assert!(bv.cursor().get("complete").unwrap().is_none());
+        assert!(bv.cursor().get("complete.x").is_err());
+        assert!(bv.cursor().contains_str("interval"));
+    }
+}
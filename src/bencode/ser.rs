@@ -0,0 +1,453 @@
+//! A serde `Serializer` producing bencode.
+//!
+//! Integers (and `bool`s, as `0`/`1`) become `BInt`s, strings and byte
+//! slices become `BString`s, sequences become `BList`s, and maps and structs
+//! become `BDict`s with their keys sorted by raw bytes. `None` fields are
+//! left out of dicts entirely; anywhere else, such as in a list, a `None`
+//! has nothing to become and is an error. Use `#[serde(with = "serde_bytes")]` to write a
+//! `Vec<u8>`/`&[u8]` field as a `BString` rather than a list of ints.
+//!
+//! Bencode has no floats and no unit, so those are rejected.
+
+use std::io;
+use std::io::Write;
+use std::fmt;
+use std::error;
+use std::result::Result;
+
+use serde::ser;
+use serde::ser::{Serialize, Impossible};
+
+use super::encode::{write_bstring, write_bint};
+
+#[derive(Debug)]
+pub enum Error {
+    Custom(String),
+    UnsupportedType(&'static str),
+    IntegerOutOfRange(u64),
+    KeyMustBeAString,
+    DuplicateKey(Vec<u8>),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Custom(ref msg) => write!(f, "{}", msg),
+            Error::UnsupportedType(t) => write!(f, "bencode can't represent {}", t),
+            Error::IntegerOutOfRange(n) => write!(f, "{} doesn't fit in a bencode integer", n),
+            Error::KeyMustBeAString => write!(f, "dict keys must be strings or bytes"),
+            Error::DuplicateKey(ref k) => write!(f, "duplicate dict key {:?}", String::from_utf8_lossy(k)),
+            Error::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::Custom(msg.to_string())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+pub fn to_bytes<T: ?Sized + Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut ser = Serializer::new();
+    value.serialize(&mut ser)?;
+    Ok(ser.into_inner())
+}
+
+pub fn to_writer<W: Write, T: ?Sized + Serialize>(w: &mut W, value: &T) -> Result<(), Error> {
+    let bytes = to_bytes(value)?;
+    w.write_all(&bytes[..]).map_err(Error::Io)
+}
+
+pub struct Serializer {
+    out: Vec<u8>,
+    // writing a dict value, where `None` leaves the key out
+    field: bool,
+}
+
+impl Serializer {
+    pub fn new() -> Serializer {
+        Serializer{ out: Vec::new(), field: false }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.out
+    }
+
+    fn int(&mut self, i: i64) -> Result<(), Error> {
+        write_bint(&mut self.out, i).map_err(Error::Io)
+    }
+
+    fn bytes(&mut self, bs: &[u8]) -> Result<(), Error> {
+        write_bstring(&mut self.out, bs).map_err(Error::Io)
+    }
+
+    fn dict(&mut self, variant: Option<&'static str>) -> Dict {
+        Dict{ ser: self, variant: variant, entries: Vec::new(), key: None }
+    }
+}
+
+impl <'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Dict<'a>;
+    type SerializeStruct = Dict<'a>;
+    type SerializeStructVariant = Dict<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> { self.int(if v { 1 } else { 0 }) }
+    fn serialize_i8(self, v: i8) -> Result<(), Error> { self.int(v as i64) }
+    fn serialize_i16(self, v: i16) -> Result<(), Error> { self.int(v as i64) }
+    fn serialize_i32(self, v: i32) -> Result<(), Error> { self.int(v as i64) }
+    fn serialize_i64(self, v: i64) -> Result<(), Error> { self.int(v) }
+    fn serialize_u8(self, v: u8) -> Result<(), Error> { self.int(v as i64) }
+    fn serialize_u16(self, v: u16) -> Result<(), Error> { self.int(v as i64) }
+    fn serialize_u32(self, v: u32) -> Result<(), Error> { self.int(v as i64) }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        if v > i64::max_value() as u64 {
+            Err(Error::IntegerOutOfRange(v))
+        } else {
+            self.int(v as i64)
+        }
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<(), Error> { Err(Error::UnsupportedType("f32")) }
+    fn serialize_f64(self, _v: f64) -> Result<(), Error> { Err(Error::UnsupportedType("f64")) }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        let mut buf = [0; 4];
+        self.bytes(v.encode_utf8(&mut buf).as_bytes())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> { self.bytes(v.as_bytes()) }
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> { self.bytes(v) }
+
+    // As a whole dict value, writes nothing so the dict drops the key.
+    fn serialize_none(self) -> Result<(), Error> {
+        if self.field && self.out.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedType("None outside a dict"))
+        }
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> { Err(Error::UnsupportedType("()")) }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<(), Error> {
+        Err(Error::UnsupportedType(name))
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str)
+        -> Result<(), Error> {
+        self.bytes(variant.as_bytes())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T)
+        -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _name: &'static str, _index: u32,
+                                                        variant: &'static str, value: &T)
+        -> Result<(), Error> {
+        self.out.push(b'd');
+        self.bytes(variant.as_bytes())?;
+        value.serialize(&mut *self)?;
+        self.out.push(b'e');
+        Ok(())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self, Error> {
+        self.out.push(b'l');
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Self, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                               _len: usize) -> Result<Self, Error> {
+        self.out.push(b'd');
+        self.bytes(variant.as_bytes())?;
+        self.out.push(b'l');
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Dict<'a>, Error> {
+        Ok(self.dict(None))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Dict<'a>, Error> {
+        Ok(self.dict(None))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                                _len: usize) -> Result<Dict<'a>, Error> {
+        Ok(self.dict(Some(variant)))
+    }
+}
+
+impl <'a> ser::SerializeSeq for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.out.push(b'e');
+        Ok(())
+    }
+}
+
+impl <'a> ser::SerializeTuple for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl <'a> ser::SerializeTupleStruct for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl <'a> ser::SerializeTupleVariant for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        // closes both the list and the wrapping `{variant: ...}` dict
+        self.out.extend_from_slice(b"ee");
+        Ok(())
+    }
+}
+
+/// Buffers encoded entries so they can be written out sorted by key.
+pub struct Dict<'a> {
+    ser: &'a mut Serializer,
+    variant: Option<&'static str>,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    key: Option<Vec<u8>>,
+}
+
+impl <'a> Dict<'a> {
+    fn push<T: ?Sized + Serialize>(&mut self, key: Vec<u8>, value: &T) -> Result<(), Error> {
+        let mut field = Serializer{ out: Vec::new(), field: true };
+        value.serialize(&mut field)?;
+        let encoded = field.into_inner();
+        // an empty encoding means `None`: leave the key out
+        if !encoded.is_empty() {
+            self.entries.push((key, encoded));
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        let Dict{ ser, variant, mut entries, .. } = self;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for w in entries.windows(2) {
+            if w[0].0 == w[1].0 {
+                return Err(Error::DuplicateKey(w[0].0.clone()));
+            }
+        }
+
+        if let Some(v) = variant {
+            ser.out.push(b'd');
+            ser.bytes(v.as_bytes())?;
+        }
+        ser.out.push(b'd');
+        for (k, v) in entries {
+            ser.bytes(&k[..])?;
+            ser.out.extend_from_slice(&v[..]);
+        }
+        ser.out.push(b'e');
+        if variant.is_some() {
+            ser.out.push(b'e');
+        }
+        Ok(())
+    }
+}
+
+impl <'a> ser::SerializeMap for Dict<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(key.serialize(KeySerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.key.take().expect("serialize_value called before serialize_key");
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl <'a> ser::SerializeStruct for Dict<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T)
+        -> Result<(), Error> {
+        self.push(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl <'a> ser::SerializeStructVariant for Dict<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T)
+        -> Result<(), Error> {
+        self.push(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+/// Turns a map key into the raw bytes of its `BString`.
+struct KeySerializer;
+
+impl ser::Serializer for KeySerializer {
+    type Ok = Vec<u8>;
+    type Error = Error;
+
+    type SerializeSeq = Impossible<Vec<u8>, Error>;
+    type SerializeTuple = Impossible<Vec<u8>, Error>;
+    type SerializeTupleStruct = Impossible<Vec<u8>, Error>;
+    type SerializeTupleVariant = Impossible<Vec<u8>, Error>;
+    type SerializeMap = Impossible<Vec<u8>, Error>;
+    type SerializeStruct = Impossible<Vec<u8>, Error>;
+    type SerializeStructVariant = Impossible<Vec<u8>, Error>;
+
+    fn serialize_str(self, v: &str) -> Result<Vec<u8>, Error> { Ok(v.as_bytes().to_vec()) }
+    fn serialize_bytes(self, v: &[u8]) -> Result<Vec<u8>, Error> { Ok(v.to_vec()) }
+
+    fn serialize_char(self, v: char) -> Result<Vec<u8>, Error> {
+        let mut buf = [0; 4];
+        Ok(v.encode_utf8(&mut buf).as_bytes().to_vec())
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str)
+        -> Result<Vec<u8>, Error> {
+        Ok(variant.as_bytes().to_vec())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T)
+        -> Result<Vec<u8>, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _v: bool) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+    fn serialize_i8(self, _v: i8) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+    fn serialize_i16(self, _v: i16) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+    fn serialize_i32(self, _v: i32) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+    fn serialize_i64(self, _v: i64) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+    fn serialize_u8(self, _v: u8) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+    fn serialize_u16(self, _v: u16) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+    fn serialize_u32(self, _v: u32) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+    fn serialize_u64(self, _v: u64) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+    fn serialize_f32(self, _v: f32) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+    fn serialize_f64(self, _v: f64) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+    fn serialize_none(self) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+    fn serialize_unit(self) -> Result<Vec<u8>, Error> { Err(Error::KeyMustBeAString) }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, _value: &T) -> Result<Vec<u8>, Error> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Vec<u8>, Error> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _name: &'static str, _index: u32,
+                                                        _variant: &'static str, _value: &T)
+        -> Result<Vec<u8>, Error> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize)
+        -> Result<Self::SerializeTupleStruct, Error> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, _variant: &'static str,
+                               _len: usize) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize)
+        -> Result<Self::SerializeStruct, Error> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, _variant: &'static str,
+                                _len: usize) -> Result<Self::SerializeStructVariant, Error> {
+        Err(Error::KeyMustBeAString)
+    }
+}
@@ -0,0 +1,125 @@
+//! Where parsed values came from in their input.
+//!
+//! Every `BVal` borrows the exact bytes it was parsed from, so a value deep
+//! inside a document can be hashed as-is, located by byte offset, or swapped
+//! out without re-encoding everything around it.
+
+use std::ops::Range;
+
+use super::{BVal, Encode};
+
+impl <'a> BVal<'a> {
+    /// The bytes this value was parsed from, e.g. `i42e` or `4:spam`.
+    /// Empty for values built by hand.
+    pub fn raw(&self) -> &'a [u8] {
+        match *self {
+            BVal::BString(raw, _) => raw,
+            BVal::BInt(raw, _) => raw,
+            BVal::BBigInt(raw, _) => raw,
+            BVal::BList(raw, _) => raw,
+            BVal::BDict(raw, _) => raw,
+        }
+    }
+
+    /// The byte offsets of this value in `input`, or `None` if it wasn't
+    /// parsed from `input`.
+    pub fn span(&self, input: &[u8]) -> Option<Range<usize>> {
+        let raw = self.raw();
+        let start = (raw.as_ptr() as usize).wrapping_sub(input.as_ptr() as usize);
+        if raw.is_empty() || start > input.len() || raw.len() > input.len() - start {
+            return None;
+        }
+        Some(start..start + raw.len())
+    }
+}
+
+/// A copy of `input` with `target`, which must have been parsed from it,
+/// replaced by `replacement`. Everything else is copied byte for byte.
+pub fn rewrite<E: Encode>(input: &[u8], target: &BVal, replacement: &E) -> Option<Vec<u8>> {
+    target.span(input).map(|span| {
+        let mut out = Vec::with_capacity(input.len());
+        out.extend_from_slice(&input[..span.start]);
+        out.extend_from_slice(&replacement.encode());
+        out.extend_from_slice(&input[span.end..]);
+        out
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::{BVal, BValue, Encode, Mode, Options, StrKeys, bval, bval_strict, bval_with};
+    use crypto::digest::Digest;
+    use crypto::sha1::Sha1;
+    use nom::IResult;
+
+    fn parse(input: &[u8]) -> BVal {
+        match bval(input) {
+            IResult::Done(_, bv) => bv,
+            _ => panic!("should parse"),
+        }
+    }
+
+    #[test]
+    fn every_node_has_its_raw_input() {
+        let input = &b"d1:ai-7e1:bl4:spami99999999999999999999eee"[..];
+        let bv = match bval_with(input, Options::new(Mode::Lenient).with_big_ints()) {
+            IResult::Done(_, bv) => bv,
+            _ => panic!("should parse"),
+        };
+        assert_eq!(bv.raw(), input);
+        let m = bv.as_bdict_ref().unwrap();
+        assert_eq!(m.get_str("a").unwrap().raw(), &b"i-7e"[..]);
+        let b = m.get_str("b").unwrap();
+        assert_eq!(b.raw(), &b"l4:spami99999999999999999999ee"[..]);
+        let items = b.as_blist().unwrap();
+        assert_eq!(items[0].raw(), &b"4:spam"[..]);
+        assert_eq!(items[1].raw(), &b"i99999999999999999999e"[..]);
+        assert_eq!(BVal::BInt(&b""[..], 1).raw(), &b""[..]);
+    }
+
+    #[test]
+    fn spans() {
+        let input = &b"d1:ai-7e1:bl4:spamee"[..];
+        let bv = parse(input);
+        assert_eq!(bv.span(input), Some(0..input.len()));
+        assert_eq!(bv.at("a").unwrap().value.span(input), Some(4..8));
+        assert_eq!(bv.at("b[0]").unwrap().value.span(input), Some(12..18));
+        // not from this input
+        assert_eq!(bv.span(&input.to_vec()), None);
+        assert_eq!(BVal::BInt(&b""[..], 1).span(input), None);
+    }
+
+    #[test]
+    fn hash_a_sub_structure() {
+        let bs = &include_bytes!("../../sample.mp4.torrent")[..];
+        let bv = match bval_strict(bs) {
+            IResult::Done(_, bv) => bv,
+            _ => panic!("should parse"),
+        };
+        let info = bv.at("info").unwrap().value;
+        let span = info.span(bs).unwrap();
+        let hash = |bs: &[u8]| {
+            let mut hasher = Sha1::new();
+            hasher.input(bs);
+            hasher.result_str()
+        };
+        assert_eq!(hash(info.raw()), hash(&bs[span]));
+        // the same bytes a re-encode would give, since the sample is canonical
+        assert_eq!(info.raw(), &info.encode()[..]);
+    }
+
+    #[test]
+    fn rewrite_one_field() {
+        // non-canonical bytes elsewhere are kept as they are
+        let input = &b"d8:announce3:old4:infod6:lengthi012eee"[..];
+        let bv = parse(input);
+        let announce = bv.at("announce").unwrap().value;
+        let out = rewrite(input, announce, &BValue::from("http://new")).unwrap();
+        assert_eq!(out, b"d8:announce10:http://new4:infod6:lengthi012eee".to_vec());
+        let length = bv.at("info.length").unwrap().value;
+        assert_eq!(rewrite(input, length, &BValue::BInt(7)).unwrap(),
+                   b"d8:announce3:old4:infod6:lengthi7eee".to_vec());
+        assert_eq!(rewrite(input, &BVal::BInt(&b""[..], 1), &BValue::BInt(7)), None);
+    }
+}
@@ -0,0 +1,616 @@
+//! A push-based bencode decoder for input that arrives in pieces.
+//!
+//! Bytes are fed in with `push` as they come off a socket, and tokens (or,
+//! with `Decoder`, whole values) are pulled out as soon as they're complete.
+//! Each byte is looked at once: partial integers, length prefixes and
+//! strings are carried over between chunks instead of re-parsed.
+//!
+//! The `ParseLimits` in the `Options` apply to each top level value, and
+//! error offsets count from the start of the stream, not the current chunk.
+
+use std::mem;
+use std::str;
+use std::collections::BTreeMap;
+use nom::is_digit;
+
+use super::{BValue, BencodeError, Path, PathSegment, Mode, Options, ParseError, big_int_text, magnitude};
+
+pub static DEFAULT_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// One piece of a bencode value.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Token {
+    Str(Vec<u8>),
+    Int(i64),
+    BigInt(String),
+    ListStart,
+    DictStart,
+    // a dict key; the next value is its value
+    Key(Vec<u8>),
+    // closes the innermost list or dict
+    End,
+}
+
+enum State {
+    // between tokens
+    Idle,
+    // after the 'i'; the '-' and digits so far
+    Int{ start: usize, text: Vec<u8> },
+    Len{ start: usize, digits: Vec<u8>, key: bool },
+    Str{ start: usize, len: usize, data: Vec<u8>, key: bool },
+}
+
+enum Frame {
+    List{ index: usize },
+    // `key` is set while its value is being read
+    Dict{ key: Option<Vec<u8>>, last: Option<Vec<u8>> },
+}
+
+/// Splits a stream of bencode into `Token`s.
+///
+/// Several top level values may follow each other; `max_size` and the
+/// limits apply to each one separately.
+pub struct Tokenizer {
+    opts: Options,
+    max_size: usize,
+    buf: Vec<u8>,
+    pos: usize,
+    // stream offset of `buf[pos]`
+    offset: usize,
+    // stream offset the current top level value started at
+    value_start: usize,
+    // values so far in the current top level value
+    items: usize,
+    state: State,
+    stack: Vec<Frame>,
+    failed: Option<BencodeError>,
+}
+
+impl Tokenizer {
+    pub fn new(opts: Options) -> Tokenizer {
+        Tokenizer{
+            opts: opts,
+            max_size: DEFAULT_MAX_SIZE,
+            buf: Vec::new(),
+            pos: 0,
+            offset: 0,
+            value_start: 0,
+            items: 0,
+            state: State::Idle,
+            stack: Vec::new(),
+            failed: None,
+        }
+    }
+
+    /// How many lists and dicts may be open at once; shorthand for setting
+    /// `limits.max_depth` in the options.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Tokenizer {
+        self.opts.limits.max_depth = max_depth;
+        self
+    }
+
+    /// The most bytes a single top level value may take up.
+    pub fn with_max_size(self, max_size: usize) -> Tokenizer {
+        Tokenizer{ max_size: max_size, ..self }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// How many lists and dicts are currently open.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// How far into the stream the tokenizer has read. Between tokens this
+    /// is where the next one starts.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The next complete token, or `None` until more input is pushed.
+    ///
+    /// After an error the tokenizer is stuck and keeps returning it.
+    pub fn next_token(&mut self) -> Result<Option<Token>, BencodeError> {
+        if let Some(ref e) = self.failed {
+            return Err(e.clone());
+        }
+        let next = self.step();
+        if let Err(ref e) = next {
+            self.failed = Some(e.clone());
+        }
+        next
+    }
+
+    /// Checks the stream ended between values, once no more input is coming.
+    pub fn finish(&self) -> Result<(), BencodeError> {
+        if let Some(ref e) = self.failed {
+            return Err(e.clone());
+        }
+        match self.state {
+            State::Idle if self.stack.is_empty() && self.pos == self.buf.len() => Ok(()),
+            _ => Err(self.error(self.offset + self.buf.len() - self.pos, ParseError::UnexpectedEof)),
+        }
+    }
+
+    fn error(&self, offset: usize, kind: ParseError) -> BencodeError {
+        let mut path = Vec::new();
+        for frame in &self.stack {
+            match *frame {
+                Frame::List{ index } => path.push(PathSegment::Index(index)),
+                Frame::Dict{ key: Some(ref k), .. } => path.push(PathSegment::Key(k.clone())),
+                Frame::Dict{ key: None, .. } => (),
+            }
+        }
+        BencodeError{ offset: offset, kind: kind, path: Path(path) }
+    }
+
+    // Moves past `n` buffered bytes, as long as that keeps the value in size.
+    fn consume(&mut self, n: usize) -> Result<(), BencodeError> {
+        if self.offset + n - self.value_start > self.max_size {
+            return Err(self.error(self.value_start + self.max_size, ParseError::TooLarge));
+        }
+        self.pos += n;
+        self.offset += n;
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<Option<Token>, BencodeError> {
+        loop {
+            let b = match self.buf.get(self.pos) {
+                Some(&b) => b,
+                None => return Ok(None),
+            };
+            match mem::replace(&mut self.state, State::Idle) {
+                State::Idle => {
+                    if let Some(token) = self.start(b)? {
+                        return Ok(Some(token));
+                    }
+                },
+                State::Int{ start, mut text } => {
+                    let digits = text.len() - if text.first() == Some(&b'-') { 1 } else { 0 };
+                    match b {
+                        b'-' if text.is_empty() => (),
+                        c if is_digit(c) => {
+                            if self.opts.mode == Mode::Strict && digits == 1 && text[text.len() - 1] == b'0' {
+                                return Err(self.error(self.offset - 1, ParseError::LeadingZero));
+                            }
+                        },
+                        b'e' if digits > 0 => {
+                            let token = self.int(start, &text)?;
+                            self.consume(1)?;
+                            self.value_done();
+                            return Ok(Some(token));
+                        },
+                        _ if digits == 0 => return Err(self.error(self.offset, ParseError::EmptyInteger)),
+                        _ => return Err(self.error(self.offset, ParseError::ExpectedEnd)),
+                    }
+                    self.consume(1)?;
+                    text.push(b);
+                    self.state = State::Int{ start: start, text: text };
+                },
+                State::Len{ start, mut digits, key } => {
+                    match b {
+                        c if is_digit(c) => {
+                            if self.opts.mode == Mode::Strict && digits == b"0" {
+                                return Err(self.error(start, ParseError::LeadingZero));
+                            }
+                            self.consume(1)?;
+                            digits.push(b);
+                            self.state = State::Len{ start: start, digits: digits, key: key };
+                        },
+                        b':' => {
+                            let len = match magnitude(&digits) {
+                                Some(n) if n <= usize::max_value() as u64 => n as usize,
+                                _ => return Err(self.error(start, ParseError::IntegerOverflow)),
+                            };
+                            if len > self.opts.limits.max_string_len {
+                                return Err(self.error(start, ParseError::StringTooLong));
+                            }
+                            if len > self.max_size {
+                                return Err(self.error(start, ParseError::TooLarge));
+                            }
+                            self.consume(1)?;
+                            if len == 0 {
+                                return self.string(start, Vec::new(), key).map(Some);
+                            }
+                            self.state = State::Str{ start: start, len: len, data: Vec::new(), key: key };
+                        },
+                        _ => return Err(self.error(self.offset, ParseError::ExpectedColon)),
+                    }
+                },
+                // copied in bulk rather than a byte at a time
+                State::Str{ start, len, mut data, key } => {
+                    let n = (len - data.len()).min(self.buf.len() - self.pos);
+                    data.extend_from_slice(&self.buf[self.pos..self.pos + n]);
+                    self.consume(n)?;
+                    if data.len() == len {
+                        return self.string(start, data, key).map(Some);
+                    }
+                    self.state = State::Str{ start: start, len: len, data: data, key: key };
+                },
+            }
+        }
+    }
+
+    // The first byte of a value, key or closing "e".
+    fn start(&mut self, b: u8) -> Result<Option<Token>, BencodeError> {
+        if self.stack.is_empty() {
+            self.value_start = self.offset;
+            self.items = 0;
+        }
+        let (key, value) = match self.stack.last() {
+            Some(&Frame::Dict{ key: None, .. }) => (true, false),
+            Some(&Frame::Dict{ key: Some(_), .. }) => (false, true),
+            _ => (false, false),
+        };
+        if !key && b != b'e' {
+            if self.items == self.opts.limits.max_items {
+                return Err(self.error(self.offset, ParseError::TooManyItems));
+            }
+            self.items += 1;
+        }
+        match b {
+            // a key needs its value before the dict can close
+            b'e' if value => Err(self.error(self.offset, ParseError::ExpectedValue)),
+            b'e' if !self.stack.is_empty() => {
+                self.consume(1)?;
+                self.stack.pop();
+                self.value_done();
+                Ok(Some(Token::End))
+            },
+            _ if key && !is_digit(b) => Err(self.error(self.offset, ParseError::ExpectedKey)),
+            b'i' => {
+                self.consume(1)?;
+                self.state = State::Int{ start: self.offset, text: Vec::new() };
+                Ok(None)
+            },
+            b'l' | b'd' => {
+                if self.stack.len() >= self.opts.limits.max_depth {
+                    return Err(self.error(self.offset, ParseError::TooDeep));
+                }
+                self.consume(1)?;
+                if b == b'l' {
+                    self.stack.push(Frame::List{ index: 0 });
+                    Ok(Some(Token::ListStart))
+                } else {
+                    self.stack.push(Frame::Dict{ key: None, last: None });
+                    Ok(Some(Token::DictStart))
+                }
+            },
+            c if is_digit(c) => {
+                self.state = State::Len{ start: self.offset, digits: Vec::new(), key: key };
+                Ok(None)
+            },
+            _ => Err(self.error(self.offset, ParseError::ExpectedValue)),
+        }
+    }
+
+    fn int(&self, start: usize, text: &[u8]) -> Result<Token, BencodeError> {
+        let (neg, digits) = match text.split_first() {
+            Some((&b'-', digits)) => (true, digits),
+            _ => (false, text),
+        };
+        let n = magnitude(digits).and_then(|m| {
+            if !neg && m <= i64::max_value() as u64 {
+                Some(m as i64)
+            } else if neg && m <= i64::max_value() as u64 + 1 {
+                Some((m as i64).wrapping_neg())
+            } else {
+                None
+            }
+        });
+        match n {
+            Some(0) if neg && self.opts.mode == Mode::Strict =>
+                Err(self.error(start, ParseError::NegativeZero)),
+            Some(n) => Ok(Token::Int(n)),
+            // only '-' and digits, so always valid UTF-8
+            None if self.opts.big_ints => Ok(Token::BigInt(big_int_text(str::from_utf8(text).unwrap()).into_owned())),
+            None => Err(self.error(start, ParseError::IntegerOverflow)),
+        }
+    }
+
+    fn string(&mut self, start: usize, data: Vec<u8>, key: bool) -> Result<Token, BencodeError> {
+        if !key {
+            self.value_done();
+            return Ok(Token::Str(data));
+        }
+        let strict = self.opts.mode == Mode::Strict;
+        let misplaced = match self.stack.last() {
+            Some(&Frame::Dict{ last: Some(ref last), .. }) if strict && *last == data =>
+                Some(ParseError::DuplicateKey),
+            Some(&Frame::Dict{ last: Some(ref last), .. }) if strict && *last > data =>
+                Some(ParseError::UnsortedKey),
+            _ => None,
+        };
+        if let Some(&mut Frame::Dict{ ref mut key, .. }) = self.stack.last_mut() {
+            *key = Some(data.clone());
+        }
+        match misplaced {
+            Some(kind) => Err(self.error(start, kind)),
+            None => Ok(Token::Key(data)),
+        }
+    }
+
+    fn value_done(&mut self) {
+        match self.stack.last_mut() {
+            Some(&mut Frame::List{ ref mut index }) => *index += 1,
+            Some(&mut Frame::Dict{ ref mut key, ref mut last }) => *last = key.take(),
+            None => (),
+        }
+    }
+}
+
+enum Partial {
+    List(Vec<BValue>),
+    Dict(BTreeMap<Vec<u8>, BValue>, Option<Vec<u8>>),
+}
+
+/// Assembles the tokens from a `Tokenizer` into whole values.
+pub struct Decoder {
+    tokens: Tokenizer,
+    stack: Vec<Partial>,
+}
+
+impl Decoder {
+    pub fn new(opts: Options) -> Decoder {
+        Decoder::from(Tokenizer::new(opts))
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.tokens.push(chunk)
+    }
+
+    /// The next complete top level value, or `None` until more input is pushed.
+    pub fn next_value(&mut self) -> Result<Option<BValue>, BencodeError> {
+        while let Some(token) = self.tokens.next_token()? {
+            let bv = match token {
+                Token::ListStart => { self.stack.push(Partial::List(Vec::new())); continue },
+                Token::DictStart => { self.stack.push(Partial::Dict(BTreeMap::new(), None)); continue },
+                Token::Key(k) => {
+                    if let Some(&mut Partial::Dict(_, ref mut key)) = self.stack.last_mut() {
+                        *key = Some(k);
+                    }
+                    continue
+                },
+                Token::End => match self.stack.pop() {
+                    Some(Partial::List(bvs)) => BValue::BList(bvs),
+                    Some(Partial::Dict(m, _)) => BValue::BDict(m),
+                    None => unreachable!("tokenizer closed a container that wasn't open"),
+                },
+                Token::Str(bs) => BValue::BString(bs),
+                Token::Int(i) => BValue::BInt(i),
+                Token::BigInt(s) => BValue::BBigInt(s),
+            };
+            match self.stack.last_mut() {
+                None => return Ok(Some(bv)),
+                Some(&mut Partial::List(ref mut bvs)) => bvs.push(bv),
+                Some(&mut Partial::Dict(ref mut m, ref mut key)) => {
+                    m.insert(key.take().expect("tokenizer gives a key before each value"), bv);
+                },
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn finish(&self) -> Result<(), BencodeError> {
+        self.tokens.finish()
+    }
+}
+
+impl From<Tokenizer> for Decoder {
+    fn from(tokens: Tokenizer) -> Decoder {
+        Decoder{ tokens: tokens, stack: Vec::new() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::{BValue, Encode, Mode, Options, ParseLimits, ParseError, arbitrary, bval, decode};
+    use nom::IResult;
+    use proptest::prelude::*;
+    use proptest::collection::vec;
+    use proptest::sample::Index;
+
+    fn tokens(input: &[u8], chunk: usize, opts: Options) -> Result<Vec<Token>, BencodeError> {
+        let mut t = Tokenizer::new(opts);
+        let mut out = Vec::new();
+        for c in input.chunks(chunk) {
+            t.push(c);
+            while let Some(token) = t.next_token()? {
+                out.push(token);
+            }
+        }
+        t.finish().map(|_| out)
+    }
+
+    fn err(input: &[u8], t: Tokenizer) -> BencodeError {
+        let mut d = Decoder::from(t);
+        d.push(input);
+        loop {
+            match d.next_value() {
+                Ok(Some(_)) => (),
+                Ok(None) => return d.finish().err().expect("should fail"),
+                Err(e) => return e,
+            }
+        }
+    }
+
+    #[test]
+    fn tokens_across_chunks() {
+        let input = &b"d3:bari-12e3:fool4:spami99eee"[..];
+        let expected = vec![
+            Token::DictStart,
+            Token::Key(b"bar".to_vec()), Token::Int(-12),
+            Token::Key(b"foo".to_vec()), Token::ListStart,
+            Token::Str(b"spam".to_vec()), Token::Int(99),
+            Token::End,
+            Token::End,
+        ];
+        for chunk in 1..input.len() + 1 {
+            assert_eq!(tokens(input, chunk, Mode::Strict.into()), Ok(expected.clone()));
+        }
+    }
+
+    #[test]
+    fn sample_torrent_byte_by_byte() {
+        let bs = &include_bytes!("../../sample.mp4.torrent")[..];
+        let whole = match bval(bs) {
+            IResult::Done(_, bv) => BValue::from(bv),
+            _ => panic!("sample torrent should parse"),
+        };
+        let mut d = Decoder::new(Mode::Strict.into());
+        let mut values = Vec::new();
+        for b in bs {
+            d.push(&[*b]);
+            if let Some(v) = d.next_value().unwrap() {
+                values.push(v);
+            }
+        }
+        assert_eq!(values, vec![whole]);
+        assert_eq!(d.finish(), Ok(()));
+    }
+
+    #[test]
+    fn back_to_back_values() {
+        let mut d = Decoder::new(Mode::Lenient.into());
+        d.push(b"i1e0:l");
+        assert_eq!(d.next_value(), Ok(Some(BValue::BInt(1))));
+        assert_eq!(d.next_value(), Ok(Some(BValue::BString(vec![]))));
+        assert_eq!(d.next_value(), Ok(None));
+        assert_eq!(d.finish().map_err(|e| (e.kind, e.offset)), Err((ParseError::UnexpectedEof, 6)));
+        d.push(b"ee");
+        assert_eq!(d.next_value(), Ok(Some(BValue::BList(vec![]))));
+        assert_eq!(d.next_value().map_err(|e| e.kind), Err(ParseError::ExpectedValue));
+    }
+
+    #[test]
+    fn max_depth() {
+        let t = || Tokenizer::new(Mode::Lenient.into()).with_max_depth(3);
+        assert!(tokens(b"llleee", 1, Mode::Lenient.into()).is_ok());
+        let e = err(b"lld1:xlleeee", t());
+        assert_eq!((e.kind, e.offset), (ParseError::TooDeep, 6));
+        assert_eq!(e.path.to_string(), "[0][0].x");
+        // stops at the limit however much more is pushed
+        let mut deep = t();
+        deep.push(&[b'l'; 100000]);
+        while let Ok(Some(_)) = deep.next_token() {}
+        assert_eq!(deep.depth(), 3);
+    }
+
+    #[test]
+    fn max_size() {
+        let t = || Tokenizer::new(Mode::Lenient.into()).with_max_size(8);
+        let e = err(b"4:spami1234567890e", t());
+        assert_eq!(e.kind, ParseError::TooLarge);
+        // a huge length prefix fails before any of the string arrives
+        let e = err(b"li1e99:", t());
+        assert_eq!((e.kind, e.offset, e.path.to_string()), (ParseError::TooLarge, 4, "[1]".to_string()));
+        // each top level value gets the full allowance
+        let mut d = Decoder::from(t());
+        d.push(b"l4:spamel4:eggse");
+        assert!(d.next_value().unwrap().is_some());
+        assert!(d.next_value().unwrap().is_some());
+    }
+
+    #[test]
+    fn limits() {
+        let limits = ParseLimits{ max_depth: 8, max_items: 3, max_string_len: 4 };
+        let t = || Tokenizer::new(Options::new(Mode::Lenient).with_limits(limits));
+        let e = err(b"li1ei2ei3ee", t());
+        assert_eq!((e.kind, e.offset, e.path.to_string()), (ParseError::TooManyItems, 7, "[2]".to_string()));
+        // keys don't count, and each top level value starts afresh
+        let mut d = Decoder::from(t());
+        d.push(b"d1:ai1e1:bi2eed1:ai1e1:bi2ee");
+        assert!(d.next_value().unwrap().is_some());
+        assert!(d.next_value().unwrap().is_some());
+        let e = err(b"l5:spam!e", t());
+        assert_eq!((e.kind, e.offset), (ParseError::StringTooLong, 1));
+    }
+
+    #[test]
+    fn strict_errors_have_stream_offsets() {
+        let strict = || Tokenizer::new(Mode::Strict.into());
+        let e = err(b"d1:ali1ei02eee", strict());
+        assert_eq!((e.kind, e.offset, e.path.to_string()), (ParseError::LeadingZero, 9, "a[1]".to_string()));
+        let e = err(b"d1:bi1e1:ai2ee", strict());
+        assert_eq!((e.kind, e.offset, e.path.to_string()), (ParseError::UnsortedKey, 7, "a".to_string()));
+        assert_eq!(err(b"d1:ai1e1:ai2ee", strict()).kind, ParseError::DuplicateKey);
+        assert_eq!(err(b"i-0e", strict()).kind, ParseError::NegativeZero);
+        assert_eq!(err(b"01:x", strict()).kind, ParseError::LeadingZero);
+        assert_eq!(err(b"di1ei2ee", strict()).kind, ParseError::ExpectedKey);
+        assert_eq!(err(b"ie", strict()).kind, ParseError::EmptyInteger);
+        assert_eq!(err(b"3;abc", strict()).kind, ParseError::ExpectedColon);
+        assert_eq!(err(b"i12x", strict()).kind, ParseError::ExpectedEnd);
+    }
+
+    #[test]
+    fn key_without_value() {
+        for &(input, offset) in &[(&b"d1:ae"[..], 4), (&b"ld1:ai1e1:bee"[..], 11)] {
+            let whole = decode(input, Mode::Lenient.into()).unwrap_err();
+            assert_eq!((whole.kind, whole.offset), (ParseError::ExpectedValue, offset));
+            for chunk in 1..input.len() + 1 {
+                let e = tokens(input, chunk, Mode::Lenient.into()).unwrap_err();
+                assert_eq!((e.kind, e.offset), (ParseError::ExpectedValue, offset));
+            }
+            let e = err(input, Tokenizer::new(Mode::Lenient.into()));
+            assert_eq!((e.kind, e.offset), (ParseError::ExpectedValue, offset));
+        }
+    }
+
+    #[test]
+    fn big_ints() {
+        let opts = Options::new(Mode::Lenient);
+        assert_eq!(tokens(b"i9223372036854775807e", 4, opts), Ok(vec![Token::Int(i64::max_value())]));
+        assert_eq!(tokens(b"i9223372036854775808e", 4, opts).map_err(|e| e.kind), Err(ParseError::IntegerOverflow));
+        assert_eq!(tokens(b"i-9223372036854775809e", 4, opts.with_big_ints()),
+                   Ok(vec![Token::BigInt("-9223372036854775809".to_string())]));
+        assert_eq!(tokens(b"i-09223372036854775809e", 4, opts.with_big_ints()),
+                   Ok(vec![Token::BigInt("-9223372036854775809".to_string())]));
+        assert_eq!(tokens(b"i009223372036854775808e", 4, opts.with_big_ints()),
+                   Ok(vec![Token::BigInt("9223372036854775808".to_string())]));
+    }
+
+    #[test]
+    fn stays_failed() {
+        let mut t = Tokenizer::new(Mode::Lenient.into());
+        t.push(b"x");
+        let e = t.next_token().unwrap_err();
+        t.push(b"i1e");
+        assert_eq!(t.next_token(), Err(e));
+    }
+
+    proptest! {
+        // however the input is split up, the result is the same as all at once
+        #[test]
+        fn chunks_match_whole(v in arbitrary::bvalue(), cuts in vec(any::<Index>(), 0..6)) {
+            let bs = v.encode();
+            let mut cuts: Vec<usize> = cuts.iter().map(|c| c.index(bs.len() + 1)).collect();
+            cuts.sort();
+            let mut d = Decoder::new(Options::new(Mode::Strict).with_big_ints());
+            let mut last = 0;
+            for cut in cuts.into_iter().chain(Some(bs.len())) {
+                d.push(&bs[last..cut]);
+                last = cut;
+            }
+            prop_assert_eq!(d.next_value(), Ok(Some(v)));
+            prop_assert!(d.finish().is_ok());
+        }
+
+        #[test]
+        fn mangled_matches_decode(bs in arbitrary::mangled()) {
+            let opts = Options::new(Mode::Lenient).with_big_ints();
+            let mut d = Decoder::new(opts);
+            d.push(&bs);
+            let streamed = d.next_value().and_then(|v| d.finish().map(|_| v));
+            match decode(&bs, opts) {
+                Ok(bv) => prop_assert_eq!(streamed, Ok(Some(BValue::from(&bv)))),
+                Err(_) => prop_assert!(streamed.is_err() || streamed == Ok(None)),
+            }
+        }
+    }
+}
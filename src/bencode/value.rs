@@ -0,0 +1,243 @@
+use std::str;
+use std::io;
+use std::io::Write;
+use std::result::Result;
+use std::collections::BTreeMap;
+use nom::IResult;
+
+use super::{BVal, ReadError, Mode, Options, ParseLimits, bval_with};
+use super::{BSTRING_TYPE_NAME, BINT_TYPE_NAME, BBIGINT_TYPE_NAME, BLIST_TYPE_NAME, BDICT_TYPE_NAME};
+use super::encode::{Encode, write_bstring, write_bint, write_blist, write_bdict};
+
+/// An owned bencode value.
+///
+/// Unlike `BVal` this doesn't borrow from the input, so it can outlive the
+/// buffer it was parsed from, be built by hand, and be sent between threads.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum BValue {
+    BString(Vec<u8>),
+    BInt(i64),
+    BBigInt(String),
+    BList(Vec<BValue>),
+    BDict(BTreeMap<Vec<u8>, BValue>),
+}
+
+impl BValue {
+    fn report<T>(&self, expected: &'static str) -> Result<T, ReadError> {
+        match self {
+            &BValue::BString(_) => Err(ReadError::WrongType{found: BSTRING_TYPE_NAME, expected: expected}),
+            &BValue::BInt(_) => Err(ReadError::WrongType{found: BINT_TYPE_NAME, expected: expected}),
+            &BValue::BBigInt(_) => Err(ReadError::WrongType{found: BBIGINT_TYPE_NAME, expected: expected}),
+            &BValue::BList(_) => Err(ReadError::WrongType{found: BLIST_TYPE_NAME, expected: expected}),
+            &BValue::BDict(_) => Err(ReadError::WrongType{found: BDICT_TYPE_NAME, expected: expected}),
+        }
+    }
+
+    pub fn as_bstring_str(&self) -> Result<&str, ReadError> {
+        match self {
+            &BValue::BString(ref bs) => str::from_utf8(bs)
+                .or_else(|e| Err(ReadError::BadString(e))),
+            _ => self.report(BSTRING_TYPE_NAME),
+        }
+    }
+
+    pub fn as_bstring_bytes(&self) -> Result<&[u8], ReadError> {
+        match self {
+            &BValue::BString(ref s) => Result::Ok(s),
+            _ => self.report(BSTRING_TYPE_NAME),
+        }
+    }
+
+    pub fn as_bint(&self) -> Result<i64, ReadError> {
+        match self {
+            &BValue::BInt(i) => Result::Ok(i),
+            _ => self.report(BINT_TYPE_NAME),
+        }
+    }
+
+    pub fn as_bbigint_str(&self) -> Result<&str, ReadError> {
+        match self {
+            &BValue::BBigInt(ref s) => Result::Ok(s),
+            _ => self.report(BBIGINT_TYPE_NAME),
+        }
+    }
+
+    pub fn as_blist(&self) -> Result<&Vec<BValue>, ReadError> {
+        match *self {
+            BValue::BList(ref v) => Result::Ok(v),
+            _ => self.report(BLIST_TYPE_NAME),
+        }
+    }
+
+    pub fn as_bdict_ref(&self) -> Result<&BTreeMap<Vec<u8>, BValue>, ReadError> {
+        match *self {
+            BValue::BDict(ref m) => Result::Ok(m),
+            _ => self.report(BDICT_TYPE_NAME),
+        }
+    }
+
+    pub fn as_bdict(self) -> Result<BTreeMap<Vec<u8>, BValue>, ReadError> {
+        match self {
+            BValue::BDict(m) => Result::Ok(m),
+            _ => self.report(BDICT_TYPE_NAME),
+        }
+    }
+
+    /// Borrows this value as a `BVal`.
+    ///
+    /// A `BVal` dict points at its raw input, so the value is first encoded
+    /// into `buf` and the view is parsed back out of it.
+    pub fn to_bval<'a>(&self, buf: &'a mut Vec<u8>) -> BVal<'a> {
+        buf.clear();
+        self.encode_to(buf).expect("writing to a Vec never fails");
+        let buf: &'a Vec<u8> = buf;
+        let opts = Options::new(Mode::Lenient).with_big_ints().with_limits(ParseLimits::none());
+        match bval_with(&buf[..], opts) {
+            IResult::Done(_, bv) => bv,
+            _ => unreachable!("encoder produced unparseable bencode"),
+        }
+    }
+}
+
+impl <'a, 'b> From<&'b BVal<'a>> for BValue {
+    fn from(bv: &'b BVal<'a>) -> BValue {
+        match *bv {
+            BVal::BString(_, bs) => BValue::BString(bs.to_vec()),
+            BVal::BInt(_, i) => BValue::BInt(i),
+            BVal::BBigInt(_, ref s) => BValue::BBigInt(s.to_string()),
+            BVal::BList(_, ref bvs) => BValue::BList(bvs.iter().map(BValue::from).collect()),
+            BVal::BDict(_, ref m) => BValue::BDict(
+                m.iter().map(|(k, v)| (k.to_vec(), BValue::from(v))).collect()),
+        }
+    }
+}
+
+impl <'a> From<BVal<'a>> for BValue {
+    fn from(bv: BVal<'a>) -> BValue {
+        BValue::from(&bv)
+    }
+}
+
+impl From<i64> for BValue {
+    fn from(i: i64) -> BValue { BValue::BInt(i) }
+}
+
+impl <'b> From<&'b [u8]> for BValue {
+    fn from(bs: &'b [u8]) -> BValue { BValue::BString(bs.to_vec()) }
+}
+
+impl From<Vec<u8>> for BValue {
+    fn from(bs: Vec<u8>) -> BValue { BValue::BString(bs) }
+}
+
+impl <'b> From<&'b str> for BValue {
+    fn from(s: &'b str) -> BValue { BValue::BString(s.as_bytes().to_vec()) }
+}
+
+impl From<String> for BValue {
+    fn from(s: String) -> BValue { BValue::BString(s.into_bytes()) }
+}
+
+impl From<Vec<BValue>> for BValue {
+    fn from(vs: Vec<BValue>) -> BValue { BValue::BList(vs) }
+}
+
+impl From<BTreeMap<Vec<u8>, BValue>> for BValue {
+    fn from(m: BTreeMap<Vec<u8>, BValue>) -> BValue { BValue::BDict(m) }
+}
+
+impl Encode for BValue {
+    fn encode_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match *self {
+            BValue::BString(ref bs) => write_bstring(w, bs),
+            BValue::BInt(i) => write_bint(w, i),
+            BValue::BBigInt(ref s) => write!(w, "i{}e", s),
+            BValue::BList(ref vs) => write_blist(w, vs),
+            BValue::BDict(ref m) =>
+                write_bdict(w, m.iter().map(|(k, v)| (&k[..], v))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::{BVal, ReadError, Encode, StrKeys, bval};
+    use bencode::{BINT_TYPE_NAME, BDICT_TYPE_NAME};
+    use nom::IResult;
+    use bencode::{Mode, Options, arbitrary, decode};
+    use std::collections::BTreeMap;
+    use std::thread;
+    use proptest::prelude::*;
+
+    #[test]
+    fn from_bval() {
+        let input = &b"d3:keyli1e1:xe4:key2i10ee"[..];
+        let owned = match bval(input) {
+            IResult::Done(_, bv) => BValue::from(bv),
+            _ => panic!("should parse"),
+        };
+
+        let mut expected = BTreeMap::new();
+        expected.insert(b"key".to_vec(), BValue::from(vec![BValue::from(1), BValue::from("x")]));
+        expected.insert(b"key2".to_vec(), BValue::from(10));
+        assert_eq!(owned, BValue::BDict(expected));
+    }
+
+    #[test]
+    fn to_bval_round_trip() {
+        let mut m = BTreeMap::new();
+        m.insert(b"b".to_vec(), BValue::from("hi"));
+        m.insert(b"a".to_vec(), BValue::from(-3));
+        m.insert(vec![0xff], BValue::from(0));
+        let owned = BValue::from(m);
+
+        let mut buf = Vec::new();
+        let bv = owned.to_bval(&mut buf);
+        assert_eq!(bv.as_bdict_ref().unwrap().get_str("a"), Some(&BVal::BInt(&b"i-3e"[..], -3)));
+        assert_eq!(BValue::from(&bv), owned);
+        assert_eq!(owned.as_bdict_ref().unwrap().get_str("b"), Some(&BValue::from("hi")));
+        assert_eq!(owned.encode(), b"d1:ai-3e1:b2:hi1:\xffi0ee".to_vec());
+    }
+
+    #[test]
+    fn big_int_round_trip() {
+        let owned = BValue::from(vec![BValue::BBigInt("18446744073709551616".to_string())]);
+        let mut buf = Vec::new();
+        let bv = owned.to_bval(&mut buf);
+        assert_eq!(bv.as_blist().unwrap()[0].as_bbigint_str(), Ok("18446744073709551616"));
+        assert_eq!(BValue::from(&bv), owned);
+    }
+
+    #[test]
+    fn outlives_input_and_sends() {
+        let owned = {
+            let input = b"l4:spami42ee".to_vec();
+            match bval(&input[..]) {
+                IResult::Done(_, bv) => BValue::from(bv),
+                _ => panic!("should parse"),
+            }
+        };
+        let back = thread::spawn(move || owned).join().unwrap();
+        assert_eq!(back.as_blist().unwrap()[0].as_bstring_str(), Ok("spam"));
+    }
+
+    #[test]
+    fn accessors_report() {
+        assert_eq!(BValue::from(7).as_bint(), Ok(7));
+        assert_eq!(
+            BValue::from(7).as_bdict_ref(),
+            Err(ReadError::WrongType{found: BINT_TYPE_NAME, expected: BDICT_TYPE_NAME})
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn encode_then_decode(v in arbitrary::bvalue()) {
+            let bs = v.encode();
+            let bv = decode(&bs, Options::new(Mode::Strict).with_big_ints()).unwrap();
+            prop_assert_eq!(BValue::from(&bv), v);
+            prop_assert_eq!(bv.raw(), &bs[..]);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use time;
+
+use bencode::pretty;
+use metainfo;
+use metainfo::create::CreateOptions;
+use metainfo::lint;
+use metainfo::lint::Severity;
+use tracker::Announcer;
+
+static USAGE: &'static str = "\
+usage: torrent <command> [<args>]
+
+commands:
+    announce <file>                    ask the torrent's trackers for peers, tier by tier
+    bencode-dump [--offsets] <file>    print any bencode file as an indented tree
+    create [<options>] <path>          make a .torrent from a file or directory
+    lint [--deny-warnings] <file>...   check torrents for problems; fails if any has errors
+
+create options:
+    -a, --announce <url>[,<url>...]    a tier of trackers; repeat for more tiers, give at least one
+    -c, --comment <text>
+    -l, --piece-length <bytes>         a power of two; picked from the size if not given
+    -o, --output <file>                where to write it, <name>.torrent by default
+    -p, --private                      only use the trackers to find peers
+    --created-by <text>
+    --no-date                          leave out the creation date";
+
+/// Runs the subcommand named by `args`, which don't include the program name.
+pub fn run(args: &[String]) -> Result<(), String> {
+    match args.first().map(|s| &s[..]) {
+        Some("announce") => announce(&args[1..]),
+        Some("bencode-dump") => bencode_dump(&args[1..]),
+        Some("create") => create(&args[1..]),
+        Some("lint") => lint(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    let mut contents = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .or_else(|e| Err(format!("{}: {}", path, e)))
+        .map(|_| contents)
+}
+
+fn announce(args: &[String]) -> Result<(), String> {
+    let path = match args.len() {
+        1 if !args[0].starts_with("--") => &args[0],
+        _ => return Err(USAGE.to_string()),
+    };
+    let contents = read_file(path)?;
+    let mi = metainfo::parse(&contents).or_else(|e| Err(format!("{}: {}", path, e)))?;
+    match Announcer::new(&mi).announce() {
+        Ok((url, announcement)) => {
+            println!("{}: {} peers, again in {}s", url, announcement.peers().len(), announcement.interval());
+            for &(ip, port) in announcement.peers() {
+                println!("{}:{}", ip, port);
+            }
+            Ok(())
+        },
+        Err(failures) => {
+            for &(ref url, ref e) in &failures {
+                println!("{}: {}", url, e);
+            }
+            Err("no tracker answered".to_string())
+        },
+    }
+}
+
+fn bencode_dump(args: &[String]) -> Result<(), String> {
+    let mut opts = pretty::DumpOptions::default();
+    let mut path = None;
+    for arg in args {
+        match &arg[..] {
+            "--offsets" => opts.offsets = true,
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let path = path.ok_or(USAGE.to_string())?;
+    let contents = read_file(path)?;
+    let out = pretty::dump(&contents, &opts).or_else(|e| Err(format!("{}: {}", path, e)))?;
+    print!("{}", out);
+    Ok(())
+}
+
+fn create(args: &[String]) -> Result<(), String> {
+    let mut opts = CreateOptions{ creation_date: Some(time::get_time().sec), ..CreateOptions::default() };
+    let mut output = None;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(USAGE.to_string());
+        match &arg[..] {
+            "-a" | "--announce" => opts.trackers.push(value()?.split(',').map(|s| s.to_string()).collect()),
+            "-c" | "--comment" => opts.comment = Some(value()?.to_string()),
+            "-l" | "--piece-length" => {
+                let n = value()?;
+                opts.piece_length = Some(n.parse().or_else(|_| Err(format!("bad piece length: {}", n)))?);
+            },
+            "-o" | "--output" => output = Some(value()?.to_string()),
+            "-p" | "--private" => opts.private = true,
+            "--created-by" => opts.created_by = Some(value()?.to_string()),
+            "--no-date" => opts.creation_date = None,
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let path = Path::new(path.ok_or(USAGE.to_string())?);
+    let torrent = metainfo::create::create(path, &opts).or_else(|e| Err(e.to_string()))?;
+    let output = match output {
+        Some(output) => output,
+        None => {
+            let name = path.canonicalize().ok()
+                .and_then(|p| p.file_name().and_then(|n| n.to_str()).map(|n| n.to_string()))
+                .unwrap_or("out".to_string());
+            format!("{}.torrent", name)
+        },
+    };
+    File::create(&output)
+        .and_then(|mut f| f.write_all(&torrent))
+        .or_else(|e| Err(format!("{}: {}", output, e)))
+}
+
+fn lint(args: &[String]) -> Result<(), String> {
+    let mut deny_warnings = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match &arg[..] {
+            "--deny-warnings" => deny_warnings = true,
+            _ if !arg.starts_with("--") => paths.push(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    if paths.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    let mut failed = 0;
+    for path in &paths {
+        // the error already names the file
+        let problems = match read_file(path) {
+            Ok(contents) => lint::check(&contents),
+            Err(e) => {
+                println!("{}: {}", Severity::Error, e);
+                failed += 1;
+                continue;
+            },
+        };
+        for problem in &problems {
+            println!("{}: {}: {}", path, problem.severity(), problem);
+        }
+        if problems.iter().any(|p| deny_warnings || p.severity() == Severity::Error) {
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} torrents failed", failed, paths.len()));
+    }
+    Ok(())
+}
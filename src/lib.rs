@@ -0,0 +1,25 @@
+#[macro_use] extern crate nom;
+extern crate url;
+extern crate crypto;
+extern crate rotor;
+extern crate rotor_stream;
+extern crate rotor_http;
+extern crate time;
+extern crate httparse;
+extern crate rand;
+#[macro_use] extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_bytes;
+extern crate serde_json;
+#[cfg(test)] extern crate proptest;
+
+pub mod bencode;
+pub mod cli;
+pub mod magnet;
+pub mod metainfo;
+#[cfg(test)] mod scratch;
+pub mod sha1bytes;
+pub mod sha256bytes;
+pub mod storage;
+pub mod tracker;
+pub mod webseed;
@@ -0,0 +1,351 @@
+//! Magnet links (BEP 9): torrents known by their info hash, before the
+//! metainfo has been fetched from peers.
+//!
+//! Parses and builds `magnet:?xt=urn:btih:...` links, with v1 hashes in hex
+//! or base32 and v2 hashes as `urn:btmh:` multihashes (BEP 52).
+
+use std::error;
+use std::fmt;
+use std::str;
+
+use url::Url;
+use url::percent_encoding::{percent_decode, utf8_percent_encode, FORM_URLENCODED_ENCODE_SET};
+
+use metainfo::{Metainfo, Version, parse_url};
+use sha1bytes::SHA1Hash;
+use sha256bytes::SHA256Hash;
+
+static PREFIX: &'static str = "magnet:?";
+static BTIH_PREFIX: &'static str = "urn:btih:";
+static BTMH_PREFIX: &'static str = "urn:btmh:";
+// multihash header for a 32 byte SHA-256
+static SHA256_MULTIHASH: &'static [u8] = &[0x12, 0x20];
+
+static EXACT_TOPIC_KEY: &'static str = "xt";
+static DISPLAY_NAME_KEY: &'static str = "dn";
+static TRACKER_KEY: &'static str = "tr";
+static WEB_SEED_KEY: &'static str = "ws";
+static PEER_KEY: &'static str = "x.pe";
+static SELECT_ONLY_KEY: &'static str = "so";
+
+/// What a magnet link says about a torrent.
+#[derive(Debug, PartialEq)]
+pub struct PendingTorrent<'a> {
+    /// The v1 info hash, or for v2-only links the v2 hash cut to 20 bytes,
+    /// as in `Info`.
+    pub info_hash: SHA1Hash<'a>,
+    pub info_hash_v2: Option<SHA256Hash<'a>>,
+    /// Which hashes the link has: btih, btmh, or both.
+    pub version: Version,
+    pub name: Option<String>,
+    /// Trackers that don't parse are left out.
+    pub trackers: Vec<Url>,
+    pub web_seeds: Vec<Url>,
+    /// Peers to try straight away, as `host:port`.
+    pub peers: Vec<String>,
+    /// Just these files, as inclusive ranges of indices.
+    pub select_only: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MagnetError {
+    NotAMagnet,
+    // neither a btih nor a btmh
+    NoInfoHash,
+    BadInfoHash(String),
+    BadPeer(String),
+    BadSelection(String),
+    // not UTF-8 once percent-decoded
+    BadEncoding(String),
+}
+
+impl fmt::Display for MagnetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MagnetError::NotAMagnet => write!(f, "not a magnet link"),
+            MagnetError::NoInfoHash => write!(f, "no btih or btmh info hash"),
+            MagnetError::BadInfoHash(ref s) => write!(f, "bad info hash {}", s),
+            MagnetError::BadPeer(ref s) => write!(f, "bad peer address {}", s),
+            MagnetError::BadSelection(ref s) => write!(f, "bad file selection {}", s),
+            MagnetError::BadEncoding(ref s) => write!(f, "{} isn't UTF-8", s),
+        }
+    }
+}
+
+impl error::Error for MagnetError {}
+
+/// Parses a `magnet:?` link. Unknown parameters and topics other than btih
+/// and btmh are ignored.
+pub fn parse(uri: &str) -> Result<PendingTorrent<'static>, MagnetError> {
+    let query = strip_prefix(uri, PREFIX).ok_or(MagnetError::NotAMagnet)?;
+    let mut v1 = None;
+    let mut v2 = None;
+    let mut pending = PendingTorrent{
+        info_hash: SHA1Hash::from_owned(Vec::new()),
+        info_hash_v2: None,
+        version: Version::V1,
+        name: None,
+        trackers: Vec::new(),
+        web_seeds: Vec::new(),
+        peers: Vec::new(),
+        select_only: Vec::new(),
+    };
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let mut kv = param.splitn(2, '=');
+        let key = without_index(kv.next().unwrap_or(""));
+        let value = decode(kv.next().unwrap_or(""))?;
+        if key == EXACT_TOPIC_KEY {
+            if let Some(hash) = strip_prefix(&value, BTIH_PREFIX) {
+                v1 = Some(btih(hash)?);
+            } else if let Some(hash) = strip_prefix(&value, BTMH_PREFIX) {
+                v2 = Some(btmh(hash)?);
+            }
+        } else if key == DISPLAY_NAME_KEY {
+            pending.name = Some(value);
+        } else if key == TRACKER_KEY {
+            pending.trackers.extend(parse_url(&value).ok());
+        } else if key == WEB_SEED_KEY {
+            pending.web_seeds.extend(parse_url(&value).ok());
+        } else if key == PEER_KEY {
+            pending.peers.push(peer(value)?);
+        } else if key == SELECT_ONLY_KEY {
+            pending.select_only = selection(&value)?;
+        }
+    }
+    let (info_hash, version) = match (v1, &v2) {
+        (Some(v1), &Some(_)) => (v1, Version::Hybrid),
+        (Some(v1), &None) => (v1, Version::V1),
+        (None, &Some(ref v2)) => (v2.truncated(), Version::V2),
+        (None, &None) => return Err(MagnetError::NoInfoHash),
+    };
+    pending.info_hash = info_hash;
+    pending.info_hash_v2 = v2;
+    pending.version = version;
+    Ok(pending)
+}
+
+// `tr.1`, `tr.2` and so on number several values for one key
+fn without_index(key: &str) -> &str {
+    match key.rfind('.') {
+        Some(i) if i + 1 < key.len() && key[i + 1..].bytes().all(|b| b.is_ascii_digit()) => &key[..i],
+        _ => key,
+    }
+}
+
+fn decode(value: &str) -> Result<String, MagnetError> {
+    let bytes = percent_decode(value.replace('+', " ").as_bytes());
+    String::from_utf8(bytes).or_else(|_| Err(MagnetError::BadEncoding(value.to_string())))
+}
+
+fn strip_prefix<'s>(s: &'s str, prefix: &str) -> Option<&'s str> {
+    match s.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&s[prefix.len()..]),
+        _ => None,
+    }
+}
+
+// 40 hex digits, or 32 base32 ones
+fn btih(hash: &str) -> Result<SHA1Hash<'static>, MagnetError> {
+    let bytes = match hash.len() {
+        40 => from_hex(hash),
+        32 => from_base32(hash),
+        _ => None,
+    };
+    bytes.map(SHA1Hash::from_owned).ok_or_else(|| MagnetError::BadInfoHash(hash.to_string()))
+}
+
+fn btmh(hash: &str) -> Result<SHA256Hash<'static>, MagnetError> {
+    match from_hex(hash) {
+        Some(ref bytes) if bytes.len() == 34 && bytes.starts_with(SHA256_MULTIHASH) =>
+            Ok(SHA256Hash::from_owned(bytes[2..].to_vec())),
+        _ => Err(MagnetError::BadInfoHash(hash.to_string())),
+    }
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.bytes().all(|b| (b as char).is_digit(16)) {
+        return None;
+    }
+    (0..s.len() / 2).map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()).collect()
+}
+
+// RFC 4648, without padding
+fn from_base32(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut buf, mut bits) = (0u32, 0);
+    for b in s.bytes() {
+        let v = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a',
+            b'2'..=b'7' => b - b'2' + 26,
+            _ => return None,
+        };
+        buf = (buf << 5) | v as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+            buf &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// hostname:port, ipv4:port or [ipv6]:port
+fn peer(addr: String) -> Result<String, MagnetError> {
+    let ok = match addr.rfind(':') {
+        Some(i) => i > 0 && addr[i + 1..].parse::<u16>().is_ok(),
+        None => false,
+    };
+    if ok { Ok(addr) } else { Err(MagnetError::BadPeer(addr)) }
+}
+
+// like `0,2,4,6-8`
+fn selection(s: &str) -> Result<Vec<(usize, usize)>, MagnetError> {
+    let bad = || MagnetError::BadSelection(s.to_string());
+    let mut out = Vec::new();
+    for part in s.split(',') {
+        let mut ends = part.splitn(2, '-').map(|n| n.parse::<usize>());
+        let from = ends.next().unwrap_or(Ok(0)).or_else(|_| Err(bad()))?;
+        let to = ends.next().unwrap_or(Ok(from)).or_else(|_| Err(bad()))?;
+        if to < from {
+            return Err(bad());
+        }
+        out.push((from, to));
+    }
+    Ok(out)
+}
+
+impl <'a> PendingTorrent<'a> {
+    pub fn from_metainfo(mi: &Metainfo<'a>) -> PendingTorrent<'a> {
+        PendingTorrent{
+            info_hash: mi.info.info_hash.clone(),
+            info_hash_v2: mi.info.info_hash_v2.clone(),
+            version: mi.info.version(),
+            name: Some(mi.info.mode().name().to_string()),
+            trackers: mi.tiers().into_iter().flat_map(|tier| tier).collect(),
+            web_seeds: mi.url_list.clone(),
+            peers: Vec::new(),
+            select_only: Vec::new(),
+        }
+    }
+
+    pub fn to_uri(&self) -> String {
+        let mut params = Vec::new();
+        if self.version != Version::V2 {
+            params.push(format!("{}={}{}", EXACT_TOPIC_KEY, BTIH_PREFIX, to_hex(self.info_hash.as_bytes())));
+        }
+        if let Some(ref hash) = self.info_hash_v2 {
+            params.push(format!("{}={}{}{}", EXACT_TOPIC_KEY, BTMH_PREFIX, to_hex(SHA256_MULTIHASH), hash.to_hex_string()));
+        }
+        let encode = |s: &str| utf8_percent_encode(s, FORM_URLENCODED_ENCODE_SET);
+        if let Some(ref name) = self.name {
+            params.push(format!("{}={}", DISPLAY_NAME_KEY, encode(name)));
+        }
+        for url in &self.trackers {
+            params.push(format!("{}={}", TRACKER_KEY, encode(&url.to_string())));
+        }
+        for url in &self.web_seeds {
+            params.push(format!("{}={}", WEB_SEED_KEY, encode(&url.to_string())));
+        }
+        for addr in &self.peers {
+            params.push(format!("{}={}", PEER_KEY, encode(addr)));
+        }
+        if !self.select_only.is_empty() {
+            let ranges: Vec<String> = self.select_only.iter()
+                .map(|&(from, to)| if from == to { from.to_string() } else { format!("{}-{}", from, to) })
+                .collect();
+            params.push(format!("{}={}", SELECT_ONLY_KEY, ranges.join(",")));
+        }
+        format!("{}{}", PREFIX, params.join("&"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metainfo;
+
+    static HEX: &'static str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+    static BASE32: &'static str = "YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK";
+
+    #[test]
+    fn hex_and_base32() {
+        let m = parse(&format!("magnet:?xt=urn:btih:{}", HEX)).unwrap();
+        assert_eq!(to_hex(m.info_hash.as_bytes()), HEX);
+        assert_eq!(m.version, Version::V1);
+        let m32 = parse(&format!("MAGNET:?xt=urn:btih:{}", BASE32.to_lowercase())).unwrap();
+        assert_eq!(m32.info_hash, m.info_hash);
+        assert_eq!(parse(&format!("magnet:?xt=urn:btih:{}", HEX.to_uppercase())).unwrap().info_hash, m.info_hash);
+    }
+
+    #[test]
+    fn all_parameters() {
+        let uri = format!("magnet:?xt=urn:btih:{}&dn=Big+Buck%20Bunny&tr.1=http%3A%2F%2Fa%2Fann&tr.2=udp://b:80\
+                           &tr=http://./&ws=http%3A%2F%2Fm%2Fbbb.mp4&x.pe=10.0.0.1:6881&x.pe=%5B::1%5D:6882\
+                           &so=0,2,4-6&xl=10826029&xt=urn:sha1:abc", HEX);
+        let m = parse(&uri).unwrap();
+        assert_eq!(m.name, Some("Big Buck Bunny".to_string()));
+        let trackers: Vec<String> = m.trackers.iter().map(|u| u.to_string()).collect();
+        assert_eq!(trackers, vec!["http://a/ann", "udp://b:80"]);
+        assert_eq!(m.web_seeds, vec![Url::parse("http://m/bbb.mp4").unwrap()]);
+        assert_eq!(m.peers, vec!["10.0.0.1:6881", "[::1]:6882"]);
+        assert_eq!(m.select_only, vec![(0, 0), (2, 2), (4, 6)]);
+    }
+
+    #[test]
+    fn v2_and_hybrid() {
+        let v2_hex = "1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e";
+        let m = parse(&format!("magnet:?xt=urn:btmh:{}", v2_hex)).unwrap();
+        assert_eq!(m.version, Version::V2);
+        let v2 = m.info_hash_v2.clone().unwrap();
+        assert_eq!(format!("1220{}", v2.to_hex_string()), v2_hex);
+        assert_eq!(m.info_hash, v2.truncated());
+
+        let m = parse(&format!("magnet:?xt=urn:btih:{}&xt=urn:btmh:{}", HEX, v2_hex)).unwrap();
+        assert_eq!(m.version, Version::Hybrid);
+        assert_eq!(to_hex(m.info_hash.as_bytes()), HEX);
+        assert_eq!(m.info_hash_v2, Some(v2));
+    }
+
+    #[test]
+    fn bad_links() {
+        assert_eq!(parse("http://a/?xt=urn:btih:x"), Err(MagnetError::NotAMagnet));
+        assert_eq!(parse("magnet:?dn=x"), Err(MagnetError::NoInfoHash));
+        assert_eq!(parse("magnet:?xt=urn:btih:abc"), Err(MagnetError::BadInfoHash("abc".to_string())));
+        assert_eq!(parse(&format!("magnet:?xt=urn:btih:{}", &BASE32.replace("Y", "1"))),
+                   Err(MagnetError::BadInfoHash(BASE32.replace("Y", "1"))));
+        assert_eq!(parse(&format!("magnet:?xt=urn:btmh:1114{}", HEX)), Err(MagnetError::BadInfoHash(format!("1114{}", HEX))));
+        let with = |param: &str| parse(&format!("magnet:?xt=urn:btih:{}&{}", HEX, param));
+        assert_eq!(with("x.pe=nohost"), Err(MagnetError::BadPeer("nohost".to_string())));
+        assert_eq!(with("so=3-1"), Err(MagnetError::BadSelection("3-1".to_string())));
+        assert_eq!(with("dn=%ff"), Err(MagnetError::BadEncoding("%ff".to_string())));
+        // found by fuzzing: prefixes that end inside a character
+        assert_eq!(parse("magnet\u{e9}"), Err(MagnetError::NotAMagnet));
+        assert_eq!(with("xt=urn:bti\u{e9}"), parse(&format!("magnet:?xt=urn:btih:{}", HEX)));
+    }
+
+    #[test]
+    fn round_trip() {
+        let uri = format!("magnet:?xt=urn:btih:{}&dn=a%20b%26c&tr=http%3A%2F%2Fa%2Fann&x.pe=h%3A1&so=1,3-4", HEX);
+        let m = parse(&uri).unwrap();
+        assert_eq!(m.to_uri(), uri);
+        assert_eq!(parse(&m.to_uri()).unwrap(), m);
+    }
+
+    #[test]
+    fn from_metainfo() {
+        let bs = &b"d8:announce14:http://a.b/ann4:infod6:lengthi1e4:name3:a b12:piece lengthi1e6:pieces0:e8:url-list10:http://m/xe"[..];
+        let mi = metainfo::parse(bs).unwrap();
+        let uri = mi.magnet_link();
+        assert_eq!(uri, format!("magnet:?xt=urn:btih:{}&dn=a%20b&tr=http%3A%2F%2Fa.b%2Fann&ws=http%3A%2F%2Fm%2Fx",
+                                to_hex(mi.info.info_hash.as_bytes())));
+        let m = parse(&uri).unwrap();
+        assert_eq!(m.info_hash, mi.info.info_hash);
+        assert_eq!(m, PendingTorrent::from_metainfo(&mi));
+    }
+}
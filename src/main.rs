@@ -1,37 +1,28 @@
-#[macro_use] extern crate nom;
-extern crate url;
-extern crate crypto;
-extern crate rotor;
-extern crate rotor_stream;
-extern crate rotor_http;
-extern crate time;
-extern crate httparse;
-extern crate rand;
+extern crate torrent;
 
-mod bencode;
-mod metainfo;
-mod sha1bytes;
-mod tracker;
+use std::env;
+use std::process;
 
-use bencode::{BVal, bval};
+use torrent::cli;
 
 fn main() {
-    println!("Hello world: {:?}", BVal::BString(&b"bval"[..]))
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(msg) = cli::run(&args) {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
-    use tracker;
-    use metainfo;
+    use torrent::{tracker, metainfo};
 
     #[test]
     fn make_request() {
         let bs = include_bytes!("../sample.mp4.torrent");
         let mi: metainfo::Metainfo = metainfo::parse(bs).unwrap();
 
-        tracker::start_every_interval(mi)
+        tracker::start_every_interval(mi).unwrap();
     }
 }
 
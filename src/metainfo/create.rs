@@ -0,0 +1,402 @@
+//! Making `.torrent` files from what's on disk.
+
+use std::fmt;
+use std::error;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+use bencode::{BValue, Encode};
+use super::parse_url;
+
+pub static MIN_PIECE_LENGTH: i64 = 16 * 1024;
+pub static MAX_PIECE_LENGTH: i64 = 16 * 1024 * 1024;
+// picked piece lengths aim for about this many pieces
+static TARGET_PIECES: i64 = 1500;
+
+static ANNOUNCE_LIST_KEY: &'static str = "announce-list";
+static COMMENT_KEY: &'static str = "comment";
+static CREATED_BY_KEY: &'static str = "created by";
+static CREATION_DATE_KEY: &'static str = "creation date";
+static PRIVATE_KEY: &'static str = "private";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateOptions {
+    /// `None` picks one from the total size.
+    pub piece_length: Option<i64>,
+    /// Tiers of tracker URLs, at least one of which is needed. The first is
+    /// `announce`, and all of them go in `announce-list` if there's more
+    /// than one.
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// Seconds since the epoch.
+    pub creation_date: Option<i64>,
+    pub private: bool,
+}
+
+impl Default for CreateOptions {
+    fn default() -> CreateOptions {
+        CreateOptions{
+            piece_length: None,
+            trackers: Vec::new(),
+            comment: None,
+            created_by: Some(concat!("torrent/", env!("CARGO_PKG_VERSION")).to_string()),
+            creation_date: None,
+            private: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CreateError {
+    Io(PathBuf, io::Error),
+    // names go in the torrent as strings
+    NotUtf8(PathBuf),
+    NoFiles(PathBuf),
+    NoTrackers,
+    BadTracker(String),
+    // not a power of two, or out of range
+    BadPieceLength(i64),
+}
+
+impl fmt::Display for CreateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CreateError::Io(ref path, ref e) => write!(f, "{}: {}", path.display(), e),
+            CreateError::NotUtf8(ref path) => write!(f, "{}: name isn't UTF-8", path.display()),
+            CreateError::NoFiles(ref path) => write!(f, "{}: no files to add", path.display()),
+            CreateError::NoTrackers => write!(f, "no trackers; torrents need at least one"),
+            CreateError::BadTracker(ref url) => write!(f, "bad tracker URL: {:?}", url),
+            CreateError::BadPieceLength(n) =>
+                write!(f, "piece length {} isn't a power of two from {} to {}", n, MIN_PIECE_LENGTH, MAX_PIECE_LENGTH),
+        }
+    }
+}
+
+impl error::Error for CreateError {}
+
+/// A power of two giving about `TARGET_PIECES` pieces.
+pub fn piece_length_for(total_size: u64) -> i64 {
+    let mut n = MIN_PIECE_LENGTH;
+    while n < MAX_PIECE_LENGTH && (total_size as i64) / n > TARGET_PIECES {
+        n *= 2;
+    }
+    n
+}
+
+/// Hashes the file or directory at `path` and returns the encoded torrent.
+/// Directories are walked in name order, skipping symlinks.
+pub fn create(path: &Path, opts: &CreateOptions) -> Result<Vec<u8>, CreateError> {
+    let urls: Vec<&String> = opts.trackers.iter().flat_map(|tier| tier.iter()).collect();
+    if urls.is_empty() {
+        return Err(CreateError::NoTrackers);
+    }
+    // `parse` would leave out any that don't parse
+    if let Some(bad) = urls.iter().find(|url| parse_url(url).is_err()) {
+        return Err(CreateError::BadTracker(bad.to_string()));
+    }
+    let name = name_of(path)?;
+    let meta = fs::metadata(path).or_else(|e| Err(CreateError::Io(path.to_path_buf(), e)))?;
+    let files = if meta.is_dir() {
+        let mut files = Vec::new();
+        walk(path, &mut Vec::new(), &mut files)?;
+        files
+    } else {
+        vec![Vec::new()]
+    };
+    if files.is_empty() {
+        return Err(CreateError::NoFiles(path.to_path_buf()));
+    }
+
+    let total: u64 = files.iter()
+        .map(|parts| fs::metadata(full_path(path, parts)).map(|m| m.len()).unwrap_or(0))
+        .sum();
+    let piece_length = opts.piece_length.unwrap_or_else(|| piece_length_for(total));
+    if piece_length < MIN_PIECE_LENGTH || piece_length > MAX_PIECE_LENGTH || piece_length.count_ones() != 1 {
+        return Err(CreateError::BadPieceLength(piece_length));
+    }
+    let paths: Vec<PathBuf> = files.iter().map(|parts| full_path(path, parts)).collect();
+    let (pieces, lengths) = hash_pieces(&paths, piece_length as usize)?;
+
+    let mut info = BTreeMap::new();
+    info.insert(b"name".to_vec(), BValue::from(name));
+    info.insert(b"piece length".to_vec(), BValue::BInt(piece_length));
+    info.insert(b"pieces".to_vec(), BValue::BString(pieces));
+    if meta.is_dir() {
+        let entries = files.into_iter().zip(lengths).map(|(parts, length)| {
+            let mut file = BTreeMap::new();
+            file.insert(b"length".to_vec(), BValue::BInt(length as i64));
+            file.insert(b"path".to_vec(), BValue::BList(parts.into_iter().map(BValue::from).collect()));
+            BValue::BDict(file)
+        });
+        info.insert(b"files".to_vec(), BValue::BList(entries.collect()));
+    } else {
+        info.insert(b"length".to_vec(), BValue::BInt(lengths[0] as i64));
+    }
+    if opts.private {
+        info.insert(PRIVATE_KEY.as_bytes().to_vec(), BValue::BInt(1));
+    }
+
+    let mut root = BTreeMap::new();
+    root.insert(b"announce".to_vec(), BValue::from(&urls[0][..]));
+    if urls.len() > 1 {
+        let tiers = opts.trackers.iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|tier| BValue::BList(tier.iter().map(|url| BValue::from(&url[..])).collect()));
+        root.insert(ANNOUNCE_LIST_KEY.as_bytes().to_vec(), BValue::BList(tiers.collect()));
+    }
+    if let Some(ref comment) = opts.comment {
+        root.insert(COMMENT_KEY.as_bytes().to_vec(), BValue::from(&comment[..]));
+    }
+    if let Some(ref created_by) = opts.created_by {
+        root.insert(CREATED_BY_KEY.as_bytes().to_vec(), BValue::from(&created_by[..]));
+    }
+    if let Some(date) = opts.creation_date {
+        root.insert(CREATION_DATE_KEY.as_bytes().to_vec(), BValue::BInt(date));
+    }
+    root.insert(b"info".to_vec(), BValue::BDict(info));
+    Ok(BValue::BDict(root).encode())
+}
+
+fn name_of(path: &Path) -> Result<String, CreateError> {
+    // `.` and `..` don't have a name of their own
+    let abs = fs::canonicalize(path).or_else(|e| Err(CreateError::Io(path.to_path_buf(), e)))?;
+    abs.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.to_string())
+        .ok_or_else(|| CreateError::NotUtf8(path.to_path_buf()))
+}
+
+fn full_path(root: &Path, parts: &[String]) -> PathBuf {
+    let mut path = root.to_path_buf();
+    for part in parts {
+        path.push(part);
+    }
+    path
+}
+
+// Adds the path components of every file under `dir` to `files`.
+fn walk(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<Vec<String>>) -> Result<(), CreateError> {
+    let io_error = |e| CreateError::Io(dir.to_path_buf(), e);
+    let mut entries = fs::read_dir(dir).or_else(|e| Err(io_error(e)))?
+        .collect::<Result<Vec<fs::DirEntry>, io::Error>>()
+        .or_else(|e| Err(io_error(e)))?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name().into_string()
+            .or_else(|_| Err(CreateError::NotUtf8(path.clone())))?;
+        // not followed: they can loop, or lead out of the directory
+        let meta = fs::symlink_metadata(&path).or_else(|e| Err(CreateError::Io(path.clone(), e)))?;
+        if meta.file_type().is_symlink() {
+            continue;
+        }
+        prefix.push(name);
+        if meta.is_dir() {
+            walk(&path, prefix, files)?;
+        } else {
+            files.push(prefix.clone());
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+// The piece hashes of all the files one after another, and how long each
+// file turned out to be.
+fn hash_pieces(paths: &[PathBuf], piece_length: usize) -> Result<(Vec<u8>, Vec<u64>), CreateError> {
+    let mut pieces = Vec::new();
+    let mut lengths = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length);
+    for path in paths {
+        let io_error = |e| CreateError::Io(path.clone(), e);
+        let mut f = File::open(path).or_else(|e| Err(io_error(e)))?;
+        let mut length = 0;
+        loop {
+            let want = piece_length - piece.len();
+            let n = f.by_ref().take(want as u64).read_to_end(&mut piece).or_else(|e| Err(io_error(e)))?;
+            length += n as u64;
+            if piece.len() == piece_length {
+                finish_piece(&mut piece, &mut pieces);
+            }
+            if n < want {
+                break;
+            }
+        }
+        lengths.push(length);
+    }
+    if !piece.is_empty() {
+        finish_piece(&mut piece, &mut pieces);
+    }
+    Ok((pieces, lengths))
+}
+
+fn finish_piece(piece: &mut Vec<u8>, pieces: &mut Vec<u8>) {
+    let mut hasher = Sha1::new();
+    hasher.input(piece);
+    let mut out = [0; 20];
+    hasher.result(&mut out);
+    pieces.extend_from_slice(&out);
+    piece.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use bencode::{BValue, Encode};
+    use metainfo::{parse, Attrs, Mode};
+    use scratch::Scratch;
+    use sha1bytes::SHA1Hash;
+
+    fn write(path: &Path, contents: &[u8]) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap().write_all(contents).unwrap();
+    }
+
+    fn sha1(bs: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.input(bs);
+        let mut out = vec![0; 20];
+        hasher.result(&mut out);
+        out
+    }
+
+    fn tracker() -> CreateOptions {
+        CreateOptions{ trackers: vec![vec!["http://tracker.example/announce".to_string()]], ..CreateOptions::default() }
+    }
+
+    #[test]
+    fn single_file() {
+        let dir = Scratch::new("create-single");
+        let file = dir.join("hello.txt");
+        write(&file, b"hello world");
+        let bs = create(&file, &tracker()).unwrap();
+        let m = parse(&bs).unwrap();
+        assert_eq!(m.info.mode, Mode::Single{ name: "hello.txt", length: 11, md5sum: None, attrs: Attrs::default() });
+
+        let mut info = BTreeMap::new();
+        info.insert(b"length".to_vec(), BValue::BInt(11));
+        info.insert(b"name".to_vec(), BValue::from("hello.txt"));
+        info.insert(b"piece length".to_vec(), BValue::BInt(MIN_PIECE_LENGTH));
+        info.insert(b"pieces".to_vec(), BValue::BString(sha1(b"hello world")));
+        assert_eq!(m.info.info_hash, SHA1Hash::from_bytes(&BValue::BDict(info).encode()));
+    }
+
+    #[test]
+    fn directory() {
+        let scratch = Scratch::new("create-dir");
+        let dir = scratch.join("album");
+        let a = vec![1u8; 20000];
+        let b = vec![2u8; 30000];
+        write(&dir.join("b/2.bin"), &b);
+        write(&dir.join("a.bin"), &a);
+        write(&dir.join("empty"), b"");
+        let opts = CreateOptions{ piece_length: Some(MIN_PIECE_LENGTH), private: true, ..tracker() };
+        let bs = create(&dir, &opts).unwrap();
+        let m = parse(&bs).unwrap();
+        assert_eq!(m.total_size(), 50000);
+        match m.info.mode {
+            Mode::Multi{ name, ref files } => {
+                assert_eq!(name, "album");
+                let paths: Vec<String> = files.iter().map(|f| f.path.to_str().unwrap().to_string()).collect();
+                assert_eq!(paths, vec!["a.bin", "b/2.bin", "empty"]);
+            },
+            ref other => panic!("expected multi-file, got {:?}", other),
+        }
+        // pieces run across file boundaries
+        let all: Vec<u8> = a.iter().chain(b.iter()).cloned().collect();
+        let expected: Vec<u8> = all.chunks(MIN_PIECE_LENGTH as usize).flat_map(sha1).collect();
+        assert_eq!(m.info.pieces.0, &expected[..]);
+        assert!(m.info.private());
+    }
+
+    #[test]
+    fn optional_fields() {
+        let dir = Scratch::new("create-fields");
+        let file = dir.join("x");
+        write(&file, b"x");
+        let opts = CreateOptions{
+            trackers: vec![vec!["http://a/ann".to_string(), "http://b/ann".to_string()], vec!["udp://c:80".to_string()]],
+            comment: Some("just a test".to_string()),
+            created_by: Some("me".to_string()),
+            creation_date: Some(1500000000),
+            ..CreateOptions::default()
+        };
+        let bs = create(&file, &opts).unwrap();
+        let expected = concat!(
+            "d8:announce12:http://a/ann",
+            "13:announce-listll12:http://a/ann12:http://b/annel10:udp://c:80ee",
+            "7:comment11:just a test10:created by2:me13:creation datei1500000000e");
+        assert!(bs.starts_with(expected.as_bytes()));
+        let m = parse(&bs).unwrap();
+        assert_eq!((m.comment, m.created_by, m.creation_date), (Some("just a test"), Some("me"), Some(1500000000)));
+    }
+
+    #[test]
+    fn errors() {
+        let dir = Scratch::new("create-errors");
+        match create(&dir, &tracker()) {
+            Err(CreateError::NoFiles(_)) => (),
+            other => panic!("expected no files, got {:?}", other),
+        }
+        write(&dir.join("x"), b"x");
+        let opts = CreateOptions{ piece_length: Some(20000), ..tracker() };
+        match create(&dir, &opts) {
+            Err(CreateError::BadPieceLength(20000)) => (),
+            other => panic!("expected a bad piece length, got {:?}", other),
+        }
+        match create(&dir.join("missing"), &tracker()) {
+            Err(CreateError::Io(..)) => (),
+            other => panic!("expected an io error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn trackers_needed() {
+        let dir = Scratch::new("create-trackers");
+        write(&dir.join("x"), b"x");
+        match create(&dir, &CreateOptions::default()) {
+            Err(CreateError::NoTrackers) => (),
+            other => panic!("expected no trackers, got {:?}", other),
+        }
+        let opts = CreateOptions{ trackers: vec![vec![], vec!["http://a/ann".to_string(), "http://./".to_string()]], ..tracker() };
+        match create(&dir, &opts) {
+            Err(CreateError::BadTracker(ref url)) if url == "http://./" => (),
+            other => panic!("expected a bad tracker, got {:?}", other),
+        }
+        let opts = CreateOptions{ trackers: vec![vec![], vec!["udp://b:80".to_string()]], ..tracker() };
+        let bs = create(&dir, &opts).unwrap();
+        assert_eq!(parse(&bs).unwrap().tiers().len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_skipped() {
+        use std::os::unix::fs::symlink;
+        let scratch = Scratch::new("create-symlinks");
+        let dir = scratch.join("d");
+        write(&dir.join("real"), b"x");
+        symlink(&dir, dir.join("loop")).unwrap();
+        symlink("/etc", dir.join("outside")).unwrap();
+        symlink(dir.join("real"), dir.join("to-real")).unwrap();
+        let bs = create(&dir, &tracker()).unwrap();
+        match parse(&bs).unwrap().info.mode {
+            Mode::Multi{ ref files, .. } => assert_eq!(files.iter().map(|f| f.path()).collect::<Vec<_>>(), vec![Path::new("real")]),
+            ref other => panic!("expected multi-file, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn picked_piece_lengths() {
+        assert_eq!(piece_length_for(0), MIN_PIECE_LENGTH);
+        assert_eq!(piece_length_for(1 << 30), 1 << 20);
+        assert_eq!(piece_length_for(1 << 50), MAX_PIECE_LENGTH);
+    }
+}
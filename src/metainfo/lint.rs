@@ -0,0 +1,336 @@
+//! Checks for torrents that parse but can't be downloaded as they are.
+//!
+//! Parsing only rejects what it can't represent, like negative lengths.
+//! `validate` finds the rest: piece counts that don't add up, empty names,
+//! paths that would need rewriting, v2 files with no hashes, and so on.
+//! `check` does both, turning parse errors into problems too.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use bencode::Path;
+use super::{parse, InfoError, Metainfo, Mode, Version};
+use super::sanitize::{PathError, PathRules};
+
+// BEP 52 requires v2 pieces to be at least this, and a power of two
+static MIN_V2_PIECE_LENGTH: i64 = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Allowed, but likely to cause trouble.
+    Warning,
+    /// Clients can't download the torrent correctly.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    // negative, or too big with the lengths before it; found while parsing
+    BadLength(Path),
+    // any other reason parsing failed
+    Unparseable(InfoError),
+    NonPositivePieceLength(i64),
+    PieceLengthNotPowerOfTwo(i64),
+    BadV2PieceLength(i64),
+    WrongPieceCount{ expected: u64, found: usize },
+    EmptyName,
+    NoFiles,
+    // by index in the file list
+    EmptyPath(usize),
+    // would escape the download directory, or collide, anywhere
+    UnsafePath(PathError),
+    // only on Windows or a case-insensitive file system
+    NotPortablePath(PathError),
+    MissingPiecesRoot(PathBuf),
+    MissingPieceLayer(PathBuf),
+    WrongPieceLayerLength{ path: PathBuf, expected: u64, found: usize },
+    NegativeCreationDate(i64),
+    // an optional field that parsing left out
+    SkippedField(Path),
+}
+
+impl Problem {
+    pub fn severity(&self) -> Severity {
+        match *self {
+            Problem::PieceLengthNotPowerOfTwo(_) | Problem::NotPortablePath(_) | Problem::NegativeCreationDate(_) |
+            Problem::SkippedField(_) => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::BadLength(ref path) => write!(f, "{}: negative, or too big for the total size", path),
+            Problem::Unparseable(ref e) => write!(f, "{}", e),
+            Problem::NonPositivePieceLength(n) => write!(f, "piece length {} isn't positive", n),
+            Problem::PieceLengthNotPowerOfTwo(n) => write!(f, "piece length {} isn't a power of two", n),
+            Problem::BadV2PieceLength(n) =>
+                write!(f, "piece length {} isn't a power of two of at least {}, as v2 needs", n, MIN_V2_PIECE_LENGTH),
+            Problem::WrongPieceCount{ expected, found } =>
+                write!(f, "expected {} pieces for the total size but found {}", expected, found),
+            Problem::EmptyName => write!(f, "empty name"),
+            Problem::NoFiles => write!(f, "no files"),
+            Problem::EmptyPath(i) => write!(f, "file {} has an empty path", i),
+            Problem::UnsafePath(ref e) => write!(f, "unsafe path: {}", e),
+            Problem::NotPortablePath(ref e) => write!(f, "path isn't portable: {}", e),
+            Problem::MissingPiecesRoot(ref path) => write!(f, "{}: no pieces root", path.display()),
+            Problem::MissingPieceLayer(ref path) => write!(f, "{}: no piece layer", path.display()),
+            Problem::WrongPieceLayerLength{ ref path, expected, found } =>
+                write!(f, "{}: expected {} hashes in its piece layer but found {}", path.display(), expected, found),
+            Problem::NegativeCreationDate(n) => write!(f, "creation date {} is before 1970", n),
+            Problem::SkippedField(ref path) => write!(f, "{}: malformed, so left out", path),
+        }
+    }
+}
+
+/// Parses `bs` and validates the result. A torrent that doesn't parse has
+/// one problem, the reason.
+pub fn check(bs: &[u8]) -> Vec<Problem> {
+    match parse(bs) {
+        Ok(m) => m.validate(),
+        Err(InfoError::BadLength(path)) => vec![Problem::BadLength(path)],
+        Err(e) => vec![Problem::Unparseable(e)],
+    }
+}
+
+fn pieces_for(length: u64, piece_length: i64) -> u64 {
+    (length + piece_length as u64 - 1) / piece_length as u64
+}
+
+impl <'a> Metainfo<'a> {
+    /// Everything wrong with the torrent, most important checks first.
+    /// Empty if there's nothing to report.
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let info = &self.info;
+        let piece_length = info.piece_length();
+        let v2 = info.version() != Version::V1;
+
+        if piece_length <= 0 {
+            problems.push(Problem::NonPositivePieceLength(piece_length));
+        } else if v2 && (piece_length < MIN_V2_PIECE_LENGTH || piece_length.count_ones() != 1) {
+            problems.push(Problem::BadV2PieceLength(piece_length));
+        } else if piece_length.count_ones() != 1 {
+            problems.push(Problem::PieceLengthNotPowerOfTwo(piece_length));
+        }
+
+        // v2-only torrents have no v1 pieces to count
+        if piece_length > 0 && info.version() != Version::V2 {
+            let expected = pieces_for(self.total_size() as u64, piece_length);
+            if expected != info.pieces().len() as u64 {
+                problems.push(Problem::WrongPieceCount{ expected: expected, found: info.pieces().len() });
+            }
+        }
+
+        let mut empty = info.mode().name().is_empty();
+        if empty {
+            problems.push(Problem::EmptyName);
+        }
+        match *info.mode() {
+            Mode::Multi{ ref files, .. } => {
+                if files.is_empty() {
+                    problems.push(Problem::NoFiles);
+                }
+                for (i, file) in files.iter().enumerate() {
+                    if file.components().is_empty() {
+                        empty = true;
+                        problems.push(Problem::EmptyPath(i));
+                    }
+                }
+            },
+            Mode::Single{ .. } => (),
+        }
+
+        // empty names and paths are already reported
+        if !empty {
+            let portable = PathRules::strict();
+            let anywhere = PathRules{ windows: false, case_insensitive: false, ..portable };
+            match (self.paths(&anywhere), self.paths(&portable)) {
+                (Err(e), _) => problems.push(Problem::UnsafePath(e)),
+                (Ok(_), Err(e)) => problems.push(Problem::NotPortablePath(e)),
+                _ => (),
+            }
+        }
+
+        if v2 && piece_length > 0 {
+            for file in info.file_tree() {
+                let path = file.path().to_path_buf();
+                let root = match file.pieces_root() {
+                    Some(root) => root,
+                    None if file.length() > 0 => {
+                        problems.push(Problem::MissingPiecesRoot(path));
+                        continue;
+                    },
+                    None => continue,
+                };
+                // files of one piece or less are hashed by their root alone
+                if file.length() <= piece_length {
+                    continue;
+                }
+                let expected = pieces_for(file.length() as u64, piece_length);
+                match self.piece_layer(root) {
+                    None => problems.push(Problem::MissingPieceLayer(path)),
+                    Some(layer) if layer.len() as u64 != expected =>
+                        problems.push(Problem::WrongPieceLayerLength{ path: path, expected: expected, found: layer.len() }),
+                    Some(_) => (),
+                }
+            }
+        }
+
+        if let Some(date) = self.creation_date {
+            if date < 0 {
+                problems.push(Problem::NegativeCreationDate(date));
+            }
+        }
+        problems.extend(self.skipped.iter().cloned().map(Problem::SkippedField));
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::{BValue, Encode};
+    use bencode::arbitrary::dict;
+    use metainfo::parse;
+    use metainfo::sanitize::Unsafe;
+
+    fn v1(piece_length: i64, pieces: usize, mode: Vec<(&'static str, BValue)>) -> Vec<u8> {
+        let mut info = vec![
+            ("name", BValue::from("x")),
+            ("piece length", BValue::BInt(piece_length)),
+            ("pieces", BValue::BString(vec![7; pieces * 20])),
+        ];
+        info.extend(mode);
+        dict(vec![("announce", BValue::from("http://a/ann")), ("info", dict(info))]).encode()
+    }
+
+    fn problems(bs: &[u8]) -> Vec<Problem> {
+        parse(bs).unwrap().validate()
+    }
+
+    #[test]
+    fn clean() {
+        assert_eq!(problems(&v1(16384, 2, vec![("length", BValue::BInt(16385))])), vec![]);
+        assert_eq!(problems(&v1(16384, 0, vec![("length", BValue::BInt(0))])), vec![]);
+    }
+
+    #[test]
+    fn piece_lengths_and_counts() {
+        let single = |n| vec![("length", BValue::BInt(n))];
+        assert_eq!(problems(&v1(0, 0, single(10))), vec![Problem::NonPositivePieceLength(0)]);
+        assert_eq!(problems(&v1(-4, 0, single(10))), vec![Problem::NonPositivePieceLength(-4)]);
+        let found = problems(&v1(3, 4, single(10)));
+        assert_eq!(found, vec![Problem::PieceLengthNotPowerOfTwo(3)]);
+        assert_eq!(found[0].severity(), Severity::Warning);
+        let found = problems(&v1(4, 2, single(10)));
+        assert_eq!(found, vec![Problem::WrongPieceCount{ expected: 3, found: 2 }]);
+        assert_eq!(found[0].severity(), Severity::Error);
+    }
+
+    #[test]
+    fn names_and_paths() {
+        let bs = dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("creation date", BValue::BInt(-1)),
+            ("info", dict(vec![
+                ("files", BValue::BList(vec![
+                    dict(vec![("length", BValue::BInt(1)), ("path", BValue::BList(vec![]))]),
+                    dict(vec![("length", BValue::BInt(1)), ("path", BValue::BList(vec![BValue::from("a")]))]),
+                ])),
+                ("name", BValue::from("")),
+                ("piece length", BValue::BInt(1)),
+                ("pieces", BValue::BString(vec![7; 40])),
+            ])),
+        ]).encode();
+        assert_eq!(problems(&bs), vec![Problem::EmptyName, Problem::EmptyPath(0), Problem::NegativeCreationDate(-1)]);
+        assert_eq!(problems(&v1(1, 0, vec![("files", BValue::BList(vec![]))])), vec![Problem::NoFiles]);
+
+        let files = |paths: Vec<Vec<&'static str>>| vec![("files", BValue::BList(paths.into_iter().map(|path| dict(vec![
+            ("length", BValue::BInt(1)),
+            ("path", BValue::BList(path.into_iter().map(BValue::from).collect())),
+        ])).collect()))];
+        let found = problems(&v1(1, 2, files(vec![vec!["a"], vec!["..", "b"]])));
+        assert_eq!(found, vec![Problem::UnsafePath(PathError::Unsafe{ file: 1, component: "..".to_string(), reason: Unsafe::ParentDir })]);
+        assert_eq!(found[0].severity(), Severity::Error);
+        let found = problems(&v1(1, 2, files(vec![vec!["a"], vec!["A"]])));
+        assert_eq!(found, vec![Problem::NotPortablePath(PathError::Collision{ first: 0, second: 1, path: PathBuf::from("x/A") })]);
+        assert_eq!(found[0].severity(), Severity::Warning);
+    }
+
+    #[test]
+    fn skipped_fields() {
+        let bs = dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("comment", BValue::BString(vec![0xff])),
+            ("info", dict(vec![
+                ("length", BValue::BInt(1)),
+                ("md5sum", BValue::from("abc")),
+                ("name", BValue::from("x")),
+                ("piece length", BValue::BInt(16384)),
+                ("pieces", BValue::BString(vec![7; 20])),
+            ])),
+        ]).encode();
+        let found = problems(&bs);
+        assert_eq!(found.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+                   vec!["info.md5sum: malformed, so left out", "comment: malformed, so left out"]);
+        assert_eq!(found[0].severity(), Severity::Warning);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let found = check(&v1(1, 1, vec![("length", BValue::BInt(-1))]));
+        assert_eq!(found, vec![Problem::BadLength("info.length".parse().unwrap())]);
+        assert_eq!(found[0].severity(), Severity::Error);
+        assert_eq!(found[0].to_string(), "info.length: negative, or too big for the total size");
+        let found = check(b"d8:announce");
+        assert_eq!(found.len(), 1);
+        match found[0] {
+            Problem::Unparseable(InfoError::Bencode(_)) => (),
+            ref other => panic!("expected a bencode error, got {:?}", other),
+        }
+        assert_eq!(check(&v1(1, 1, vec![("length", BValue::BInt(1))])), vec![]);
+    }
+
+    #[test]
+    fn v2_hashes() {
+        let file = |length, root: Option<u8>| {
+            let mut attrs = vec![("length", BValue::BInt(length))];
+            attrs.extend(root.map(|b| ("pieces root", BValue::BString(vec![b; 32]))));
+            dict(vec![("", dict(attrs))])
+        };
+        let torrent = |piece_length, layers: Vec<(u8, usize)>| dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("info", dict(vec![
+                ("file tree", dict(vec![
+                    ("big", file(40000, Some(1))),
+                    ("empty", file(0, None)),
+                    ("no root", file(5, None)),
+                    ("small", file(5, Some(2))),
+                ])),
+                ("meta version", BValue::BInt(2)),
+                ("name", BValue::from("x")),
+                ("piece length", BValue::BInt(piece_length)),
+            ])),
+            ("piece layers", BValue::BDict(layers.into_iter().map(|(b, n)| (vec![b; 32], BValue::BString(vec![0; 32 * n]))).collect())),
+        ]).encode();
+        let no_root = Problem::MissingPiecesRoot(PathBuf::from("no root"));
+        assert_eq!(problems(&torrent(16384, vec![(1, 3)])), vec![no_root]);
+        assert_eq!(problems(&torrent(16384, vec![])),
+                   vec![Problem::MissingPieceLayer(PathBuf::from("big")), Problem::MissingPiecesRoot(PathBuf::from("no root"))]);
+        assert_eq!(problems(&torrent(16384, vec![(1, 2)]))[0],
+                   Problem::WrongPieceLayerLength{ path: PathBuf::from("big"), expected: 3, found: 2 });
+        assert_eq!(problems(&torrent(8192, vec![(1, 5)]))[0], Problem::BadV2PieceLength(8192));
+    }
+}
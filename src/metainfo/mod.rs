@@ -1,13 +1,25 @@
-use nom::IResult;
+pub mod create;
+pub mod lint;
+pub mod pieces;
+pub mod sanitize;
+pub mod v2;
+
 use bencode;
-use bencode::{BVal, bdict};
+use magnet;
+use bencode::{BVal, BencodeError, Cursor, Path, ReadError};
 use sha1bytes::{SHA1Hash, SHA1Hashes};
+use sha256bytes::{SHA256Hash, SHA256Hashes};
+use self::sanitize::{PathError, PathRules};
 
 use url;
 use url::Url;
+use url::idna;
+use url::percent_encoding::percent_decode;
 
-use std::path::PathBuf;
 use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::path::PathBuf;
 use std::result::Result;
 
 use std::net::ToSocketAddrs;
@@ -15,7 +27,26 @@ use std::net::ToSocketAddrs;
 #[derive(Debug, PartialEq)]
 pub struct Metainfo<'a> {
     pub info: Info<'a>,
-    pub announce: Url,
+    /// `None` if there's only an `announce-list`.
+    pub announce: Option<Url>,
+    /// Tiers of trackers (BEP 12), as in the file, skipping bad URLs and
+    /// empty tiers.
+    pub announce_list: Vec<Vec<Url>>,
+    /// HTTP mirrors of the content (BEP 19), skipping bad URLs.
+    pub url_list: Vec<Url>,
+    /// v2 piece hashes by `pieces root`, for files longer than a piece.
+    pub piece_layers: HashMap<&'a [u8], SHA256Hashes<'a>>,
+    pub comment: Option<&'a str>,
+    pub created_by: Option<&'a str>,
+    /// Seconds since the Unix epoch.
+    pub creation_date: Option<i64>,
+    /// The character set of the strings, which most files leave out.
+    pub encoding: Option<&'a str>,
+    /// DHT nodes to bootstrap from (BEP 5), as `(host, port)`.
+    pub nodes: Vec<(&'a str, u16)>,
+    /// Optional fields that were malformed and left out, like a `comment`
+    /// that isn't UTF-8 or a node with a bad port. `validate` reports them.
+    pub skipped: Vec<Path>,
     // TODO: extensions
 }
 
@@ -28,15 +59,67 @@ impl <'a> Metainfo<'a> {
         }
     }
 
-    pub fn tracker_domain_port(&'a self) -> (&'a str, u16) {
-        let domain = self.announce.domain().unwrap();
-        let port = self.announce.port_or_default().unwrap_or(80);
-        (domain, port)
+    /// The trackers to use: `announce-list` if there is one, otherwise
+    /// `announce` on its own.
+    pub fn tiers(&self) -> Vec<Vec<Url>> {
+        if !self.announce_list.is_empty() {
+            return self.announce_list.clone();
+        }
+        self.announce.iter().map(|url| vec![url.clone()]).collect()
     }
 
-    pub fn announce_path(&self) -> String {
-        return self.announce.path().map(|cs| cs.join("/")).unwrap_or("".to_string());
+    /// Every file, with its path under the download directory. Taken from
+    /// the file tree for v2 and hybrid torrents, and from `Mode` for v1.
+    /// Paths are made safe with the default `PathRules`; see `paths` for
+    /// other rules.
+    pub fn files(&self) -> Vec<FileEntry> {
+        let name = self.info.mode.safe_name();
+        let single = match self.info.mode {
+            Mode::Single{ .. } => true,
+            Mode::Multi{ .. } => false,
+        };
+        let under = |path: &::std::path::Path| if single { PathBuf::from(&name) } else { PathBuf::from(&name).join(path) };
+        if self.info.version != Version::V1 {
+            return self.info.file_tree.iter()
+                .map(|f| FileEntry{ path: under(f.path()), length: f.length(), pieces_root: f.pieces_root(), attrs: f.attrs().clone() })
+                .collect();
+        }
+        match self.info.mode {
+            Mode::Single{ length, ref attrs, .. } =>
+                vec![FileEntry{ path: under(&PathBuf::new()), length: length, pieces_root: None, attrs: attrs.clone() }],
+            Mode::Multi{ ref files, .. } => files.iter()
+                .map(|f| FileEntry{ path: under(&f.path), length: f.length, pieces_root: None, attrs: f.attrs.clone() })
+                .collect(),
+        }
     }
+
+    /// A `magnet:` link for this torrent, with its trackers and web seeds.
+    pub fn magnet_link(&self) -> String {
+        magnet::PendingTorrent::from_metainfo(self).to_uri()
+    }
+
+    /// The piece hashes for the file with this `pieces root`.
+    pub fn piece_layer(&self, root: &SHA256Hash) -> Option<&SHA256Hashes<'a>> {
+        self.piece_layers.get(root.as_bytes())
+    }
+}
+
+/// A file in any version of torrent.
+#[derive(Debug, PartialEq)]
+pub struct FileEntry<'m, 'a: 'm> {
+    pub path: PathBuf,
+    pub length: i64,
+    /// Only in v2 and hybrid torrents, and not for empty files.
+    pub pieces_root: Option<&'m SHA256Hash<'a>>,
+    pub attrs: Attrs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    V1,
+    V2,
+    /// Both v1 and v2 info, describing the same files.
+    Hybrid,
 }
 
 #[derive(Debug, PartialEq)]
@@ -44,36 +127,175 @@ pub struct Info<'a> {
     piece_length: i64,
     pieces: SHA1Hashes<'a>,
     mode: Mode<'a>,
-    // used in torrent protocol
+    version: Version,
+    // empty for v1
+    file_tree: Vec<v2::TreeFile<'a>>,
+    // used in torrent protocol; for v2-only torrents it's the v2 hash cut
+    // to 20 bytes, as BEP 52 has trackers and peers use
     pub info_hash: SHA1Hash<'a>,
+    pub info_hash_v2: Option<SHA256Hash<'a>>,
+    // only share with peers from the torrent's trackers (BEP 27)
+    private: bool,
+    source: Option<&'a str>,
+}
+
+impl <'a> Info<'a> {
+    pub fn piece_length(&self) -> i64 {
+        self.piece_length
+    }
+
+    pub fn pieces(&self) -> &SHA1Hashes<'a> {
+        &self.pieces
+    }
+
+    pub fn mode(&self) -> &Mode<'a> {
+        &self.mode
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn file_tree(&self) -> &[v2::TreeFile<'a>] {
+        &self.file_tree
+    }
+
+    pub fn private(&self) -> bool {
+        self.private
+    }
+
+    /// Set by some trackers so the same files get a different info hash.
+    pub fn source(&self) -> Option<&'a str> {
+        self.source
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Mode<'a> {
-    // TODO: include md5sum
-    Single{ name: &'a str, length: i64 },
-    Multi{ name: &'a str, files: Vec<File> },
+    Single{ name: &'a str, length: i64, md5sum: Option<&'a str>, attrs: Attrs },
+    Multi{ name: &'a str, files: Vec<File<'a>> },
+}
+
+impl <'a> Mode<'a> {
+    /// The file's name, or the directory's for multi-file torrents.
+    pub fn name(&self) -> &'a str {
+        match *self {
+            Mode::Single{ name, .. } => name,
+            Mode::Multi{ name, .. } => name,
+        }
+    }
+
+    /// `name`, made safe to use as one path component with the default
+    /// `PathRules`.
+    pub fn safe_name(&self) -> String {
+        match sanitize::component(self.name(), &PathRules::default()) {
+            Ok(Some(name)) => name,
+            _ => "_".to_string(),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
-pub struct File {
+pub struct File<'a> {
     length: i64,
+    // as in the torrent, and made safe to write to
+    components: Vec<String>,
     path: PathBuf,
+    md5sum: Option<&'a str>,
+    attrs: Attrs,
+}
+
+impl <'a> File<'a> {
+    pub fn length(&self) -> i64 {
+        self.length
+    }
+
+    /// Relative to the torrent's directory, and safe to create there: see
+    /// `sanitize` for what's changed.
+    pub fn path(&self) -> &::std::path::Path {
+        &self.path
+    }
+
+    /// The path exactly as the torrent gives it. Not safe to write to.
+    pub fn components(&self) -> &[String] {
+        &self.components
+    }
+
+    /// 32 hex digits, when the creator included it.
+    pub fn md5sum(&self) -> Option<&'a str> {
+        self.md5sum
+    }
+
+    pub fn attrs(&self) -> &Attrs {
+        &self.attrs
+    }
+}
+
+/// File attributes (BEP 47). Letters in `attr` that aren't known are
+/// ignored, as the BEP asks.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Attrs {
+    /// Filler that aligns the next file to a piece. All zeros, and never
+    /// written to disk.
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    /// What a symlink points to, relative to the torrent's directory and
+    /// exactly as given. Not safe to use as it is.
+    pub symlink: Option<Vec<String>>,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum InfoError {
-    ExtraBytes,
-    MissingBytes,
-    BencodeParseError,
-    MissingKey(&'static str),
-    BencodeValError{for_key: &'static str, err: bencode::ReadError},
+    Bencode(BencodeError),
+    // a missing key or wrong type, with its path
+    Read(ReadError),
     BadUrl(url::ParseError),
     HashesNotMultiple20Bytes(usize),
+    // negative, or adding up to more than an i64 with the lengths before it
+    BadLength(Path),
+    // only 2 is defined
+    UnsupportedVersion(i64),
+    // a file with no name above it in the file tree
+    BadFileTree(Path),
+    // not 32 bytes, or in piece layers, not a whole number of hashes
+    BadSha256(Path),
+    // the right type but not an allowed value, like `private` other than 0
+    // or 1
+    BadValue(Path),
+    // only with rules that reject; the defaults rewrite
+    BadPath(PathError),
+}
+
+impl fmt::Display for InfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InfoError::Bencode(ref e) => write!(f, "{}", e),
+            InfoError::Read(ref e) => write!(f, "{}", e),
+            InfoError::BadUrl(ref e) => write!(f, "bad announce url: {}", e),
+            InfoError::HashesNotMultiple20Bytes(n) => write!(f, "pieces is {} bytes, not a multiple of 20", n),
+            InfoError::BadLength(ref path) => write!(f, "bad length at {}", path),
+            InfoError::UnsupportedVersion(n) => write!(f, "unsupported meta version {}", n),
+            InfoError::BadFileTree(ref path) => write!(f, "file with no name at {}", path),
+            InfoError::BadSha256(ref path) => write!(f, "bad SHA-256 hash at {}", path),
+            InfoError::BadValue(ref path) => write!(f, "bad value at {}", path),
+            InfoError::BadPath(ref e) => write!(f, "unsafe path: {}", e),
+        }
+    }
+}
+
+impl error::Error for InfoError {}
+
+impl From<ReadError> for InfoError {
+    fn from(e: ReadError) -> InfoError {
+        InfoError::Read(e)
+    }
 }
 
-static ROOT_KEY: &'static str = "_root_";
 static ANNOUNCE_KEY: &'static str = "announce";
+static ANNOUNCE_LIST_KEY: &'static str = "announce-list";
+static PIECE_LAYERS_KEY: &'static str = "piece layers";
+static URL_LIST_KEY: &'static str = "url-list";
 static INFO_KEY: &'static str = "info";
 static PIECE_LENGTH_KEY: &'static str = "piece length";
 static PIECES_KEY: &'static str = "pieces";
@@ -81,65 +303,257 @@ static LENGTH_KEY: &'static str = "length";
 static NAME_KEY: &'static str = "name";
 static FILES_KEY: &'static str = "files";
 static PATH_KEY: &'static str = "path";
+static COMMENT_KEY: &'static str = "comment";
+static CREATED_BY_KEY: &'static str = "created by";
+static CREATION_DATE_KEY: &'static str = "creation date";
+static ENCODING_KEY: &'static str = "encoding";
+static NODES_KEY: &'static str = "nodes";
+static PRIVATE_KEY: &'static str = "private";
+static SOURCE_KEY: &'static str = "source";
+static ATTR_KEY: &'static str = "attr";
+static SYMLINK_PATH_KEY: &'static str = "symlink path";
+static MD5SUM_KEY: &'static str = "md5sum";
+static META_VERSION_KEY: &'static str = "meta version";
+static FILE_TREE_KEY: &'static str = "file tree";
 
+/// Parses a torrent file, rejecting non-canonical bencode: the info hash is
+/// taken over the raw `info` bytes, so any other encoding would change it.
 pub fn parse(contents: &[u8]) -> Result<Metainfo, InfoError> {
-    match bdict(contents) {
-        IResult::Done(rest, bv) =>
-            if rest == &b""[..] {
-               metainfo_from_bval(bv)
-            } else {
-               Err(InfoError::ExtraBytes)
+    parse_with(contents, bencode::Options::new(bencode::Mode::Strict))
+}
+
+/// Parses with custom `Options`, e.g. lenient mode or tighter `ParseLimits`.
+pub fn parse_with(contents: &[u8], opts: bencode::Options) -> Result<Metainfo, InfoError> {
+    bencode::decode(contents, opts)
+        .or_else(|e| Err(InfoError::Bencode(e)))
+        .and_then(metainfo_from_bval)
+}
+
+fn metainfo_from_bval<'a>(bv: BVal<'a>) -> Result<Metainfo, InfoError> {
+    let root = bv.cursor();
+    let announce_list = match root.get(ANNOUNCE_LIST_KEY)? {
+        Some(tiers) => tiers_from_cursor(&tiers)?,
+        None => Vec::new(),
+    };
+    let mut skipped = Vec::new();
+    // only needed if there's no list to use instead, so with a list a bad
+    // one is left out like other optional fields
+    let announce = if announce_list.is_empty() {
+        let url = root.at(ANNOUNCE_KEY)?;
+        Some(parse_url(url.str()?).or_else(|e| Err(InfoError::BadUrl(e)))?)
+    } else {
+        lenient(&root, ANNOUNCE_KEY, &mut skipped, |url| parse_url(url.str()?).or_else(|e| Err(InfoError::BadUrl(e))))?
+    };
+    let url_list = match root.get(URL_LIST_KEY)? {
+        Some(urls) => web_seeds_from_cursor(&urls)?,
+        None => Vec::new(),
+    };
+    let piece_layers = match root.get(PIECE_LAYERS_KEY)? {
+        Some(layers) => v2::piece_layers_from_cursor(&layers)?,
+        None => HashMap::new(),
+    };
+    let nodes = nodes_from_cursor(&root, &mut skipped)?;
+    let info = info_from_cursor(&root.at(INFO_KEY)?, &mut skipped)?;
+    let comment = lenient(&root, COMMENT_KEY, &mut skipped, |c| Ok(c.str()?))?;
+    let created_by = lenient(&root, CREATED_BY_KEY, &mut skipped, |c| Ok(c.str()?))?;
+    let creation_date = lenient(&root, CREATION_DATE_KEY, &mut skipped, |n| Ok(n.int()?))?;
+    let encoding = lenient(&root, ENCODING_KEY, &mut skipped, |c| Ok(c.str()?))?;
+    Ok(Metainfo{
+        info: info,
+        announce: announce,
+        announce_list: announce_list,
+        url_list: url_list,
+        piece_layers: piece_layers,
+        comment: comment,
+        created_by: created_by,
+        creation_date: creation_date,
+        encoding: encoding,
+        nodes: nodes,
+        skipped: skipped,
+    })
+}
+
+// Trackers that don't parse are left out rather than failing the whole
+// torrent, since the other tiers may still work.
+fn tiers_from_cursor<'b, 'a>(tiers: &Cursor<'b, 'a>) -> Result<Vec<Vec<Url>>, InfoError> {
+    let mut out = Vec::new();
+    for tier in tiers.list()? {
+        let mut urls = Vec::new();
+        for url in tier.list()? {
+            if let Ok(url) = parse_url(url.str()?) {
+                urls.push(url);
+            }
+        }
+        if !urls.is_empty() {
+            out.push(urls);
+        }
+    }
+    Ok(out)
+}
+
+fn optional_str<'b, 'a>(dict: &Cursor<'b, 'a>, key: &str) -> Result<Option<&'a str>, InfoError> {
+    match dict.get(key)? {
+        Some(s) => Ok(Some(s.str()?)),
+        None => Ok(None),
+    }
+}
+
+fn optional_int<'b, 'a>(dict: &Cursor<'b, 'a>, key: &str) -> Result<Option<i64>, InfoError> {
+    match dict.get(key)? {
+        Some(n) => Ok(Some(n.int()?)),
+        None => Ok(None),
+    }
+}
+
+// For fields that don't change what's downloaded: a malformed one is left
+// out and its path added to `skipped`, rather than failing the torrent.
+fn lenient<'b, 'a, T, F>(dict: &Cursor<'b, 'a>, key: &str, skipped: &mut Vec<Path>, read: F) -> Result<Option<T>, InfoError>
+    where F: FnOnce(&Cursor<'b, 'a>) -> Result<T, InfoError>
+{
+    match dict.get(key)? {
+        Some(value) => match read(&value) {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => {
+                skipped.push(value.path);
+                Ok(None)
             },
-        IResult::Incomplete(_) => Err(InfoError::MissingBytes),
-        IResult::Error(_) => Err(InfoError::BencodeParseError),
+        },
+        None => Ok(None),
     }
 }
 
-// TODO: Abstract the error checking more!
-fn metainfo_from_bval<'a>(bv: BVal<'a>) -> Result<Metainfo, InfoError> {
-    bv.as_bdict()
-        .or_else(|e| Err(InfoError::BencodeValError{for_key: ROOT_KEY, err: e}))
-        .and_then(|m| {
-        let announce_opt = m.get(ANNOUNCE_KEY)
-            .ok_or(InfoError::MissingKey(ANNOUNCE_KEY))
-            .and_then(|a| a.as_bstring_str()
-                      .or_else(|e| Err(InfoError::BencodeValError{for_key: ANNOUNCE_KEY, err: e})))
-            .and_then(|url_str| Url::parse(url_str)
-                      .or_else(|e| Err(InfoError::BadUrl(e))));
-        let info_opt = m.get(INFO_KEY)
-            .ok_or(InfoError::MissingKey(INFO_KEY))
-            .and_then(|i| i.as_bdict_ref_with_input()
-                      .or_else(|e| Err(InfoError::BencodeValError{for_key: INFO_KEY, err: e})))
-            .and_then(|(input, info_dict)| info_from_dict(input, info_dict));
-
-        // TODO: For paralellism, a zip here would be better
-        announce_opt.and_then(|announce| {
-            info_opt.map(|info| Metainfo{ info: info, announce: announce })
-        })
+// Bad nodes are skipped one by one, so the rest can still be used.
+fn nodes_from_cursor<'b, 'a>(root: &Cursor<'b, 'a>, skipped: &mut Vec<Path>) -> Result<Vec<(&'a str, u16)>, InfoError> {
+    let nodes = match lenient(root, NODES_KEY, skipped, |nodes| Ok(nodes.list()?))? {
+        Some(nodes) => nodes,
+        None => return Ok(Vec::new()),
+    };
+    let mut out = Vec::new();
+    for node in nodes {
+        match node_from_cursor(&node) {
+            Ok(node) => out.push(node),
+            Err(_) => skipped.push(node.path),
+        }
+    }
+    Ok(out)
+}
+
+// Each node is a `[host, port]` pair.
+fn node_from_cursor<'b, 'a>(node: &Cursor<'b, 'a>) -> Result<(&'a str, u16), InfoError> {
+    let pair = node.list()?;
+    if pair.len() != 2 {
+        return Err(InfoError::BadValue(node.path.clone()));
+    }
+    let port = pair[1].int()?;
+    if port < 0 || port > i64::from(u16::max_value()) {
+        return Err(InfoError::BadValue(pair[1].path.clone()));
+    }
+    Ok((pair[0].str()?, port as u16))
+}
+
+fn md5sum_from_cursor<'b, 'a>(dict: &Cursor<'b, 'a>, skipped: &mut Vec<Path>) -> Result<Option<&'a str>, InfoError> {
+    lenient(dict, MD5SUM_KEY, skipped, |sum| {
+        let s = sum.str()?;
+        if s.len() != 32 || !s.chars().all(|c| c.is_digit(16)) {
+            return Err(InfoError::BadValue(sum.path.clone()));
+        }
+        Ok(s)
     })
 }
 
-fn info_from_dict<'a>(input: &[u8], dict: &HashMap<&'a str, BVal<'a>>) -> Result<Info<'a>, InfoError> {
-    let piece_length_opt = dict.get(PIECE_LENGTH_KEY)
-        .ok_or(InfoError::MissingKey(PIECE_LENGTH_KEY))
-        .and_then(|p| p.as_bint()
-                       .or_else(|e| Err(InfoError::BencodeValError{for_key: PIECE_LENGTH_KEY, err: e})));
-    let pieces_opt = dict.get(PIECES_KEY)
-        .ok_or(InfoError::MissingKey(PIECES_KEY))
-        .and_then(|ps| ps.as_bstring_bytes()
-                         .or_else(|e| Err(InfoError::BencodeValError{for_key: PIECES_KEY, err: e})))
-        .and_then(|sha_bytes| shas_from_bytes(sha_bytes));
-    let mode_opt = mode_from_dict(dict);
-
-    let info_hash = SHA1Hash::from_bytes(input);
-
-    // TODO: zip here
-    piece_length_opt.and_then(|piece_length| {
-        pieces_opt.and_then(|pieces| {
-            mode_opt.map(|mode| {
-              Info{ piece_length: piece_length, pieces: pieces, mode: mode, info_hash: info_hash }
-            })
-        })
+// `symlink path` is only read for symlinks, and they must have one.
+fn attrs_from_cursor<'b, 'a>(dict: &Cursor<'b, 'a>) -> Result<Attrs, InfoError> {
+    let attr = optional_str(dict, ATTR_KEY)?.unwrap_or("");
+    let symlink = if attr.contains('l') {
+        let path = dict.list_at(SYMLINK_PATH_KEY)?.iter()
+            .map(|c| c.str().map(|s| s.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Some(path)
+    } else {
+        None
+    };
+    Ok(Attrs{
+        padding: attr.contains('p'),
+        executable: attr.contains('x'),
+        hidden: attr.contains('h'),
+        symlink: symlink,
+    })
+}
+
+// BEP 19 allows a single URL in place of the list. Mirrors that don't parse
+// are left out, like trackers.
+fn web_seeds_from_cursor<'b, 'a>(urls: &Cursor<'b, 'a>) -> Result<Vec<Url>, InfoError> {
+    let strs = match *urls.value {
+        BVal::BString(..) => vec![urls.str()?],
+        _ => urls.list()?.iter().map(|url| url.str()).collect::<Result<Vec<_>, _>>()?,
+    };
+    Ok(strs.into_iter().filter_map(|s| parse_url(s).ok()).collect())
+}
+
+/// `Url::parse`, but an error instead of a panic for hosts that come out
+/// empty once percent-decoded and mapped to ASCII.
+// url 0.5 panics on those, like `http://./`, `http://%2e` or `http://xn--`,
+// so they're turned away first, finding the host the same way it would.
+pub fn parse_url(s: &str) -> Result<Url, url::ParseError> {
+    let clean: String = s.chars().filter(|&c| c != '\t' && c != '\n' && c != '\r').collect();
+    let rest = clean.splitn(2, ':').nth(1).unwrap_or("");
+    let authority = rest.trim_left_matches(|c| c == '/' || c == '\\')
+        .split(|c| c == '/' || c == '\\' || c == '?' || c == '#')
+        .next().unwrap_or("");
+    let host = authority.rsplitn(2, '@').next().unwrap_or("");
+    if !host.starts_with('[') {
+        let host = host.split(':').next().unwrap_or("");
+        let domain = String::from_utf8_lossy(&percent_decode(host.as_bytes())).into_owned();
+        if idna::domain_to_ascii(&domain).map(|d| d.is_empty()).unwrap_or(false) {
+            return Err(url::ParseError::EmptyHost);
+        }
+    }
+    Url::parse(s)
+}
+
+fn info_from_cursor<'b, 'a>(info: &Cursor<'b, 'a>, skipped: &mut Vec<Path>) -> Result<Info<'a>, InfoError> {
+    let piece_length = info.int_at(PIECE_LENGTH_KEY)?;
+    // hybrids are v2 torrents that keep the v1 keys too
+    let version = match info.get(META_VERSION_KEY)? {
+        None => Version::V1,
+        Some(v) => match v.int()? {
+            2 if info.get(PIECES_KEY)?.is_some() => Version::Hybrid,
+            2 => Version::V2,
+            n => return Err(InfoError::UnsupportedVersion(n)),
+        },
+    };
+    let file_tree = match version {
+        Version::V1 => Vec::new(),
+        _ => v2::files_from_tree(&info.at(FILE_TREE_KEY)?)?,
+    };
+    let (pieces, mode) = match version {
+        Version::V2 => (SHA1Hashes(&[]), v2::mode_from_tree(info.str_at(NAME_KEY)?, &file_tree)),
+        _ => (shas_from_bytes(info.bytes_at(PIECES_KEY)?)?, mode_from_cursor(info, skipped)?),
+    };
+    let input = info.dict_input()?;
+    let info_hash_v2 = match version {
+        Version::V1 => None,
+        _ => Some(SHA256Hash::from_bytes(input)),
+    };
+    let info_hash = match info_hash_v2 {
+        Some(ref hash) if version == Version::V2 => hash.truncated(),
+        _ => SHA1Hash::from_bytes(input),
+    };
+    let private = match optional_int(info, PRIVATE_KEY)? {
+        None | Some(0) => false,
+        Some(1) => true,
+        Some(_) => return Err(InfoError::BadValue(info.at(PRIVATE_KEY)?.path)),
+    };
+    Ok(Info{
+        piece_length: piece_length,
+        pieces: pieces,
+        mode: mode,
+        version: version,
+        file_tree: file_tree,
+        info_hash: info_hash,
+        info_hash_v2: info_hash_v2,
+        private: private,
+        source: lenient(info, SOURCE_KEY, skipped, |s| Ok(s.str()?))?,
     })
 }
 
@@ -151,88 +565,67 @@ fn shas_from_bytes<'a>(bytes: &'a [u8]) -> Result<SHA1Hashes<'a>, InfoError> {
     }
 }
 
-fn mode_from_dict<'a>(dict: &HashMap<&'a str, BVal<'a>>) -> Result<Mode<'a>, InfoError> {
-    let single_opt = dict.get(LENGTH_KEY)
-        .ok_or(InfoError::MissingKey(LENGTH_KEY))
-        .and_then(|l| l.as_bint()
-                       .or_else(|e| Err(InfoError::BencodeValError{for_key: LENGTH_KEY, err: e})))
-        .and_then(|length| {
-            // if there's length then it's single
-            dict.get(NAME_KEY)
-                .ok_or(InfoError::MissingKey(NAME_KEY))
-                .and_then(|s| s.as_bstring_str()
-                               .or_else(|e| Err(InfoError::BencodeValError{for_key: NAME_KEY, err: e})))
-                .map(|name| Mode::Single{ name: name, length: length })
-        });
-
-    match single_opt {
-        Ok(_) => single_opt,
-        Err(_) => {
-            // otherwise it's multi
-            let name_opt = dict.get(NAME_KEY)
-                .ok_or(InfoError::MissingKey(NAME_KEY))
-                .and_then(|s| s.as_bstring_str()
-                               .or_else(|e| Err(InfoError::BencodeValError{for_key: NAME_KEY, err: e})));
-            let files_opt: Result<Vec<File>, InfoError> = dict.get(FILES_KEY)
-                .ok_or(InfoError::MissingKey(FILES_KEY))
-                .and_then(|fs| fs.as_blist()
-                                 .or_else(|e| Err(InfoError::BencodeValError{for_key: FILES_KEY, err: e})))
-                .and_then(|file_dicts: &Vec<BVal<'a>>| {
-                    let collected: Result<Vec<File>, InfoError> =
-                    file_dicts.iter()
-                        .map(|fd_bval|
-                             fd_bval.as_bdict_ref()
-                             .or_else(|e| Err(InfoError::BencodeValError{for_key: FILES_KEY, err: e})))
-                        .map(|file_dict_opt|
-                             file_dict_opt.and_then(|file_dict| {
-                                 let length_opt = file_dict.get(LENGTH_KEY)
-                                 .ok_or(InfoError::MissingKey(LENGTH_KEY))
-                                 .and_then(|l| l.as_bint()
-                                           .or_else(|e| Err(InfoError::BencodeValError{for_key: LENGTH_KEY, err: e})));
-                             let path_opt = file_dict.get(PATH_KEY)
-                                 .ok_or(InfoError::MissingKey(PATH_KEY))
-                                 .and_then(|p| p.as_blist()
-                                           .or_else(|e| Err(InfoError::BencodeValError{for_key: PATH_KEY, err: e})))
-                                 .and_then(|ps| components_to_path(ps));
-
-                             // TODO: zip here
-                             length_opt.and_then(|length| {
-                                 path_opt.map(|path| {
-                                     File{ length: length, path: path }
-                                 })
-                             })
-                        }))
-                        .collect();
-
-                    collected
-                });
-            // TODO: zip here
-            name_opt.and_then(|name| {
-                files_opt.map(|files| {
-                    Mode::Multi{ name: name, files: files }
-                })
-            })
+fn mode_from_cursor<'b, 'a>(info: &Cursor<'b, 'a>, skipped: &mut Vec<Path>) -> Result<Mode<'a>, InfoError> {
+    let name = info.str_at(NAME_KEY)?;
+    let mut total = 0;
+    // if there's length then it's single, otherwise it's multi
+    match info.get(LENGTH_KEY)? {
+        Some(length) => Ok(Mode::Single{
+            name: name,
+            length: length_from_cursor(&length, &mut total)?,
+            md5sum: md5sum_from_cursor(info, skipped)?,
+            attrs: attrs_from_cursor(info)?,
+        }),
+        None => {
+            let mut files = Vec::new();
+            for file in info.list_at(FILES_KEY)? {
+                files.push(file_from_cursor(&file, &mut total, skipped)?);
+            }
+            let safe = {
+                let raw: Vec<Vec<&str>> = files.iter().map(|f| f.components.iter().map(|c| &c[..]).collect()).collect();
+                let padding: Vec<bool> = files.iter().map(|f| f.attrs.padding).collect();
+                sanitize::paths_with_padding(&raw, &padding, &PathRules::default()).or_else(|e| Err(InfoError::BadPath(e)))?
+            };
+            for (file, path) in files.iter_mut().zip(safe) {
+                file.path = path;
+            }
+            Ok(Mode::Multi{ name: name, files: files })
         },
     }
 }
 
-fn components_to_path<'a>(ps: &Vec<BVal<'a>>) -> Result<PathBuf, InfoError> {
-    let maybe_strs: Result<Vec<&'a str>, InfoError> =
-        ps.iter()
-          .map(|component| component.as_bstring_str()
-                                    .or_else(|e| Err(InfoError::BencodeValError{for_key: PATH_KEY, err: e})))
-          .collect();
+fn file_from_cursor<'b, 'a>(file: &Cursor<'b, 'a>, total: &mut i64, skipped: &mut Vec<Path>) -> Result<File<'a>, InfoError> {
+    let length = length_from_cursor(&file.at(LENGTH_KEY)?, total)?;
+    let components = file.list_at(PATH_KEY)?.iter()
+        .map(|c| c.str().map(|s| s.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let md5sum = md5sum_from_cursor(file, skipped)?;
+    let attrs = attrs_from_cursor(file)?;
+    // the safe path is filled in once all the files are known
+    Ok(File{ length: length, components: components, path: PathBuf::new(), md5sum: md5sum, attrs: attrs })
+}
 
-    maybe_strs.map(|strs| {
-        strs.iter()
-            .fold(PathBuf::new(), |mut acc, item| { acc.push(*item); acc })
-    })
+// Adds to `total`, so `total_size` can't overflow later.
+fn length_from_cursor<'b, 'a>(length: &Cursor<'b, 'a>, total: &mut i64) -> Result<i64, InfoError> {
+    let n = length.int()?;
+    match total.checked_add(n) {
+        Some(sum) if n >= 0 => {
+            *total = sum;
+            Ok(n)
+        },
+        _ => Err(InfoError::BadLength(length.path.clone())),
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bencode;
+    use bencode::{BValue, Encode, ParseError, StrKeys};
+    use bencode::arbitrary::{dict, mangled};
+    use proptest::prelude::*;
+    use proptest::collection::vec;
 
     #[test]
     fn parse_sample_torrent() {
@@ -242,4 +635,346 @@ mod tests {
             Err(e) => panic!("Bad err: {:?}", e),
         }
     }
+
+    #[test]
+    fn strict_by_default() {
+        let bs = &b"d8:announce14:http://a.b/ann4:infod6:lengthi03e4:name1:x12:piece lengthi1e6:pieces0:ee"[..];
+        match parse(bs) {
+            Err(InfoError::Bencode(e)) => {
+                assert_eq!(e.kind, ParseError::LeadingZero);
+                assert_eq!(e.offset, 44);
+                assert_eq!(e.path.to_string(), "info.length");
+            },
+            other => panic!("expected a bencode error, got {:?}", other),
+        }
+        assert!(parse_with(bs, bencode::Mode::Lenient.into()).is_ok());
+    }
+
+    #[test]
+    fn errors_have_paths() {
+        let bs = &b"d8:announce14:http://a.b/ann4:infod5:filesld6:lengthi1e4:pathl1:aeed6:lengthi2e4:pathli7eeee4:name1:x12:piece lengthi1e6:pieces0:ee"[..];
+        match parse(bs) {
+            Err(InfoError::Read(e)) => assert_eq!(e.to_string(), "expected BString but found BInt at info.files[1].path[0]"),
+            other => panic!("expected a read error, got {:?}", other),
+        }
+        let bs = &b"d8:announce14:http://a.b/ann4:infod4:name1:x12:piece lengthi1e6:pieces0:ee"[..];
+        match parse(bs) {
+            Err(InfoError::Read(e)) => assert_eq!(e.to_string(), "missing info.files"),
+            other => panic!("expected a read error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_limits() {
+        let bs = include_bytes!("../../sample.mp4.torrent");
+        let limits = bencode::ParseLimits{ max_string_len: 32, ..bencode::ParseLimits::default() };
+        match parse_with(bs, bencode::Options::new(bencode::Mode::Strict).with_limits(limits)) {
+            Err(InfoError::Bencode(e)) => {
+                assert_eq!(e.kind, ParseError::StringTooLong);
+                assert_eq!(e.path.to_string(), "announce");
+            },
+            other => panic!("expected a bencode error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bad_lengths() {
+        // found by the `generated_torrents` property
+        let bs = &b"d8:announce14:http://a.b/ann4:infod6:lengthi-1e4:name1:x12:piece lengthi1e6:pieces0:ee"[..];
+        assert_eq!(parse(bs).err(), Some(InfoError::BadLength("info.length".parse().unwrap())));
+        let bs = &b"d8:announce14:http://a.b/ann4:infod5:filesld6:lengthi9223372036854775807e4:pathl1:aeed6:lengthi1e4:pathl1:beee4:name1:x12:piece lengthi1e6:pieces0:ee"[..];
+        assert_eq!(parse(bs).err(), Some(InfoError::BadLength("info.files[1].length".parse().unwrap())));
+    }
+
+    #[test]
+    fn announce_list() {
+        let info = dict(vec![("length", BValue::BInt(1)), ("name", BValue::from("x")),
+                             ("piece length", BValue::BInt(1)), ("pieces", BValue::from(""))]);
+        let tier = |urls: &[&str]| BValue::BList(urls.iter().map(|&u| BValue::from(u)).collect());
+        // no `announce` at all, and a tracker that doesn't parse
+        let bs = dict(vec![
+            ("announce-list", BValue::BList(vec![tier(&["http://a/ann", "http://./"]), tier(&[]), tier(&["udp://b:80"])])),
+            ("info", info.clone()),
+        ]).encode();
+        let m = parse(&bs).unwrap();
+        assert_eq!(m.announce, None);
+        let tiers: Vec<Vec<String>> = m.tiers().iter().map(|t| t.iter().map(|u| u.to_string()).collect()).collect();
+        assert_eq!(tiers, vec![vec!["http://a/ann".to_string()], vec!["udp://b:80".to_string()]]);
+
+        let bs = &b"d8:announce14:http://a.b/ann4:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces0:ee"[..];
+        let m = parse(bs).unwrap();
+        assert_eq!(m.tiers(), vec![vec![Url::parse("http://a.b/ann").unwrap()]]);
+
+        let bs = &b"d13:announce-listl12:http://a/anne4:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces0:ee"[..];
+        assert_eq!(parse(bs).unwrap_err(), InfoError::Read(ReadError::At{
+            path: "announce-list[0]".parse().unwrap(),
+            err: Box::new(ReadError::WrongType{found: bencode::BSTRING_TYPE_NAME, expected: bencode::BLIST_TYPE_NAME}),
+        }));
+        let bs = &b"d4:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces0:ee"[..];
+        assert_eq!(parse(bs).unwrap_err(), InfoError::Read(ReadError::Missing("announce".parse().unwrap())));
+
+        // a bad `announce` doesn't matter when the list has trackers
+        for announce in &[BValue::from("http://./"), BValue::BInt(1)] {
+            let bs = dict(vec![
+                ("announce", announce.clone()),
+                ("announce-list", BValue::BList(vec![tier(&["http://a/ann"])])),
+                ("info", info.clone()),
+            ]).encode();
+            let m = parse(&bs).unwrap();
+            assert_eq!(m.announce, None);
+            assert_eq!(m.tiers(), vec![vec![Url::parse("http://a/ann").unwrap()]]);
+            assert_eq!(m.skipped, vec!["announce".parse().unwrap()]);
+        }
+        let bs = dict(vec![
+            ("announce", BValue::from("http://./")),
+            ("announce-list", BValue::BList(vec![tier(&["http://./"])])),
+            ("info", info.clone()),
+        ]).encode();
+        assert_eq!(parse(&bs).err(), Some(InfoError::BadUrl(url::ParseError::EmptyHost)));
+    }
+
+    #[test]
+    fn url_list() {
+        let info = || dict(vec![("length", BValue::BInt(1)), ("name", BValue::from("x")),
+                                ("piece length", BValue::BInt(1)), ("pieces", BValue::from(""))]);
+        let torrent = |urls: BValue| dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("info", info()),
+            ("url-list", urls),
+        ]).encode();
+        let bs = torrent(BValue::from("http://mirror/x"));
+        assert_eq!(parse(&bs).unwrap().url_list, vec![Url::parse("http://mirror/x").unwrap()]);
+        // often left empty by creators that don't use it
+        let bs = torrent(BValue::from(""));
+        assert!(parse(&bs).unwrap().url_list.is_empty());
+        let bs = torrent(BValue::BList(vec![BValue::from("http://m1/"), BValue::from("http://./"), BValue::from("http://m2/x")]));
+        let urls: Vec<String> = parse(&bs).unwrap().url_list.iter().map(|u| u.to_string()).collect();
+        assert_eq!(urls, vec!["http://m1/", "http://m2/x"]);
+        let bs = torrent(BValue::BInt(1));
+        assert_eq!(parse(&bs).unwrap_err(), InfoError::Read(ReadError::At{
+            path: "url-list".parse().unwrap(),
+            err: Box::new(ReadError::WrongType{found: bencode::BINT_TYPE_NAME, expected: bencode::BLIST_TYPE_NAME}),
+        }));
+        let bs = &b"d8:announce14:http://a.b/ann4:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces0:ee"[..];
+        assert!(parse(bs).unwrap().url_list.is_empty());
+    }
+
+    #[test]
+    fn optional_fields() {
+        let md5 = "0123456789abcdef0123456789ABCDEF";
+        let file = |path: &str, md5sum: Option<&str>| {
+            let mut attrs = vec![("length", BValue::BInt(1)), ("path", BValue::BList(vec![BValue::from(path)]))];
+            attrs.extend(md5sum.map(|s| ("md5sum", BValue::from(s))));
+            dict(attrs)
+        };
+        let bs = dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("comment", BValue::from("hi")),
+            ("created by", BValue::from("me")),
+            ("creation date", BValue::BInt(1500000000)),
+            ("encoding", BValue::from("UTF-8")),
+            ("info", dict(vec![
+                ("files", BValue::BList(vec![file("a", Some(md5)), file("b", None)])),
+                ("name", BValue::from("x")),
+                ("piece length", BValue::BInt(1)),
+                ("pieces", BValue::from("")),
+                ("private", BValue::BInt(1)),
+                ("source", BValue::from("TRK")),
+            ])),
+            ("nodes", BValue::BList(vec![BValue::BList(vec![BValue::from("router.example"), BValue::BInt(6881)])])),
+        ]).encode();
+        let m = parse(&bs).unwrap();
+        assert_eq!(m.comment, Some("hi"));
+        assert_eq!(m.created_by, Some("me"));
+        assert_eq!(m.creation_date, Some(1500000000));
+        assert_eq!(m.encoding, Some("UTF-8"));
+        assert_eq!(m.nodes, vec![("router.example", 6881)]);
+        assert!(m.info.private());
+        assert_eq!(m.info.source(), Some("TRK"));
+        match *m.info.mode() {
+            Mode::Multi{ ref files, .. } => {
+                assert_eq!(files[0].md5sum(), Some(md5));
+                assert_eq!(files[1].md5sum(), None);
+                assert_eq!(files[1].path(), ::std::path::Path::new("b"));
+                assert_eq!(files[1].length(), 1);
+            },
+            ref other => panic!("expected multi-file, got {:?}", other),
+        }
+
+        // all of them are optional
+        let bs = &b"d8:announce14:http://a.b/ann4:infod6:lengthi1e6:md5sum32:0123456789abcdef0123456789abcdef4:name1:x12:piece lengthi1e6:pieces0:ee"[..];
+        let m = parse(bs).unwrap();
+        assert_eq!((m.comment, m.created_by, m.creation_date, m.encoding), (None, None, None, None));
+        assert!(m.nodes.is_empty());
+        assert!(!m.info.private());
+        assert_eq!(m.info.source(), None);
+        assert_eq!(*m.info.mode(), Mode::Single{
+            name: "x",
+            length: 1,
+            md5sum: Some("0123456789abcdef0123456789abcdef"),
+            attrs: Attrs::default(),
+        });
+    }
+
+    #[test]
+    fn bad_optional_fields() {
+        let with = |root: Vec<(&str, BValue)>, info: Vec<(&str, BValue)>| {
+            let mut info_pairs = vec![("length", BValue::BInt(1)), ("name", BValue::from("x")),
+                                      ("piece length", BValue::BInt(1)), ("pieces", BValue::from(""))];
+            info_pairs.extend(info);
+            let mut pairs = vec![("announce", BValue::from("http://a/ann")), ("info", dict(info_pairs))];
+            pairs.extend(root);
+            dict(pairs).encode()
+        };
+        // `private` changes where peers come from, so it has to be right
+        assert_eq!(parse(&with(vec![], vec![("private", BValue::BInt(2))])).err(),
+                   Some(InfoError::BadValue("info.private".parse().unwrap())));
+
+        // the rest are left out, and noted
+        let skipped = |bs: &[u8]| -> Vec<String> {
+            parse(bs).unwrap().skipped.iter().map(|p| p.to_string()).collect()
+        };
+        assert_eq!(skipped(&with(vec![], vec![("md5sum", BValue::from("abc"))])), vec!["info.md5sum"]);
+        assert_eq!(skipped(&with(vec![], vec![("source", BValue::BInt(1))])), vec!["info.source"]);
+        assert_eq!(skipped(&with(vec![("comment", BValue::BString(vec![0xc4, 0xe3]))], vec![])), vec!["comment"]);
+        assert_eq!(skipped(&with(vec![("creation date", BValue::from("today"))], vec![])), vec!["creation date"]);
+        assert_eq!(skipped(&with(vec![("nodes", BValue::BInt(1))], vec![])), vec!["nodes"]);
+        let node = |host: &str, port| BValue::BList(vec![BValue::from(host), BValue::BInt(port)]);
+        let bs = with(vec![("nodes", BValue::BList(vec![node("a", 65536), BValue::BList(vec![]), node("b", 6881)]))], vec![]);
+        let m = parse(&bs).unwrap();
+        assert_eq!(m.nodes, vec![("b", 6881)]);
+        assert_eq!(m.skipped.iter().map(|p| p.to_string()).collect::<Vec<_>>(), vec!["nodes[0]", "nodes[1]"]);
+        let bs = with(vec![("comment", BValue::BInt(1)), ("encoding", BValue::BInt(1))], vec![]);
+        let m = parse(&bs).unwrap();
+        assert_eq!((m.comment, m.encoding), (None, None));
+        assert_eq!(m.skipped.len(), 2);
+    }
+
+    #[test]
+    fn file_attrs() {
+        let file = |path: Vec<&str>, length, attrs: Vec<(&str, BValue)>| {
+            let mut pairs = vec![
+                ("length", BValue::BInt(length)),
+                ("path", BValue::BList(path.into_iter().map(BValue::from).collect())),
+            ];
+            pairs.extend(attrs);
+            dict(pairs)
+        };
+        let torrent = |files| dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("info", dict(vec![
+                ("files", BValue::BList(files)),
+                ("name", BValue::from("x")),
+                ("piece length", BValue::BInt(4)),
+                ("pieces", BValue::BString(vec![7; 40])),
+            ])),
+        ]).encode();
+        let link = vec![("attr", BValue::from("lh")), ("symlink path", BValue::BList(vec![BValue::from("run")]))];
+        let bs = torrent(vec![
+            file(vec!["run"], 3, vec![("attr", BValue::from("x?"))]),
+            file(vec![".pad", "1"], 1, vec![("attr", BValue::from("p"))]),
+            file(vec!["link"], 0, link),
+            file(vec![".pad", "1"], 1, vec![("attr", BValue::from("p"))]),
+        ]);
+        let m = parse(&bs).unwrap();
+        let attrs: Vec<Attrs> = m.files().into_iter().map(|f| f.attrs).collect();
+        assert_eq!(attrs, vec![
+            Attrs{ executable: true, ..Attrs::default() },
+            Attrs{ padding: true, ..Attrs::default() },
+            Attrs{ hidden: true, symlink: Some(vec!["run".to_string()]), ..Attrs::default() },
+            Attrs{ padding: true, ..Attrs::default() },
+        ]);
+        // padding files can share a name
+        assert!(m.paths(&PathRules::strict()).is_ok());
+
+        let bs = torrent(vec![file(vec!["link"], 0, vec![("attr", BValue::from("l"))])]);
+        assert!(parse(&bs).is_err());
+    }
+
+    #[test]
+    fn hostile_paths() {
+        let file = |path: Vec<&str>| dict(vec![
+            ("length", BValue::BInt(1)),
+            ("path", BValue::BList(path.into_iter().map(BValue::from).collect())),
+        ]);
+        let bs = dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("info", dict(vec![
+                ("files", BValue::BList(vec![file(vec!["..", "..", "etc", "passwd"]), file(vec!["/etc", "passwd"])])),
+                ("name", BValue::from("..")),
+                ("piece length", BValue::BInt(1)),
+                ("pieces", BValue::BString(vec![7; 40])),
+            ])),
+        ]).encode();
+        let m = parse(&bs).unwrap();
+        let files = match *m.info.mode() {
+            Mode::Multi{ ref files, .. } => files,
+            _ => panic!("expected multiple files"),
+        };
+        assert_eq!(files[0].components(), &["..", "..", "etc", "passwd"]);
+        assert_eq!(files[0].path(), ::std::path::Path::new("etc/passwd"));
+        assert_eq!(files[1].path(), ::std::path::Path::new("_etc/passwd"));
+        let paths: Vec<PathBuf> = m.files().into_iter().map(|f| f.path).collect();
+        assert_eq!(paths, vec![PathBuf::from("_/etc/passwd"), PathBuf::from("_/_etc/passwd")]);
+        assert!(m.paths(&PathRules::strict()).is_err());
+    }
+
+    #[test]
+    fn hosts_that_crash_url() {
+        // found by fuzzing the `metainfo` target
+        for announce in &["http://./", "http:/.", "http://%2e%2e", "http://x@.:80/", "http://\u{3002}", "http://xn--/"] {
+            let bs = dict(vec![
+                ("announce", BValue::from(*announce)),
+                ("info", dict(vec![("length", BValue::BInt(1)), ("name", BValue::from("x")),
+                                   ("piece length", BValue::BInt(1)), ("pieces", BValue::from(""))])),
+            ]).encode();
+            assert_eq!(parse(&bs).err(), Some(InfoError::BadUrl(url::ParseError::EmptyHost)));
+        }
+        assert!(parse_url("http://[::1]:6969/announce").is_ok());
+        assert!(parse_url("udp://tracker.example:80").is_ok());
+    }
+
+    fn torrent() -> BoxedStrategy<BValue> {
+        let name = || "[a-z]{1,8}";
+        let single = any::<i64>().prop_map(|n| vec![("length", BValue::BInt(n))]);
+        let file = (any::<i64>(), vec(name(), 1..3)).prop_map(|(n, path)| dict(vec![
+            ("length", BValue::BInt(n)),
+            ("path", BValue::BList(path.into_iter().map(BValue::from).collect())),
+        ]));
+        let multi = vec(file, 0..4).prop_map(|files| vec![("files", BValue::BList(files))]);
+        (name(), name(), 1..1i64 << 24, 0..4usize, prop_oneof![single, multi])
+            .prop_map(|(host, name, piece_length, pieces, mode)| {
+                let mut info = vec![
+                    ("name", BValue::from(name)),
+                    ("piece length", BValue::BInt(piece_length)),
+                    ("pieces", BValue::BString(vec![7; pieces * 20])),
+                ];
+                info.extend(mode);
+                dict(vec![
+                    ("announce", BValue::from(format!("http://{}.com/announce", host))),
+                    ("info", dict(info)),
+                ])
+            })
+            .boxed()
+    }
+
+    proptest! {
+        #[test]
+        fn generated_torrents(t in torrent()) {
+            let bs = t.encode();
+            if let Ok(m) = parse(&bs) {
+                let info = t.as_bdict_ref().unwrap().get_str("info").unwrap();
+                prop_assert_eq!(m.info.piece_length, info.as_bdict_ref().unwrap().get_str("piece length").unwrap().as_bint().unwrap());
+                prop_assert!(m.total_size() >= 0);
+                prop_assert_eq!(m.info.pieces.0.len() % 20, 0);
+            }
+        }
+
+        #[test]
+        fn mangled_torrents(bs in mangled()) {
+            if let Ok(m) = parse_with(&bs, bencode::Mode::Lenient.into()) {
+                m.total_size();
+            }
+        }
+    }
 }
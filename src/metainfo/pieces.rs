@@ -0,0 +1,171 @@
+//! Where pieces fall in files, and files in pieces.
+//!
+//! v1 pieces are cut from the files laid end to end in order, so one piece
+//! can span several files and a file can start partway into a piece. v2-only
+//! torrents have no v1 pieces, so nothing here applies to them.
+
+use std::cmp::{max, min};
+use std::ops::Range;
+
+use super::{Info, Mode};
+
+/// The part of a piece that lies in one file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    /// Index into the file list; always 0 for a single file.
+    pub file: usize,
+    /// Where the span starts in the file.
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl <'a> Info<'a> {
+    fn file_lengths(&self) -> Vec<u64> {
+        match self.mode {
+            Mode::Single{ length, .. } => vec![length as u64],
+            Mode::Multi{ ref files, .. } => files.iter().map(|f| f.length() as u64).collect(),
+        }
+    }
+
+    fn piece_start(&self, index: usize) -> Option<u64> {
+        if self.piece_length <= 0 || index >= self.pieces().len() {
+            return None;
+        }
+        (index as u64).checked_mul(self.piece_length as u64)
+    }
+
+    /// How long piece `index` is: `piece_length`, except for a short last
+    /// piece. `None` if there's no such piece.
+    pub fn piece_size(&self, index: usize) -> Option<u64> {
+        let total: u64 = self.file_lengths().iter().sum();
+        let start = self.piece_start(index)?;
+        if start >= total {
+            return None;
+        }
+        Some(min(self.piece_length as u64, total - start))
+    }
+
+    /// The files covering piece `index`, in order. Empty files are skipped.
+    pub fn piece_spans(&self, index: usize) -> Option<Vec<Span>> {
+        let size = self.piece_size(index)?;
+        self.block_spans(index, 0, size)
+    }
+
+    /// The files covering `length` bytes from `begin` in piece `index`.
+    /// `None` unless the whole range is inside the piece.
+    pub fn block_spans(&self, index: usize, begin: u64, length: u64) -> Option<Vec<Span>> {
+        let size = self.piece_size(index)?;
+        if begin.checked_add(length)? > size {
+            return None;
+        }
+        let start = self.piece_start(index)? + begin;
+        let end = start + length;
+        let mut spans = Vec::new();
+        let mut offset = 0;
+        for (i, len) in self.file_lengths().into_iter().enumerate() {
+            if offset >= end {
+                break;
+            }
+            let (from, to) = (max(start, offset), min(end, offset + len));
+            if from < to {
+                spans.push(Span{ file: i, offset: from - offset, length: to - from });
+            }
+            offset += len;
+        }
+        Some(spans)
+    }
+
+    /// The pieces holding any of file `file`. Empty for an empty file, and
+    /// cut short if the torrent has too few pieces.
+    pub fn file_pieces(&self, file: usize) -> Option<Range<usize>> {
+        if self.piece_length <= 0 {
+            return None;
+        }
+        let lengths = self.file_lengths();
+        let length = *lengths.get(file)?;
+        let start: u64 = lengths[..file].iter().sum();
+        let piece_length = self.piece_length as u64;
+        let first = start / piece_length;
+        let end = if length == 0 { first } else { (start + length - 1) / piece_length + 1 };
+        let count = self.pieces().len() as u64;
+        Some(min(first, count) as usize..min(end, count) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::{BValue, Encode};
+    use bencode::arbitrary::dict;
+    use metainfo::parse;
+
+    // files of 3, 0, 5 and 2 bytes in pieces of 4
+    fn torrent(pieces: usize) -> Vec<u8> {
+        let file = |name: &str, length| dict(vec![
+            ("length", BValue::BInt(length)),
+            ("path", BValue::BList(vec![BValue::from(name)])),
+        ]);
+        dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("info", dict(vec![
+                ("files", BValue::BList(vec![file("a", 3), file("empty", 0), file("b", 5), file("c", 2)])),
+                ("name", BValue::from("x")),
+                ("piece length", BValue::BInt(4)),
+                ("pieces", BValue::BString(vec![7; pieces * 20])),
+            ])),
+        ]).encode()
+    }
+
+    fn span(file: usize, offset: u64, length: u64) -> Span {
+        Span{ file: file, offset: offset, length: length }
+    }
+
+    #[test]
+    fn pieces_to_files() {
+        let bs = torrent(3);
+        let m = parse(&bs).unwrap();
+        assert_eq!(m.info.piece_spans(0), Some(vec![span(0, 0, 3), span(2, 0, 1)]));
+        assert_eq!(m.info.piece_spans(1), Some(vec![span(2, 1, 4)]));
+        assert_eq!(m.info.piece_spans(2), Some(vec![span(3, 0, 2)]));
+        assert_eq!(m.info.piece_spans(3), None);
+        assert_eq!(m.info.piece_size(2), Some(2));
+        assert_eq!(m.info.block_spans(0, 2, 2), Some(vec![span(0, 2, 1), span(2, 0, 1)]));
+        assert_eq!(m.info.block_spans(1, 4, 0), Some(vec![]));
+        assert_eq!(m.info.block_spans(2, 1, 2), None);
+        assert_eq!(m.info.block_spans(0, u64::max_value(), 2), None);
+    }
+
+    #[test]
+    fn files_to_pieces() {
+        let bs = torrent(3);
+        let m = parse(&bs).unwrap();
+        assert_eq!(m.info.file_pieces(0), Some(0..1));
+        assert_eq!(m.info.file_pieces(1), Some(0..0));
+        assert_eq!(m.info.file_pieces(2), Some(0..2));
+        assert_eq!(m.info.file_pieces(3), Some(2..3));
+        assert_eq!(m.info.file_pieces(4), None);
+
+        // too few pieces for the data
+        let bs = torrent(1);
+        let m = parse(&bs).unwrap();
+        assert_eq!(m.info.file_pieces(2), Some(0..1));
+        assert_eq!(m.info.file_pieces(3), Some(1..1));
+        assert_eq!(m.info.piece_spans(1), None);
+    }
+
+    #[test]
+    fn single_file() {
+        let bs = dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("info", dict(vec![
+                ("length", BValue::BInt(10)),
+                ("name", BValue::from("x")),
+                ("piece length", BValue::BInt(4)),
+                ("pieces", BValue::BString(vec![7; 60])),
+            ])),
+        ]).encode();
+        let m = parse(&bs).unwrap();
+        assert_eq!(m.info.piece_spans(2), Some(vec![span(0, 8, 2)]));
+        assert_eq!(m.info.file_pieces(0), Some(0..3));
+    }
+}
@@ -0,0 +1,373 @@
+//! Making torrent paths safe to write under a download directory.
+//!
+//! A torrent's paths are whatever its creator wrote, so a hostile one can
+//! name `..`, `/etc/passwd`, `a\0b` or `CON`. Each component is checked on
+//! its own, then whole paths are checked against each other. What's unsafe,
+//! and what happens to it, is set by `PathRules`:
+//!
+//! - `""`, `.` and `..` are dropped, since they'd stay in the directory or
+//!   leave it. A path with nothing left is named `_`.
+//! - `/` and `\` inside a component, which would split it or make it
+//!   absolute, NUL and other control characters become `_`.
+//! - With `windows`, so are `<>:"|?*`, which also covers drive letters.
+//!   Trailing dots and spaces are trimmed, and reserved device names like
+//!   `CON` or `lpt1.txt` get a `_` in front.
+//! - Components longer than `max_component_len` bytes are cut short.
+//! - A path that's the same as an earlier one, or is a file where an earlier
+//!   one needs a directory, gets `.1`, `.2` and so on before its extension.
+//!   With `case_insensitive`, paths that differ only in case collide too.
+//!   Padding files (BEP 47) are never written, so they can't collide.
+//!
+//! With `Action::Reject` instead, the first thing that would be rewritten is
+//! returned as an error.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use super::{Metainfo, Mode, Version};
+
+static RESERVED_CHARS: &'static str = "<>:\"|?*";
+static RESERVED_NAMES: &'static [&'static str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Reject,
+    Rewrite,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathRules {
+    /// For components that aren't safe as they are.
+    pub on_unsafe: Action,
+    /// For paths that collide with an earlier one.
+    pub on_collision: Action,
+    /// Also avoid what Windows can't create. On by default on Windows.
+    pub windows: bool,
+    /// Paths differing only in case collide. On by default on Windows and
+    /// macOS.
+    pub case_insensitive: bool,
+    pub max_component_len: usize,
+}
+
+impl Default for PathRules {
+    /// Rewrites everything, for the platform this is built for.
+    fn default() -> PathRules {
+        PathRules{
+            on_unsafe: Action::Rewrite,
+            on_collision: Action::Rewrite,
+            windows: cfg!(windows),
+            case_insensitive: cfg!(any(windows, target_os = "macos")),
+            max_component_len: 255,
+        }
+    }
+}
+
+impl PathRules {
+    /// Rejects anything that wouldn't be safe on every platform.
+    pub fn strict() -> PathRules {
+        PathRules{
+            on_unsafe: Action::Reject,
+            on_collision: Action::Reject,
+            windows: true,
+            case_insensitive: true,
+            ..PathRules::default()
+        }
+    }
+}
+
+/// Why a component isn't safe.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unsafe {
+    Empty,
+    CurrentDir,
+    ParentDir,
+    Separator,
+    Nul,
+    ControlChar,
+    ReservedChar,
+    TrailingDotOrSpace,
+    ReservedName,
+    TooLong,
+}
+
+impl fmt::Display for Unsafe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Unsafe::Empty => "is empty",
+            Unsafe::CurrentDir => "is .",
+            Unsafe::ParentDir => "is ..",
+            Unsafe::Separator => "has a path separator",
+            Unsafe::Nul => "has a NUL",
+            Unsafe::ControlChar => "has a control character",
+            Unsafe::ReservedChar => "has a character Windows doesn't allow",
+            Unsafe::TrailingDotOrSpace => "ends in a dot or space",
+            Unsafe::ReservedName => "is a reserved name on Windows",
+            Unsafe::TooLong => "is too long",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathError {
+    /// A component of file `file`'s path.
+    Unsafe{ file: usize, component: String, reason: Unsafe },
+    /// File `second` is at, or inside, file `first`'s `path`.
+    Collision{ first: usize, second: usize, path: PathBuf },
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PathError::Unsafe{ file, ref component, reason } =>
+                write!(f, "file {}: {:?} {}", file, component, reason),
+            PathError::Collision{ first, second, ref path } =>
+                write!(f, "files {} and {} collide at {}", first, second, path.display()),
+        }
+    }
+}
+
+/// One component made safe, or `None` if it should be dropped.
+pub fn component(c: &str, rules: &PathRules) -> Result<Option<String>, Unsafe> {
+    let rewrite = |reason| if rules.on_unsafe == Action::Reject { Err(reason) } else { Ok(()) };
+    match c {
+        "" => return rewrite(Unsafe::Empty).map(|_| None),
+        "." => return rewrite(Unsafe::CurrentDir).map(|_| None),
+        ".." => return rewrite(Unsafe::ParentDir).map(|_| None),
+        _ => (),
+    }
+    let mut out = String::with_capacity(c.len());
+    for ch in c.chars() {
+        let reason = match ch {
+            '/' | '\\' => Some(Unsafe::Separator),
+            '\0' => Some(Unsafe::Nul),
+            _ if ch.is_control() => Some(Unsafe::ControlChar),
+            _ if rules.windows && RESERVED_CHARS.contains(ch) => Some(Unsafe::ReservedChar),
+            _ => None,
+        };
+        match reason {
+            Some(reason) => {
+                rewrite(reason)?;
+                out.push('_');
+            },
+            None => out.push(ch),
+        }
+    }
+    if rules.windows {
+        if out.ends_with('.') || out.ends_with(' ') {
+            rewrite(Unsafe::TrailingDotOrSpace)?;
+            out = out.trim_right_matches(|c| c == '.' || c == ' ').to_string();
+            if out.is_empty() {
+                out.push('_');
+            }
+        }
+        let stem = out.split('.').next().unwrap_or("");
+        if RESERVED_NAMES.iter().any(|name| name.eq_ignore_ascii_case(stem)) {
+            rewrite(Unsafe::ReservedName)?;
+            out.insert(0, '_');
+        }
+    }
+    if out.len() > rules.max_component_len {
+        rewrite(Unsafe::TooLong)?;
+        let mut end = rules.max_component_len;
+        while !out.is_char_boundary(end) {
+            end -= 1;
+        }
+        out.truncate(end);
+    }
+    Ok(Some(out))
+}
+
+/// Safe relative paths for a list of files, each given as its components,
+/// in the same order.
+pub fn paths(files: &[Vec<&str>], rules: &PathRules) -> Result<Vec<PathBuf>, PathError> {
+    paths_with_padding(files, &[], rules)
+}
+
+/// Like `paths`, but files marked in `padding` are only made safe, not
+/// checked for collisions: creators often give many of them the same name.
+pub fn paths_with_padding(files: &[Vec<&str>], padding: &[bool], rules: &PathRules) -> Result<Vec<PathBuf>, PathError> {
+    // the first file at each path, and the first inside each directory
+    let mut taken_files: HashMap<String, usize> = HashMap::new();
+    let mut taken_dirs: HashMap<String, usize> = HashMap::new();
+    let mut out = Vec::new();
+    for (i, raw) in files.iter().enumerate() {
+        let mut parts = Vec::new();
+        for c in raw {
+            match component(c, rules) {
+                Ok(Some(c)) => parts.push(c),
+                Ok(None) => (),
+                Err(reason) => return Err(PathError::Unsafe{ file: i, component: c.to_string(), reason: reason }),
+            }
+        }
+        if parts.is_empty() {
+            if rules.on_unsafe == Action::Reject {
+                return Err(PathError::Unsafe{ file: i, component: String::new(), reason: Unsafe::Empty });
+            }
+            parts.push("_".to_string());
+        }
+        if padding.get(i) == Some(&true) {
+            out.push(parts.iter().collect());
+            continue;
+        }
+
+        for j in 0..parts.len() {
+            let last = j + 1 == parts.len();
+            let original = parts[j].clone();
+            let mut n = 0;
+            loop {
+                let k = key(&parts[..j + 1], rules);
+                let clash = match taken_files.get(&k) {
+                    None if last => taken_dirs.get(&k),
+                    clash => clash,
+                };
+                let first = match clash {
+                    Some(&first) => first,
+                    None => break,
+                };
+                if rules.on_collision == Action::Reject {
+                    return Err(PathError::Collision{ first: first, second: i, path: parts[..j + 1].iter().collect() });
+                }
+                n += 1;
+                parts[j] = numbered(&original, n);
+            }
+        }
+
+        for j in 1..parts.len() {
+            taken_dirs.entry(key(&parts[..j], rules)).or_insert(i);
+        }
+        taken_files.insert(key(&parts, rules), i);
+        out.push(parts.iter().collect());
+    }
+    Ok(out)
+}
+
+fn key(parts: &[String], rules: &PathRules) -> String {
+    let joined = parts.join("/");
+    if rules.case_insensitive { joined.to_lowercase() } else { joined }
+}
+
+// `a.txt` to `a.1.txt`; a leading dot isn't an extension
+fn numbered(name: &str, n: usize) -> String {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{}.{}{}", &name[..dot], n, &name[dot..]),
+        _ => format!("{}.{}", name, n),
+    }
+}
+
+impl <'a> Metainfo<'a> {
+    /// The paths `files` gives, made safe with `rules` instead of the
+    /// defaults: the name, then each file's components.
+    pub fn paths(&self, rules: &PathRules) -> Result<Vec<PathBuf>, PathError> {
+        let name = self.info.mode().name();
+        let (files, padding): (Vec<Vec<&str>>, Vec<bool>) = match *self.info.mode() {
+            Mode::Single{ .. } => (vec![vec![name]], vec![false]),
+            Mode::Multi{ ref files, .. } if self.info.version() == Version::V1 => files.iter()
+                .map(|f| (with_name(name, f.components().iter().map(|c| &c[..])), f.attrs().padding))
+                .unzip(),
+            Mode::Multi{ .. } => self.info.file_tree().iter()
+                .map(|f| (with_name(name, f.components().iter().cloned()), f.attrs().padding))
+                .unzip(),
+        };
+        paths_with_padding(&files, &padding, rules)
+    }
+}
+
+fn with_name<'s, I: Iterator<Item=&'s str>>(name: &'s str, components: I) -> Vec<&'s str> {
+    let mut path = vec![name];
+    path.extend(components);
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(on: Action, windows: bool) -> PathRules {
+        PathRules{ on_unsafe: on, on_collision: on, windows: windows, case_insensitive: windows, max_component_len: 8 }
+    }
+
+    fn rewritten(files: Vec<Vec<&str>>, windows: bool) -> Vec<String> {
+        paths(&files, &rules(Action::Rewrite, windows)).unwrap().iter()
+            .map(|p| p.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn traversal() {
+        let files = vec![
+            vec!["..", "..", "etc", "passwd"],
+            vec!["/etc", "shadow"],
+            vec!["a\\..\\b", ".", "", "c"],
+            vec!["..", "."],
+            vec!["nul\0byte", "tab\there"],
+        ];
+        assert_eq!(rewritten(files, false), vec!["etc/passwd", "_etc/shadow", "a_.._b/c", "_", "nul_byte/tab_here"]);
+    }
+
+    #[test]
+    fn windows_names() {
+        let files = vec![vec!["C:", "x"], vec!["aux.txt"], vec!["dots..."], vec!["a<b>?"], vec!["COM1"]];
+        assert_eq!(rewritten(files.clone(), true), vec!["C_/x", "_aux.txt", "dots", "a_b__", "_COM1"]);
+        // left alone elsewhere
+        assert_eq!(rewritten(files, false), vec!["C:/x", "aux.txt", "dots...", "a<b>?", "COM1"]);
+    }
+
+    #[test]
+    fn long_names() {
+        assert_eq!(rewritten(vec![vec!["abcdefghij"], vec!["\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}"]], false),
+                   vec!["abcdefgh", "\u{e9}\u{e9}\u{e9}\u{e9}"]);
+    }
+
+    #[test]
+    fn collisions() {
+        let files = vec![vec!["a.txt"], vec!["a.txt"], vec!["A.TXT"], vec!["d"], vec!["d", "x"], vec!["e", "y"], vec!["e"]];
+        assert_eq!(rewritten(files.clone(), false), vec!["a.txt", "a.1.txt", "A.TXT", "d", "d.1/x", "e/y", "e.1"]);
+        assert_eq!(rewritten(files.clone(), true), vec!["a.txt", "a.1.txt", "A.2.TXT", "d", "d.1/x", "e/y", "e.1"]);
+        // dropping `..` can make a collision too
+        assert_eq!(rewritten(vec![vec!["x", "..", "y"], vec!["x", "y"]], false), vec!["x/y", "x/y.1"]);
+
+        assert_eq!(paths(&files, &rules(Action::Reject, false)),
+                   Err(PathError::Collision{ first: 0, second: 1, path: PathBuf::from("a.txt") }));
+        assert_eq!(paths(&files[3..5], &rules(Action::Reject, false)),
+                   Err(PathError::Collision{ first: 0, second: 1, path: PathBuf::from("d") }));
+        assert_eq!(paths(&files[5..], &rules(Action::Reject, false)),
+                   Err(PathError::Collision{ first: 0, second: 1, path: PathBuf::from("e") }));
+    }
+
+    #[test]
+    fn padding() {
+        let files = vec![vec![".pad", "4"], vec!["a"], vec![".pad", "4"], vec![".pad", ".."], vec!["a"]];
+        let padding = [true, false, true, true, false];
+        let found = paths_with_padding(&files, &padding, &rules(Action::Rewrite, false)).unwrap();
+        assert_eq!(found, vec![".pad/4", "a", ".pad/4", ".pad", "a.1"].into_iter().map(PathBuf::from).collect::<Vec<_>>());
+        assert_eq!(paths_with_padding(&files[..3], &padding, &rules(Action::Reject, false)).unwrap().len(), 3);
+        assert!(paths_with_padding(&files[3..], &padding[3..], &rules(Action::Reject, false)).is_err());
+    }
+
+    #[test]
+    fn reject() {
+        let check = |c: &str, windows, reason| {
+            assert_eq!(paths(&[vec!["ok"], vec!["d", c]], &rules(Action::Reject, windows)),
+                       Err(PathError::Unsafe{ file: 1, component: c.to_string(), reason: reason }));
+        };
+        check("", false, Unsafe::Empty);
+        check(".", false, Unsafe::CurrentDir);
+        check("..", false, Unsafe::ParentDir);
+        check("/abs", false, Unsafe::Separator);
+        check("a\0", false, Unsafe::Nul);
+        check("a\x1b[0m", false, Unsafe::ControlChar);
+        check("a|b", true, Unsafe::ReservedChar);
+        check("a ", true, Unsafe::TrailingDotOrSpace);
+        check("Lpt1.log", true, Unsafe::ReservedName);
+        check("123456789", false, Unsafe::TooLong);
+        assert_eq!(paths(&[vec![]], &rules(Action::Reject, false)),
+                   Err(PathError::Unsafe{ file: 0, component: String::new(), reason: Unsafe::Empty }));
+        assert!(paths(&[vec!["a|b", "Lpt1.log"]], &rules(Action::Reject, false)).is_ok());
+    }
+}
@@ -0,0 +1,268 @@
+//! BitTorrent v2 metainfo (BEP 52): the `file tree` and `piece layers`.
+//!
+//! A v2 info dict describes its files as a tree of dicts keyed by path
+//! component, with each file's dict under the empty key. Every file is hashed
+//! on its own into a merkle tree whose root is its `pieces root`; the layer of
+//! that tree at piece size is kept outside the info dict, in `piece layers`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str;
+
+use bencode::{Cursor, PathSegment, ReadError};
+use sha256bytes::{SHA256Hash, SHA256Hashes};
+
+use super::{Attrs, File, InfoError, LENGTH_KEY, Mode, attrs_from_cursor, length_from_cursor};
+use super::sanitize;
+use super::sanitize::PathRules;
+
+static PIECES_ROOT_KEY: &'static str = "pieces root";
+
+/// A file from a v2 `file tree`.
+#[derive(Debug, PartialEq)]
+pub struct TreeFile<'a> {
+    // as in the tree, and made safe to write to
+    components: Vec<&'a str>,
+    path: PathBuf,
+    length: i64,
+    // absent for empty files
+    pieces_root: Option<SHA256Hash<'a>>,
+    attrs: Attrs,
+}
+
+impl <'a> TreeFile<'a> {
+    /// Relative to the root of the tree, and safe to create there.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The keys leading to the file. Not safe to write to.
+    pub fn components(&self) -> &[&'a str] {
+        &self.components
+    }
+
+    pub fn length(&self) -> i64 {
+        self.length
+    }
+
+    pub fn pieces_root(&self) -> Option<&SHA256Hash<'a>> {
+        self.pieces_root.as_ref()
+    }
+
+    pub fn attrs(&self) -> &Attrs {
+        &self.attrs
+    }
+}
+
+/// The files in `tree`, depth first in key order.
+pub fn files_from_tree<'b, 'a>(tree: &Cursor<'b, 'a>) -> Result<Vec<TreeFile<'a>>, InfoError> {
+    let mut files = Vec::new();
+    walk(tree, &mut Vec::new(), &mut 0, &mut files)?;
+    let safe = {
+        let raw: Vec<Vec<&str>> = files.iter().map(|f| f.components.clone()).collect();
+        let padding: Vec<bool> = files.iter().map(|f| f.attrs.padding).collect();
+        sanitize::paths_with_padding(&raw, &padding, &PathRules::default()).or_else(|e| Err(InfoError::BadPath(e)))?
+    };
+    for (file, path) in files.iter_mut().zip(safe) {
+        file.path = path;
+    }
+    Ok(files)
+}
+
+fn walk<'b, 'a>(node: &Cursor<'b, 'a>, path: &mut Vec<&'a str>, total: &mut i64, files: &mut Vec<TreeFile<'a>>)
+    -> Result<(), InfoError>
+{
+    for (key, value) in node.dict()? {
+        let child = Cursor{ value: value, path: node.path.child(PathSegment::Key(key.to_vec())) };
+        if key.is_empty() {
+            // a file needs a name above it
+            if path.is_empty() {
+                return Err(InfoError::BadFileTree(child.path));
+            }
+            let length = length_from_cursor(&child.at(LENGTH_KEY)?, total)?;
+            let pieces_root = match child.get(PIECES_ROOT_KEY)? {
+                Some(root) => Some(sha256_from_cursor(&root)?),
+                None => None,
+            };
+            files.push(TreeFile{
+                components: path.clone(),
+                path: PathBuf::new(),
+                length: length,
+                pieces_root: pieces_root,
+                attrs: attrs_from_cursor(&child)?,
+            });
+        } else {
+            let name = str::from_utf8(key)
+                .or_else(|e| Err(ReadError::At{ path: child.path.clone(), err: Box::new(ReadError::BadString(e)) }))?;
+            path.push(name);
+            walk(&child, path, total, files)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
+fn sha256_from_cursor<'b, 'a>(hash: &Cursor<'b, 'a>) -> Result<SHA256Hash<'a>, InfoError> {
+    let bytes = hash.bytes()?;
+    if bytes.len() != 32 {
+        return Err(InfoError::BadSha256(hash.path.clone()));
+    }
+    Ok(SHA256Hash::from_prehashed(bytes))
+}
+
+/// The v1-style layout of a v2-only torrent: single-file if the tree is just
+/// one file at its root.
+pub fn mode_from_tree<'a>(name: &'a str, files: &[TreeFile<'a>]) -> Mode<'a> {
+    match files {
+        [file] if file.components.len() == 1 =>
+            Mode::Single{ name: name, length: file.length, md5sum: None, attrs: file.attrs.clone() },
+        _ => Mode::Multi{
+            name: name,
+            files: files.iter().map(|f| File{
+                length: f.length,
+                components: f.components.iter().map(|c| c.to_string()).collect(),
+                path: f.path.clone(),
+                md5sum: None,
+                attrs: f.attrs.clone(),
+            }).collect(),
+        },
+    }
+}
+
+/// `piece layers`, keyed by the `pieces root` they belong to.
+pub fn piece_layers_from_cursor<'b, 'a>(layers: &Cursor<'b, 'a>) -> Result<HashMap<&'a [u8], SHA256Hashes<'a>>, InfoError> {
+    let mut out = HashMap::new();
+    for (root, hashes) in layers.dict()? {
+        let child = Cursor{ value: hashes, path: layers.path.child(PathSegment::Key(root.to_vec())) };
+        let bytes = child.bytes()?;
+        if root.len() != 32 || bytes.is_empty() || bytes.len() % 32 != 0 {
+            return Err(InfoError::BadSha256(child.path));
+        }
+        out.insert(root, SHA256Hashes(bytes));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use bencode::{BValue, Encode};
+    use bencode::arbitrary::dict;
+    use metainfo::{Attrs, FileEntry, InfoError, Mode, Version, parse};
+    use sha1bytes::SHA1Hash;
+    use sha256bytes::SHA256Hash;
+
+    fn file(length: i64, root: Option<u8>) -> BValue {
+        let mut attrs = vec![("length", BValue::BInt(length))];
+        if let Some(b) = root {
+            attrs.push(("pieces root", BValue::BString(vec![b; 32])));
+        }
+        dict(vec![("", dict(attrs))])
+    }
+
+    fn torrent(info: Vec<(&str, BValue)>, layers: Vec<(&str, BValue)>) -> Vec<u8> {
+        let mut root = vec![("announce", BValue::from("http://a/ann")), ("info", dict(info))];
+        if !layers.is_empty() {
+            root.push(("piece layers", BValue::BDict(layers.into_iter().map(|(k, v)| (vec![k.as_bytes()[0]; 32], v)).collect())));
+        }
+        dict(root).encode()
+    }
+
+    fn v2_info(name: &str, tree: BValue) -> Vec<(&str, BValue)> {
+        vec![
+            ("file tree", tree),
+            ("meta version", BValue::BInt(2)),
+            ("name", BValue::from(name)),
+            ("piece length", BValue::BInt(16384)),
+        ]
+    }
+
+    fn entry<'m, 'a>(path: &str, length: i64, root: Option<&'m SHA256Hash<'a>>) -> FileEntry<'m, 'a> {
+        FileEntry{ path: PathBuf::from(path), length: length, pieces_root: root, attrs: Attrs::default() }
+    }
+
+    #[test]
+    fn v2_only() {
+        let tree = dict(vec![
+            ("a", file(20000, Some(1))),
+            ("sub", dict(vec![("b", file(0, None))])),
+        ]);
+        let info = v2_info("dir", tree);
+        let info_bytes = dict(info.clone()).encode();
+        let bs = torrent(info, vec![("\x01", BValue::BString(vec![9; 64]))]);
+        let m = parse(&bs).unwrap();
+        assert_eq!(m.info.version(), Version::V2);
+        let v2_hash = SHA256Hash::from_bytes(&info_bytes);
+        assert_eq!(m.info.info_hash_v2, Some(v2_hash.clone()));
+        assert_eq!(m.info.info_hash, v2_hash.truncated());
+        assert!(m.info.pieces().is_empty());
+        assert_eq!(m.total_size(), 20000);
+
+        let root = SHA256Hash::from_prehashed(&[1; 32]);
+        assert_eq!(m.files(), vec![entry("dir/a", 20000, Some(&root)), entry("dir/sub/b", 0, None)]);
+        assert_eq!(m.piece_layer(&root).map(|l| l.len()), Some(2));
+        assert_eq!(m.piece_layer(&SHA256Hash::from_prehashed(&[2; 32])), None);
+    }
+
+    #[test]
+    fn v2_single_file() {
+        let bs = torrent(v2_info("x.iso", dict(vec![("x.iso", file(5, Some(1)))])), vec![]);
+        let m = parse(&bs).unwrap();
+        assert_eq!(*m.info.mode(), Mode::Single{ name: "x.iso", length: 5, md5sum: None, attrs: Attrs::default() });
+        let root = SHA256Hash::from_prehashed(&[1; 32]);
+        assert_eq!(m.files(), vec![entry("x.iso", 5, Some(&root))]);
+    }
+
+    #[test]
+    fn hybrid() {
+        let mut info = v2_info("dir", dict(vec![("a", file(3, Some(1))), ("b", file(4, Some(2)))]));
+        info.push(("files", BValue::BList(vec![
+            dict(vec![("length", BValue::BInt(3)), ("path", BValue::BList(vec![BValue::from("a")]))]),
+            dict(vec![("length", BValue::BInt(4)), ("path", BValue::BList(vec![BValue::from("b")]))]),
+        ])));
+        info.push(("pieces", BValue::BString(vec![7; 20])));
+        let info_bytes = dict(info.clone()).encode();
+        let bs = torrent(info, vec![]);
+        let m = parse(&bs).unwrap();
+        assert_eq!(m.info.version(), Version::Hybrid);
+        // both hashes, each over the whole info dict
+        assert_eq!(m.info.info_hash, SHA1Hash::from_bytes(&info_bytes));
+        assert_eq!(m.info.info_hash_v2, Some(SHA256Hash::from_bytes(&info_bytes)));
+        assert_eq!(m.info.pieces().len(), 1);
+        let roots = [SHA256Hash::from_prehashed(&[1; 32]), SHA256Hash::from_prehashed(&[2; 32])];
+        assert_eq!(m.files(), vec![entry("dir/a", 3, Some(&roots[0])), entry("dir/b", 4, Some(&roots[1]))]);
+    }
+
+    #[test]
+    fn v1_files() {
+        let bs = &b"d8:announce14:http://a.b/ann4:infod5:filesld6:lengthi1e4:pathl1:aeed6:lengthi2e4:pathl1:b1:ceee4:name1:x12:piece lengthi1e6:pieces0:ee"[..];
+        let m = parse(bs).unwrap();
+        assert_eq!(m.info.version(), Version::V1);
+        assert_eq!(m.info.info_hash_v2, None);
+        assert_eq!(m.files(), vec![entry("x/a", 1, None), entry("x/b/c", 2, None)]);
+        let bs = &b"d8:announce14:http://a.b/ann4:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces0:ee"[..];
+        assert_eq!(parse(bs).unwrap().files(), vec![entry("x", 1, None)]);
+    }
+
+    #[test]
+    fn bad_v2() {
+        let mut info = v2_info("x", dict(vec![("a", file(1, None))]));
+        info[1] = ("meta version", BValue::BInt(3));
+        assert_eq!(parse(&torrent(info, vec![])).err(), Some(InfoError::UnsupportedVersion(3)));
+
+        let short_root = dict(vec![("", dict(vec![("length", BValue::BInt(1)), ("pieces root", BValue::from("short"))]))]);
+        match parse(&torrent(v2_info("x", dict(vec![("a", short_root)])), vec![])) {
+            Err(InfoError::BadSha256(path)) => assert_eq!(path.to_string(), "info.file tree.a..pieces root"),
+            other => panic!("expected a bad hash, got {:?}", other),
+        }
+        match parse(&torrent(v2_info("x", file(1, None)), vec![])) {
+            Err(InfoError::BadFileTree(path)) => assert_eq!(path.to_string(), "info.file tree."),
+            other => panic!("expected a bad file tree, got {:?}", other),
+        }
+        let bs = torrent(v2_info("x", dict(vec![("a", file(1, None))])), vec![("\x01", BValue::BString(vec![9; 40]))]);
+        match parse(&bs) {
+            Err(InfoError::BadSha256(_)) => (),
+            other => panic!("expected a bad piece layer, got {:?}", other),
+        }
+    }
+}
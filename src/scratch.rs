@@ -0,0 +1,34 @@
+//! Temporary directories for tests that touch the file system.
+
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A fresh, empty directory, removed with everything in it when dropped.
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    /// `name` should be unique among tests, since they run in parallel.
+    pub fn new(name: &str) -> Scratch {
+        let dir = env::temp_dir().join(format!("torrent-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+}
+
+impl Deref for Scratch {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
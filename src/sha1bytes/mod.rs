@@ -6,7 +6,7 @@ use std::slice::Chunks;
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
 pub struct SHA1Hash<'a>(Cow<'a, [u8]>);
 
 impl <'a> fmt::Debug for SHA1Hash<'a> {
@@ -29,6 +29,14 @@ impl <'a> SHA1Hash<'a> {
         SHA1Hash(Cow::Owned(out))
     }
 
+    pub fn from_owned(raw: Vec<u8>) -> SHA1Hash<'static> {
+        SHA1Hash(Cow::Owned(raw))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.borrow()
+    }
+
     // from http://illegalargumentexception.blogspot.com/2015/05/rust-byte-array-to-hex-string.html
     fn to_hex_string(&self) -> String {
         let &SHA1Hash(ref view) = self;
@@ -70,6 +78,20 @@ impl <'a> SHA1Hashes<'a> {
         let &SHA1Hashes(view) = self;
         view.chunks(20).map(SHA1Hash::from_prehashed)
     }
+
+    pub fn len(&self) -> usize {
+        self.0.len() / 20
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The hash of piece `i`.
+    pub fn get(&self, i: usize) -> Option<SHA1Hash<'a>> {
+        let &SHA1Hashes(view) = self;
+        view.chunks(20).nth(i).map(SHA1Hash::from_prehashed)
+    }
 }
 
 impl <'a> IntoIterator for SHA1Hashes<'a> {
@@ -0,0 +1,103 @@
+use std::iter;
+use std::fmt;
+use std::borrow::{Cow, Borrow};
+use std::slice::Chunks;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use sha1bytes::SHA1Hash;
+
+#[derive(PartialEq, Clone)]
+pub struct SHA256Hash<'a>(Cow<'a, [u8]>);
+
+impl <'a> fmt::Debug for SHA256Hash<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SHA256Hash({})", self.to_hex_string())
+    }
+}
+
+impl <'a> SHA256Hash<'a> {
+    pub fn from_prehashed(raw: &'a [u8]) -> SHA256Hash<'a> {
+        SHA256Hash(Cow::Borrowed(raw))
+    }
+
+    pub fn from_owned(raw: Vec<u8>) -> SHA256Hash<'static> {
+        SHA256Hash(Cow::Owned(raw))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> SHA256Hash<'static> {
+        let mut out: Vec<u8> = vec![0; 32];
+        let mut hasher = Sha256::new();
+        hasher.input(bytes);
+        hasher.result(&mut out);
+        SHA256Hash(Cow::Owned(out))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.borrow()
+    }
+
+    /// The first 20 bytes, which stand in for the info hash of v2 torrents
+    /// wherever a SHA-1 sized one is expected (BEP 52).
+    pub fn truncated(&self) -> SHA1Hash<'static> {
+        SHA1Hash::from_owned(self.as_bytes()[..20].to_vec())
+    }
+
+    pub fn to_hex_string(&self) -> String {
+        let strs: Vec<String> = self.as_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        strs.join("")
+    }
+}
+
+// ASSERT: len(input) % 32 == 0
+#[derive(PartialEq)]
+pub struct SHA256Hashes<'a>(pub &'a [u8]);
+
+impl <'a> fmt::Debug for SHA256Hashes<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let strs: Vec<String> = self.iter()
+            .map(|h| format!("{:?}", h))
+            .collect();
+
+        write!(f, "SHA256Hashes[{}]", strs.join(",\n"))
+    }
+}
+
+impl <'a> SHA256Hashes<'a> {
+    pub fn iter(&self) -> iter::Map<Chunks<'a, u8>, fn(&'a [u8]) -> SHA256Hash<'a>> {
+        let &SHA256Hashes(view) = self;
+        view.chunks(32).map(SHA256Hash::from_prehashed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len() / 32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> Option<SHA256Hash<'a>> {
+        let &SHA256Hashes(view) = self;
+        view.chunks(32).nth(i).map(SHA256Hash::from_prehashed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_and_truncate() {
+        let hash = SHA256Hash::from_bytes(b"abc");
+        assert_eq!(hash.to_hex_string(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hash.truncated(), SHA1Hash::from_prehashed(&hash.as_bytes()[..20]));
+        let hashes = SHA256Hashes(&[1; 64]);
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes.get(1), Some(SHA256Hash::from_prehashed(&[1; 32])));
+        assert_eq!(hashes.get(2), None);
+    }
+}
@@ -0,0 +1,308 @@
+//! A torrent's pieces on disk, in the files it describes.
+//!
+//! Files go under the torrent's name in a download directory, at the paths
+//! `metainfo` has made safe, and pieces are split across them with
+//! `Info::piece_spans`. File attributes (BEP 47) are honored: padding files
+//! are never created, since their bytes are zeros that only exist in pieces;
+//! symlinks are made in place of files, and must have no length, as their
+//! bytes would have nowhere to go; and executable files get an exec bit
+//! for each read bit. Hidden files are left alone, as on Unix that's only a
+//! leading dot in the name.
+//!
+//! v2-only torrents have no v1 pieces, so their files can be created but no
+//! pieces written.
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use metainfo::{Attrs, Info, Metainfo, Mode};
+use metainfo::sanitize;
+use metainfo::sanitize::PathRules;
+
+#[derive(Debug)]
+pub enum StorageError {
+    NoSuchPiece(usize),
+    WrongLength{ expected: u64, found: usize },
+    // a symlink whose length puts bytes in pieces
+    LinkWithData(PathBuf),
+    Io(PathBuf, io::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StorageError::NoSuchPiece(i) => write!(f, "no piece {}", i),
+            StorageError::WrongLength{ expected, found } =>
+                write!(f, "expected a piece of {} bytes but got {}", expected, found),
+            StorageError::LinkWithData(ref path) => write!(f, "{}: symlink with a non-zero length", path.display()),
+            StorageError::Io(ref path, ref e) => write!(f, "{}: {}", path.display(), e),
+        }
+    }
+}
+
+impl error::Error for StorageError {
+    fn description(&self) -> &str {
+        "storage error"
+    }
+}
+
+pub struct Storage<'m, 'a: 'm> {
+    info: &'m Info<'a>,
+    // by index in the file list; None for padding and symlinks, which hold
+    // no data of their own
+    paths: Vec<Option<PathBuf>>,
+}
+
+impl <'m, 'a> Storage<'m, 'a> {
+    /// Creates the torrent's files under `dir`, keeping whatever data is
+    /// already in them.
+    pub fn create(dir: &Path, mi: &'m Metainfo<'a>) -> Result<Storage<'m, 'a>, StorageError> {
+        let root = dir.join(mi.info.mode().safe_name());
+        let files: Vec<(PathBuf, i64, &Attrs)> = match *mi.info.mode() {
+            Mode::Single{ length, ref attrs, .. } => vec![(PathBuf::new(), length, attrs)],
+            Mode::Multi{ ref files, .. } => files.iter().map(|f| (f.path().to_path_buf(), f.length(), f.attrs())).collect(),
+        };
+
+        let mut paths = Vec::new();
+        for (relative, length, attrs) in files {
+            let path = root.join(&relative);
+            if attrs.padding {
+                paths.push(None);
+                continue;
+            }
+            if attrs.symlink.is_some() && length != 0 {
+                return Err(StorageError::LinkWithData(path));
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).or_else(|e| Err(StorageError::Io(parent.to_path_buf(), e)))?;
+            }
+            let io_error = |e| StorageError::Io(path.clone(), e);
+            match attrs.symlink {
+                Some(ref target) => {
+                    let target = link_target(&relative, target);
+                    if fs::symlink_metadata(&path).is_ok() {
+                        fs::remove_file(&path).or_else(|e| Err(io_error(e)))?;
+                    }
+                    symlink(&target, &path).or_else(|e| Err(io_error(e)))?;
+                    paths.push(None);
+                },
+                None => {
+                    let file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(&path).or_else(|e| Err(io_error(e)))?;
+                    file.set_len(length as u64).or_else(|e| Err(io_error(e)))?;
+                    if attrs.executable {
+                        set_executable(&file).or_else(|e| Err(io_error(e)))?;
+                    }
+                    paths.push(Some(path));
+                },
+            }
+        }
+        Ok(Storage{ info: &mi.info, paths: paths })
+    }
+
+    /// Writes piece `index`, which should already have been checked.
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> Result<(), StorageError> {
+        let expected = self.info.piece_size(index).ok_or(StorageError::NoSuchPiece(index))?;
+        if data.len() as u64 != expected {
+            return Err(StorageError::WrongLength{ expected: expected, found: data.len() });
+        }
+        let mut at = 0;
+        for span in self.info.piece_spans(index).ok_or(StorageError::NoSuchPiece(index))? {
+            let chunk = &data[at..at + span.length as usize];
+            at += span.length as usize;
+            let path = match self.paths[span.file] {
+                Some(ref path) => path,
+                None => continue,
+            };
+            let io_error = |e| StorageError::Io(path.clone(), e);
+            let mut file = fs::OpenOptions::new().write(true).open(path).or_else(|e| Err(io_error(e)))?;
+            file.seek(SeekFrom::Start(span.offset)).or_else(|e| Err(io_error(e)))?;
+            file.write_all(chunk).or_else(|e| Err(io_error(e)))?;
+        }
+        Ok(())
+    }
+
+    /// Reads piece `index` back. Padding reads as zeros.
+    pub fn read_piece(&self, index: usize) -> Result<Vec<u8>, StorageError> {
+        let size = self.info.piece_size(index).ok_or(StorageError::NoSuchPiece(index))?;
+        let mut data = vec![0; size as usize];
+        let mut at = 0;
+        for span in self.info.piece_spans(index).ok_or(StorageError::NoSuchPiece(index))? {
+            let chunk = &mut data[at..at + span.length as usize];
+            at += span.length as usize;
+            let path = match self.paths[span.file] {
+                Some(ref path) => path,
+                None => continue,
+            };
+            let io_error = |e| StorageError::Io(path.clone(), e);
+            let mut file = fs::File::open(path).or_else(|e| Err(io_error(e)))?;
+            file.seek(SeekFrom::Start(span.offset)).or_else(|e| Err(io_error(e)))?;
+            file.read_exact(chunk).or_else(|e| Err(io_error(e)))?;
+        }
+        Ok(data)
+    }
+}
+
+// `symlink path` is relative to the torrent's directory, so climb out of
+// the link's own directories first. With `..` dropped from the target, the
+// link can't point outside the torrent.
+fn link_target(link: &Path, target: &[String]) -> PathBuf {
+    let mut path = PathBuf::new();
+    for _ in 1..link.components().count() {
+        path.push("..");
+    }
+    let rules = PathRules::default();
+    for c in target {
+        if let Ok(Some(c)) = sanitize::component(c, &rules) {
+            path.push(c);
+        }
+    }
+    if path.as_os_str().is_empty() {
+        path.push(".");
+    }
+    path
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    ::std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    ::std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(unix)]
+fn set_executable(file: &fs::File) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = file.metadata()?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(mode | (mode & 0o444) >> 2);
+    file.set_permissions(permissions)
+}
+
+// there's no exec bit to set
+#[cfg(not(unix))]
+fn set_executable(_: &fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bencode::{BValue, Encode};
+    use bencode::arbitrary::dict;
+    use metainfo::parse;
+    use scratch::Scratch;
+
+    fn file(path: Vec<&str>, length: i64, attrs: Vec<(&str, BValue)>) -> BValue {
+        let mut pairs = vec![
+            ("length", BValue::BInt(length)),
+            ("path", BValue::BList(path.into_iter().map(BValue::from).collect())),
+        ];
+        pairs.extend(attrs);
+        dict(pairs)
+    }
+
+    // "abc", padded to a piece, then "defg" in bin/run and a link to it
+    fn torrent() -> Vec<u8> {
+        let attr = |a: &str| ("attr", BValue::from(a));
+        let link = vec![attr("l"), ("symlink path", BValue::BList(vec![BValue::from("bin"), BValue::from("run")]))];
+        dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("info", dict(vec![
+                ("files", BValue::BList(vec![
+                    file(vec!["a"], 3, vec![]),
+                    file(vec![".pad", "1"], 1, vec![attr("p")]),
+                    file(vec!["bin", "run"], 4, vec![attr("x")]),
+                    file(vec!["lib", "run"], 0, link),
+                ])),
+                ("name", BValue::from("x")),
+                ("piece length", BValue::BInt(4)),
+                ("pieces", BValue::BString(vec![7; 40])),
+            ])),
+        ]).encode()
+    }
+
+    #[test]
+    fn pieces_round_trip() {
+        let dir = Scratch::new("storage-round-trip");
+        let bs = torrent();
+        let m = parse(&bs).unwrap();
+        let storage = Storage::create(&dir, &m).unwrap();
+        storage.write_piece(0, b"abc\0").unwrap();
+        storage.write_piece(1, b"defg").unwrap();
+
+        let root = dir.join("x");
+        assert_eq!(fs::read(root.join("a")).unwrap(), b"abc".to_vec());
+        assert_eq!(fs::read(root.join("bin/run")).unwrap(), b"defg".to_vec());
+        assert!(!root.join(".pad").exists());
+        assert_eq!(storage.read_piece(0).unwrap(), b"abc\0".to_vec());
+        assert_eq!(storage.read_piece(1).unwrap(), b"defg".to_vec());
+
+        match storage.write_piece(1, b"de") {
+            Err(StorageError::WrongLength{ expected: 4, found: 2 }) => (),
+            other => panic!("expected wrong length, got {:?}", other),
+        }
+        match storage.read_piece(2) {
+            Err(StorageError::NoSuchPiece(2)) => (),
+            other => panic!("expected no such piece, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_and_exec_bits() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = Scratch::new("storage-attrs");
+        let bs = torrent();
+        let m = parse(&bs).unwrap();
+        let storage = Storage::create(&dir, &m).unwrap();
+        storage.write_piece(1, b"defg").unwrap();
+
+        let root = dir.join("x");
+        assert_eq!(fs::read_link(root.join("lib/run")).unwrap(), PathBuf::from("../bin/run"));
+        assert_eq!(fs::read(root.join("lib/run")).unwrap(), b"defg".to_vec());
+        let mode = fs::metadata(root.join("bin/run")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o111, (mode & 0o444) >> 2);
+        assert!(mode & 0o100 != 0);
+        assert_eq!(fs::metadata(root.join("a")).unwrap().permissions().mode() & 0o111, 0);
+
+        // again over the same files, as when resuming
+        Storage::create(&dir, &m).unwrap();
+        assert_eq!(fs::read(root.join("lib/run")).unwrap(), b"defg".to_vec());
+    }
+
+    #[test]
+    fn links_with_data() {
+        let dir = Scratch::new("storage-link-data");
+        let link = vec![("attr", BValue::from("l")), ("symlink path", BValue::BList(vec![BValue::from("a")]))];
+        let bs = dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("info", dict(vec![
+                ("files", BValue::BList(vec![file(vec!["a"], 3, vec![]), file(vec!["l"], 1, link)])),
+                ("name", BValue::from("x")),
+                ("piece length", BValue::BInt(4)),
+                ("pieces", BValue::BString(vec![7; 20])),
+            ])),
+        ]).encode();
+        let m = parse(&bs).unwrap();
+        match Storage::create(&dir, &m) {
+            Err(StorageError::LinkWithData(ref path)) if *path == dir.join("x/l") => (),
+            other => panic!("expected a link with data, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn link_targets() {
+        let target = |link: &str, to: Vec<&str>| link_target(Path::new(link), &to.into_iter().map(String::from).collect::<Vec<_>>());
+        assert_eq!(target("l", vec!["a", "b"]), PathBuf::from("a/b"));
+        assert_eq!(target("d/e/l", vec!["a"]), PathBuf::from("../../a"));
+        assert_eq!(target("l", vec!["..", "..", "etc", "passwd"]), PathBuf::from("etc/passwd"));
+        assert_eq!(target("l", vec![]), PathBuf::from("."));
+    }
+}
@@ -1,8 +1,9 @@
-use nom::IResult;
+pub mod tiers;
+
+use std::fmt;
 use std::net::Ipv4Addr;
-use std::collections::HashMap;
 use bencode;
-use bencode::{BVal, bdict};
+use bencode::{BVal, BencodeError, Cursor, ReadError};
 use peers as p;
 
 #[derive(Debug, PartialEq)]
@@ -15,7 +16,17 @@ pub struct Announcement {
     // TODO: extensions
 }
 
-static ROOT_KEY: &'static str = "_root_";
+impl Announcement {
+    /// Seconds to wait before announcing again.
+    pub fn interval(&self) -> i32 {
+        self.interval
+    }
+
+    pub fn peers(&self) -> &[p::Peer] {
+        &self.peers
+    }
+}
+
 static COMPLETE_KEY: &'static str = "complete";
 static DOWNLOADED_KEY: &'static str = "downloaded";
 static INCOMPLETE_KEY: &'static str = "incomplete";
@@ -25,78 +36,72 @@ static FAILURE_REASON_KEY: &'static str = "failure reason";
 
 #[derive(Debug)]
 pub enum Error {
-    MissingKey(&'static str),
+    // a missing key or wrong type, with its path
+    Read(ReadError),
     BadPeerFormat,
-    BencodeValError{for_key: &'static str, err: bencode::ReadError},
-    ExtraBytes,
-    MissingBytes,
-    BencodeParseError,
+    Bencode(BencodeError),
     TrackerReason(String)
 }
 
-pub fn parse(contents: &[u8]) -> Result<Announcement, Error> {
-    match bdict(contents) {
-        IResult::Done(rest, bv) =>
-            if rest == &b""[..] {
-               announce_from_bval(bv)
-            } else {
-               Err(Error::ExtraBytes)
-            },
-        IResult::Incomplete(_) => Err(Error::MissingBytes),
-        IResult::Error(_) => Err(Error::BencodeParseError),
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Read(ref e) => write!(f, "{}", e),
+            Error::BadPeerFormat => write!(f, "peers isn't a multiple of 6 bytes"),
+            Error::Bencode(ref e) => write!(f, "{}", e),
+            Error::TrackerReason(ref reason) => write!(f, "tracker says: {}", reason),
+        }
     }
 }
 
-fn get_opt_i32<'a>(m: &HashMap<&'a str, BVal<'a>>, key: &'static str) -> Result<i32, Error> {
-    return m.get(key)
-            .ok_or(Error::MissingKey(key))
-            .and_then(|c| c.as_bint()
-                      .map(|i| i as i32)
-                      .or_else(|e| Err(Error::BencodeValError{for_key: key, err: e})))
+impl From<ReadError> for Error {
+    fn from(e: ReadError) -> Error {
+        Error::Read(e)
+    }
 }
 
-fn announce_from_bval<'a>(bv: BVal<'a>) -> Result<Announcement, Error> {
-    bv.as_bdict()
-        .or_else(|e| Err(Error::BencodeValError{for_key: ROOT_KEY, err: e}))
-        .and_then(|m| {
-        let complete_opt = get_opt_i32(&m, COMPLETE_KEY);
-        let incomplete_opt = get_opt_i32(&m, INCOMPLETE_KEY);
-        let downloaded_opt = get_opt_i32(&m, DOWNLOADED_KEY);
-        let interval_opt = get_opt_i32(&m, INTERVAL_KEY);
-
-        let peers_opt = m.get(PEERS_KEY)
-            .ok_or(Error::MissingKey(PEERS_KEY))
-            .and_then(|p| p.as_bstring_bytes()
-                      .or_else(|e| Err(Error::BencodeValError{for_key: PEERS_KEY, err: e})))
-            .and_then(|bs| 
-                      if bs.len() % 6 != 0 {
-                        Err(Error::BadPeerFormat)
-                      } else {
-                          let peers: Vec<p::Peer> = bs.chunks(6)
-                              .map(|chunk| {
-                                  (Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]), 
-                                     ((chunk[4] as u16) << 8) + chunk[5] as u16)
-                              })
-                              .collect();
-                          Ok(peers)
-                      });
-
-        // TODO: For paralellism, a zip here would be better
-        interval_opt.and_then(|interval| {
-            peers_opt.map(|peers| Announcement{ 
-                complete: complete_opt.ok(),
-                downloaded: downloaded_opt.ok(),
-                incomplete: incomplete_opt.ok(),
-                interval: interval,
-                peers: peers,
-            })
+pub fn parse(contents: &[u8]) -> Result<Announcement, Error> {
+    parse_with(contents, bencode::Options::new(bencode::Mode::Lenient))
+}
+
+/// Parses with custom `Options`, e.g. tighter `ParseLimits` for a tracker
+/// that isn't trusted.
+pub fn parse_with(contents: &[u8], opts: bencode::Options) -> Result<Announcement, Error> {
+    bencode::decode(contents, opts)
+        .or_else(|e| Err(Error::Bencode(e)))
+        .and_then(announce_from_bval)
+}
+
+fn get_opt_i32<'b, 'a>(root: &Cursor<'b, 'a>, key: &'static str) -> Option<i32> {
+    root.int_at(key).ok().map(|i| i as i32)
+}
+
+fn peers_from_bytes(bs: &[u8]) -> Result<Vec<p::Peer>, Error> {
+    if bs.len() % 6 != 0 {
+        return Err(Error::BadPeerFormat);
+    }
+    Ok(bs.chunks(6)
+        .map(|chunk| {
+            (Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]),
+               ((chunk[4] as u16) << 8) + chunk[5] as u16)
         })
-        // TODO: loko for FAILURE_REASON
-        /*res.or_else(|e| m.get(FAILURE_REASON_KEY)
-                        .ok_or(e)
-                        .and_then(|r| r.as_bstring_str()
-                                  .or_else(|e_| Err(e))
-                                  .and_then(|reason| Err(Error::TrackerReason(String::from_utf8(reason))))))*/
+        .collect())
+}
+
+fn announce_from_bval<'a>(bv: BVal<'a>) -> Result<Announcement, Error> {
+    let root = bv.cursor();
+    // a refusal has this instead of the other keys
+    if let Some(reason) = root.get(FAILURE_REASON_KEY)? {
+        return Err(Error::TrackerReason(String::from_utf8_lossy(reason.bytes()?).into_owned()));
+    }
+    let interval = root.int_at(INTERVAL_KEY)?;
+    let peers = peers_from_bytes(root.bytes_at(PEERS_KEY)?)?;
+    Ok(Announcement{
+        complete: get_opt_i32(&root, COMPLETE_KEY),
+        downloaded: get_opt_i32(&root, DOWNLOADED_KEY),
+        incomplete: get_opt_i32(&root, INCOMPLETE_KEY),
+        interval: interval as i32,
+        peers: peers,
     })
 }
 
@@ -105,30 +110,105 @@ fn announce_from_bval<'a>(bv: BVal<'a>) -> Result<Announcement, Error> {
 //
 //
 
+use std::cell::RefCell;
 use std::cmp::max;
 use std::str::from_utf8;
 use std::net::ToSocketAddrs;
 use std::io::{stdout, Write};
+use std::rc::Rc;
 
 use rotor;
 use httparse;
 use rotor::mio::tcp::{TcpStream};
-use rotor::{Scope};
+use rotor::{Scope, Machine, Response, EventSet};
 use time::{SteadyTime, Duration};
 use tracker;
 use metainfo;
 use sha1bytes;
 use rand;
 use rand::Rng;
+use url::Url;
 
-use rotor_http::client::{connect_tcp, Request, Head, Client, RecvMode};
+use rotor_http::client::{connect_tcp, Fsm, Request, Head, Client, RecvMode};
 use rotor_http::client::{Context as HttpCtx};
 use rotor_http::version::HttpVersion;
 use rotor_http::method::Method;
 use rotor_http::Deadline;
 
-struct Context;
-impl HttpCtx for Context {}
+static TIMEOUT_SECS: i64 = 30;
+static MAX_REPLY_BYTES: usize = 16386;
+
+// The tracker's reply, or why there isn't one, left for `announce_to` when
+// the loop stops.
+type Reply = Rc<RefCell<Option<Result<Vec<u8>, String>>>>;
+
+struct Context(Reply);
+impl HttpCtx for Context {
+    // no sooner than `Announce` gives up, as rotor-http panics on these
+    fn byte_timeout(&self) -> Duration {
+        Duration::seconds(TIMEOUT_SECS)
+    }
+}
+
+fn finish(scope: &mut Scope<Context>, reply: Result<Vec<u8>, String>) {
+    *scope.0.borrow_mut() = Some(reply);
+    scope.shutdown_loop();
+}
+
+// Stops the loop however the request ends. rotor-http drops the machine
+// without telling `Req` when the connection fails, and panics when a read
+// takes too long, so this gives up first.
+struct Announce {
+    fsm: Fsm<Req, TcpStream>,
+    deadline: SteadyTime,
+}
+
+type Seed = <Fsm<Req, TcpStream> as Machine>::Seed;
+
+impl Announce {
+    fn new(fsm: Fsm<Req, TcpStream>) -> Announce {
+        Announce{ fsm: fsm, deadline: SteadyTime::now() + Duration::seconds(TIMEOUT_SECS) }
+    }
+
+    fn watch(deadline: SteadyTime, res: Response<Fsm<Req, TcpStream>, Seed>, scope: &mut Scope<Context>)
+        -> Response<Announce, Seed>
+    {
+        let mut alive = false;
+        let res = res.map(|fsm| {
+            alive = true;
+            Announce{ fsm: fsm, deadline: deadline }
+        }, |seed| seed);
+        if !alive && scope.0.borrow().is_none() {
+            finish(scope, Err("connection closed without a reply".to_string()));
+        }
+        res
+    }
+}
+
+impl Machine for Announce {
+    type Context = Context;
+    type Seed = Seed;
+
+    fn create(seed: Seed, scope: &mut Scope<Context>) -> Result<Announce, Box<::std::error::Error>> {
+        Fsm::<Req, TcpStream>::create(seed, scope).map(Announce::new)
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<Context>) -> Response<Announce, Seed> {
+        Announce::watch(self.deadline, self.fsm.ready(events, scope), scope)
+    }
+    fn spawned(self, scope: &mut Scope<Context>) -> Response<Announce, Seed> {
+        Announce::watch(self.deadline, self.fsm.spawned(scope), scope)
+    }
+    fn timeout(self, scope: &mut Scope<Context>) -> Response<Announce, Seed> {
+        if SteadyTime::now() >= self.deadline {
+            finish(scope, Err("timed out".to_string()));
+            return Response::done();
+        }
+        Announce::watch(self.deadline, self.fsm.timeout(scope), scope)
+    }
+    fn wakeup(self, scope: &mut Scope<Context>) -> Response<Announce, Seed> {
+        Announce::watch(self.deadline, self.fsm.wakeup(scope), scope)
+    }
+}
 
 struct Req(String);
 
@@ -141,25 +221,21 @@ impl Client for Req {
         Some(self)
     }
     fn headers_received(self, head: Head, _request: &mut Request,
-                        _scope: &mut Scope<Self::Context>)
+                        scope: &mut Scope<Self::Context>)
         -> Option<(Self, RecvMode, Deadline)> {
-            println!("----- Headers -----");
-            println!("Status: {} {}", head.code, head.reason);
-            for header in head.headers {
-                println!("{}: {}", header.name,
-                         String::from_utf8_lossy(header.value));
+            if head.code != 200 {
+                finish(scope, Err(format!("got status {}", head.code)));
+                return None;
             }
-            Some((self,  RecvMode::Buffered(16386), Deadline::now() +
-                  Duration::seconds(1000)))
+            Some((self,  RecvMode::Buffered(MAX_REPLY_BYTES), Deadline::now() +
+                  Duration::seconds(TIMEOUT_SECS)))
     }
     fn response_received(self, data: &[u8], _request: &mut Request,
                          scope: &mut Scope<Self::Context>) {
-        println!("----- Response -----");
-        match parse(data) {
-            Err(e) => panic!("Got err {:?}", e),
-            Ok(announcement) => panic!("Look {:?}", announcement)
-        };
-        scope.shutdown_loop();
+        finish(scope, Ok(data.to_vec()));
+    }
+    fn bad_response(self, scope: &mut Scope<Self::Context>) {
+        finish(scope, Err("bad response".to_string()));
     }
     fn response_chunk(self, _chunk: &[u8], _request: &mut Request,
                       _scope: &mut Scope<Self::Context>) -> Option<Self> {
@@ -169,9 +245,10 @@ impl Client for Req {
                     _scope: &mut Scope<Self::Context>) {
         unreachable!();
     }
-    fn timeout(self, _request: &mut Request, _scope: &mut Scope<Self::Context>)
+    fn timeout(self, _request: &mut Request, scope: &mut Scope<Self::Context>)
         -> Option<(Self, Deadline)> {
-            unreachable!();
+            finish(scope, Err("timed out".to_string()));
+            None
     }
     fn wakeup(self, _request: &mut Request, _scope: &mut Scope<Self::Context>)
         -> Option<Self> {
@@ -179,24 +256,185 @@ impl Client for Req {
     }
 }
 
-pub fn start_every_interval(mi: metainfo::Metainfo) {
-    let mut rand = rand::thread_rng();
-    let rand_id: Vec<u8> = rand.gen_iter::<u8>().take(20).collect();
-    let peer_id = sha1bytes::SHA1Hash::from_prehashed(&rand_id[..]).to_url_escaped_string();
+/// A torrent's announce state, kept between announces so a tracker that
+/// answered stays in front of its tier (BEP 12).
+pub struct Announcer {
+    trackers: tiers::Tiers,
+    info_hash: String,
+    peer_id: String,
+    total_size: i64,
+}
+
+impl Announcer {
+    pub fn new(mi: &metainfo::Metainfo) -> Announcer {
+        let mut rand = rand::thread_rng();
+        let rand_id: Vec<u8> = rand.gen_iter::<u8>().take(20).collect();
+        Announcer{
+            trackers: tiers::Tiers::new(mi.tiers()),
+            info_hash: mi.info.info_hash.to_url_escaped_string(),
+            peer_id: sha1bytes::SHA1Hash::from_prehashed(&rand_id[..]).to_url_escaped_string(),
+            total_size: mi.total_size(),
+        }
+    }
 
-    let path = mi.announce_path();
-    let (domain, port) = mi.tracker_domain_port();
-    let total_size = mi.total_size();
-    let info_hash = mi.info.info_hash.to_url_escaped_string();
+    /// Announces tier by tier until a tracker answers, and returns it with
+    /// its answer. If none does, each tracker tried and why it failed.
+    pub fn announce(&mut self) -> Result<(Url, Announcement), Vec<(Url, String)>> {
+        let (info_hash, peer_id, total_size) = (&self.info_hash, &self.peer_id, self.total_size);
+        let mut failures = Vec::new();
+        let answered = self.trackers.first_working(|url| {
+            match announce_to(url, info_hash, peer_id, total_size) {
+                Ok(announcement) => Ok(announcement),
+                Err(e) => {
+                    failures.push((url.clone(), e));
+                    Err(())
+                },
+            }
+        });
+        answered.ok_or(failures)
+    }
+}
+
+pub fn start_every_interval(mi: metainfo::Metainfo) -> Result<(Url, Announcement), Vec<(Url, String)>> {
+    Announcer::new(&mi).announce()
+}
+
+fn announce_path(url: &Url) -> String {
+    url.path().map(|cs| cs.join("/")).unwrap_or("".to_string())
+}
+
+fn announce_to(url: &Url, info_hash: &str, peer_id: &str, total_size: i64) -> Result<Announcement, String> {
+    // no UDP (BEP 15) or TLS yet, so those trackers are passed over
+    if url.scheme != "http" {
+        return Err(format!("unsupported scheme {}", url.scheme));
+    }
+    let path = announce_path(url);
+    let domain = url.serialize_host().ok_or("no host".to_string())?;
+    let port = url.port_or_default().unwrap_or(80);
     let fullpath = format!("/{}?info_hash={}&peer_id={}&port={}&uploaded=0&downloaded=0&left={}&numwant=7&event=started", path, info_hash, peer_id, port, total_size);
 
     let event_loop = rotor::Loop::new(&rotor::Config::new()).unwrap();
-    let addr = (domain, port).to_socket_addrs()
-        .map(|mut addrs| addrs.next().unwrap())
-        .unwrap();
-    let mut loop_inst = event_loop.instantiate(Context);
+    let addr = (&domain[..], port).to_socket_addrs()
+        .or_else(|e| Err(e.to_string()))?
+        .next()
+        .ok_or("no addresses".to_string())?;
+    let reply: Reply = Rc::new(RefCell::new(None));
+    let mut loop_inst = event_loop.instantiate(Context(reply.clone()));
     loop_inst.add_machine_with(|scope| {
-        connect_tcp(scope, &addr, Req(fullpath.to_string()))
-    }).unwrap();
-    loop_inst.run().unwrap();
+        connect_tcp(scope, &addr, Req(fullpath.to_string())).map(Announce::new)
+    }).or_else(|e| Err(e.to_string()))?;
+    loop_inst.run().or_else(|e| Err(format!("{:?}", e)))?;
+    let body = reply.borrow_mut().take().unwrap_or(Err("no reply".to_string()))?;
+    parse(&body).or_else(|e| Err(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+    use bencode::{BValue, Encode};
+    use bencode::arbitrary::dict;
+
+    // Answers every announce with `body` until the test exits.
+    fn serve(body: Vec<u8>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+                response.extend_from_slice(&body);
+                stream.write_all(&response).unwrap();
+            }
+        });
+        Url::parse(&format!("http://127.0.0.1:{}/ann", port)).unwrap()
+    }
+
+    // Hangs up on every announce.
+    fn hang_up() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                drop(stream);
+            }
+        });
+        Url::parse(&format!("http://127.0.0.1:{}/ann", port)).unwrap()
+    }
+
+    fn torrent(tiers: Vec<Vec<String>>) -> Vec<u8> {
+        let tier = |urls: Vec<String>| BValue::BList(urls.into_iter().map(|u| BValue::from(&u[..])).collect());
+        dict(vec![
+            ("announce-list", BValue::BList(tiers.into_iter().map(tier).collect())),
+            ("info", dict(vec![("length", BValue::BInt(1)), ("name", BValue::from("x")),
+                               ("piece length", BValue::BInt(1)), ("pieces", BValue::BString(vec![7; 20]))])),
+        ]).encode()
+    }
+
+    fn reply(pairs: Vec<(&'static str, BValue)>) -> Vec<u8> {
+        dict(pairs).encode()
+    }
+
+    #[test]
+    fn replies() {
+        let ok = reply(vec![("interval", BValue::BInt(1800)), ("peers", BValue::BString(vec![127, 0, 0, 1, 0x1a, 0xe1]))]);
+        let announcement = parse(&ok).unwrap();
+        assert_eq!(announcement.interval(), 1800);
+        assert_eq!(announcement.peers(), &[(Ipv4Addr::new(127, 0, 0, 1), 6881)]);
+        let refused = reply(vec![("failure reason", BValue::from("unregistered torrent"))]);
+        match parse(&refused) {
+            Err(Error::TrackerReason(ref reason)) if reason == "unregistered torrent" => (),
+            other => panic!("expected a failure reason, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn announces_until_one_answers() {
+        let refusing = serve(reply(vec![("failure reason", BValue::from("go away"))]));
+        let garbled = serve(b"not bencode".to_vec());
+        let working = serve(reply(vec![("interval", BValue::BInt(60)), ("peers", BValue::BString(vec![10, 0, 0, 1, 0, 80]))]));
+        let bs = torrent(vec![vec![refusing.to_string(), working.to_string(), garbled.to_string()]]);
+        let mi = metainfo::parse(&bs).unwrap();
+        let mut announcer = Announcer::new(&mi);
+        let (url, announcement) = announcer.announce().unwrap();
+        assert_eq!(url, working);
+        assert_eq!(announcement.peers(), &[(Ipv4Addr::new(10, 0, 0, 1), 80)]);
+        // it's kept in front, so it's the only one asked next time
+        assert_eq!(announcer.trackers.tiers()[0][0], working);
+        assert_eq!(announcer.announce().unwrap().0, working);
+
+        let closed = hang_up();
+        let bs = torrent(vec![vec![refusing.to_string()], vec![garbled.to_string()], vec![closed.to_string()]]);
+        let mi = metainfo::parse(&bs).unwrap();
+        let failures = Announcer::new(&mi).announce().unwrap_err();
+        assert_eq!(failures.len(), 3);
+        assert_eq!(failures[0], (refusing, "tracker says: go away".to_string()));
+        assert_eq!(failures[1].0, garbled);
+        assert_eq!(failures[2], (closed, "connection closed without a reply".to_string()));
+    }
+
+    #[test]
+    fn only_http_is_announced_to() {
+        let tier = |url: &str| BValue::BList(vec![BValue::from(url)]);
+        let bs = dict(vec![
+            ("announce-list", BValue::BList(vec![tier("udp://a:80"), tier("https://b/ann")])),
+            ("info", dict(vec![("length", BValue::BInt(1)), ("name", BValue::from("x")),
+                               ("piece length", BValue::BInt(1)), ("pieces", BValue::BString(vec![7; 20]))])),
+        ]).encode();
+        let mi = metainfo::parse(&bs).unwrap();
+        for url in mi.tiers().iter().flat_map(|tier| tier.iter()) {
+            assert_eq!(announce_to(url, "", "", 1), Err(format!("unsupported scheme {}", url.scheme)));
+        }
+        let failures = Announcer::new(&mi).announce().unwrap_err();
+        assert_eq!(failures.iter().map(|f| f.1.clone()).collect::<Vec<_>>(),
+                   vec!["unsupported scheme udp".to_string(), "unsupported scheme https".to_string()]);
+    }
 }
@@ -0,0 +1,121 @@
+//! Tiers of trackers, tried in the order BEP 12 gives.
+//!
+//! Each tier is shuffled once when loaded. Trackers are tried tier by tier,
+//! in order within a tier, and one that works moves to the front of its
+//! tier so it's tried first next time.
+
+use std::iter;
+use std::slice;
+use rand;
+use rand::Rng;
+use url::Url;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tiers(Vec<Vec<Url>>);
+
+impl Tiers {
+    pub fn new(tiers: Vec<Vec<Url>>) -> Tiers {
+        Tiers::shuffled(tiers, &mut rand::thread_rng())
+    }
+
+    pub fn shuffled<R: Rng>(tiers: Vec<Vec<Url>>, rng: &mut R) -> Tiers {
+        let mut tiers: Vec<Vec<Url>> = tiers.into_iter().filter(|tier| !tier.is_empty()).collect();
+        for tier in &mut tiers {
+            rng.shuffle(tier);
+        }
+        Tiers(tiers)
+    }
+
+    pub fn tiers(&self) -> &[Vec<Url>] {
+        &self.0
+    }
+
+    /// Every tracker, in the order they'd be tried.
+    pub fn iter<'t>(&'t self) -> iter::FlatMap<slice::Iter<'t, Vec<Url>>, slice::Iter<'t, Url>, fn(&'t Vec<Url>) -> slice::Iter<'t, Url>> {
+        self.0.iter().flat_map(tier_iter)
+    }
+
+    /// Moves `url` to the front of its tier.
+    pub fn promote(&mut self, url: &Url) {
+        for tier in &mut self.0 {
+            if let Some(i) = tier.iter().position(|u| u == url) {
+                let working = tier.remove(i);
+                tier.insert(0, working);
+                return;
+            }
+        }
+    }
+
+    /// Tries trackers in order until `announce` works for one, which is then
+    /// promoted. `None` if none of them did.
+    pub fn first_working<T, E, F>(&mut self, mut announce: F) -> Option<(Url, T)>
+        where F: FnMut(&Url) -> Result<T, E>
+    {
+        let found = self.iter()
+            .filter_map(|url| announce(url).ok().map(|t| (url.clone(), t)))
+            .next();
+        if let Some((ref url, _)) = found {
+            self.promote(url);
+        }
+        found
+    }
+}
+
+fn tier_iter(tier: &Vec<Url>) -> slice::Iter<Url> {
+    tier.iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{SeedableRng, XorShiftRng};
+    use url::Url;
+
+    fn urls(tier: &[&str]) -> Vec<Url> {
+        tier.iter().map(|u| Url::parse(u).unwrap()).collect()
+    }
+
+    fn sample() -> Vec<Vec<Url>> {
+        vec![
+            urls(&["http://a1/ann", "http://a2/ann", "http://a3/ann"]),
+            vec![],
+            urls(&["udp://b1:80", "udp://b2:80"]),
+        ]
+    }
+
+    #[test]
+    fn shuffles_within_tiers() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let tiers = Tiers::shuffled(sample(), &mut rng);
+        // empty tiers are dropped, and trackers never leave their tier
+        assert_eq!(tiers.tiers().len(), 2);
+        let mut first = tiers.tiers()[0].clone();
+        first.sort();
+        assert_eq!(first, sample()[0]);
+        let mut second = tiers.tiers()[1].clone();
+        second.sort();
+        assert_eq!(second, sample()[2]);
+    }
+
+    #[test]
+    fn walks_tiers_in_order_and_promotes() {
+        let mut tiers = Tiers(sample().into_iter().filter(|t| !t.is_empty()).collect());
+        let order: Vec<String> = tiers.iter().map(|u| u.to_string()).collect();
+        assert_eq!(order, vec!["http://a1/ann", "http://a2/ann", "http://a3/ann", "udp://b1:80", "udp://b2:80"]);
+
+        let mut tried = Vec::new();
+        let found = tiers.first_working(|url| {
+            tried.push(url.to_string());
+            if url.to_string() == "udp://b2:80" { Ok(42) } else { Err(()) }
+        });
+        assert_eq!(found, Some((Url::parse("udp://b2:80").unwrap(), 42)));
+        assert_eq!(tried.len(), 5);
+        assert_eq!(tiers.tiers()[1], urls(&["udp://b2:80", "udp://b1:80"]));
+        // the first tier is left alone
+        assert_eq!(tiers.tiers()[0], sample()[0]);
+
+        tiers.promote(&Url::parse("http://a3/ann").unwrap());
+        assert_eq!(tiers.tiers()[0], urls(&["http://a3/ann", "http://a1/ann", "http://a2/ann"]));
+        assert_eq!(tiers.first_working(|_| Err::<(), ()>(())), None);
+    }
+}
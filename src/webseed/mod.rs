@@ -0,0 +1,472 @@
+//! Downloading pieces from HTTP mirrors (web seeds, BEP 19).
+//!
+//! A piece is fetched with one Range request for each file it overlaps, then
+//! checked against its hash in `Info.pieces` before it's handed back.
+//! Padding files (BEP 47) aren't on mirrors, so their bytes are zeros that
+//! are never requested.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use httparse;
+use url;
+use url::Url;
+
+use metainfo::{Metainfo, Mode};
+use sha1bytes::SHA1Hash;
+
+static TIMEOUT_SECS: u64 = 30;
+static MAX_HEADERS: usize = 64;
+static MAX_HEAD_BYTES: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum WebSeedError {
+    NoSuchPiece(usize),
+    NoWebSeeds,
+    BadUrl(url::ParseError),
+    // only plain http for now
+    UnsupportedScheme(String),
+    Io(io::Error),
+    BadResponse(String),
+    BadStatus(u16),
+    WrongLength{ expected: u64, found: u64 },
+    HashMismatch(usize),
+}
+
+impl fmt::Display for WebSeedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WebSeedError::NoSuchPiece(i) => write!(f, "no piece {}", i),
+            WebSeedError::NoWebSeeds => write!(f, "no web seeds"),
+            WebSeedError::BadUrl(ref e) => write!(f, "bad url: {}", e),
+            WebSeedError::UnsupportedScheme(ref s) => write!(f, "unsupported scheme {}", s),
+            WebSeedError::Io(ref e) => write!(f, "{}", e),
+            WebSeedError::BadResponse(ref msg) => write!(f, "bad response: {}", msg),
+            WebSeedError::BadStatus(code) => write!(f, "got status {}", code),
+            WebSeedError::WrongLength{ expected, found } =>
+                write!(f, "expected {} bytes but got {}", expected, found),
+            WebSeedError::HashMismatch(i) => write!(f, "piece {} doesn't match its hash", i),
+        }
+    }
+}
+
+impl error::Error for WebSeedError {}
+
+impl From<io::Error> for WebSeedError {
+    fn from(e: io::Error) -> WebSeedError {
+        WebSeedError::Io(e)
+    }
+}
+
+/// Part of a piece: `length` bytes of the file at `url`, from `start`, that
+/// go at `begin` in the piece.
+#[derive(Debug, PartialEq)]
+pub struct Segment {
+    pub url: Url,
+    pub start: u64,
+    pub length: u64,
+    pub begin: u64,
+}
+
+/// Where a file is on a web seed. For a single-file torrent the seed is the
+/// file itself, unless it ends in `/`; otherwise it's the directory holding
+/// the torrent's top directory.
+pub fn file_url(seed: &Url, name: &str, path: &[&str]) -> Result<Url, WebSeedError> {
+    let mut s = seed.to_string();
+    if path.is_empty() && !s.ends_with('/') {
+        return Ok(seed.clone());
+    }
+    if !s.ends_with('/') {
+        s.push('/');
+    }
+    s.push_str(&escape(name));
+    for component in path {
+        s.push('/');
+        s.push_str(&escape(component));
+    }
+    Url::parse(&s).or_else(|e| Err(WebSeedError::BadUrl(e)))
+}
+
+// Everything but the unreserved characters, so names with `%`, `?` or `#` in
+// them stay one path segment.
+fn escape(component: &str) -> String {
+    component.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// The file ranges piece `index` is made of, in order, leaving out padding.
+pub fn segments(seed: &Url, mi: &Metainfo, index: usize) -> Result<Vec<Segment>, WebSeedError> {
+    let spans = mi.info.piece_spans(index).ok_or(WebSeedError::NoSuchPiece(index))?;
+    let mut out = Vec::new();
+    let mut begin = 0;
+    for span in spans {
+        let at = begin;
+        begin += span.length;
+        let url = match *mi.info.mode() {
+            Mode::Single{ ref attrs, .. } if attrs.padding => continue,
+            Mode::Single{ name, .. } => file_url(seed, name, &[])?,
+            Mode::Multi{ ref files, .. } if files[span.file].attrs().padding => continue,
+            Mode::Multi{ name, ref files } => {
+                // the names on the server, not the ones made safe to write
+                let path: Vec<&str> = files[span.file].components().iter().map(|c| &c[..]).collect();
+                file_url(seed, name, &path)?
+            },
+        };
+        out.push(Segment{ url: url, start: span.offset, length: span.length, begin: at });
+    }
+    Ok(out)
+}
+
+/// Downloads piece `index` from `seed` and checks its hash.
+pub fn fetch_piece(seed: &Url, mi: &Metainfo, index: usize) -> Result<Vec<u8>, WebSeedError> {
+    let size = mi.info.piece_size(index).ok_or(WebSeedError::NoSuchPiece(index))?;
+    // padding stays zeros
+    let mut piece = vec![0; size as usize];
+    for segment in segments(seed, mi, index)? {
+        let data = get_range(&segment.url, segment.start, segment.length)?;
+        let begin = segment.begin as usize;
+        piece[begin..begin + data.len()].copy_from_slice(&data);
+    }
+    match mi.info.pieces().get(index) {
+        Some(ref hash) if *hash == SHA1Hash::from_bytes(&piece) => Ok(piece),
+        _ => Err(WebSeedError::HashMismatch(index)),
+    }
+}
+
+/// Tries each of the torrent's web seeds in turn, returning the piece and
+/// the seed it came from, or the last error if none of them had it.
+pub fn fetch_piece_from_any(mi: &Metainfo, index: usize) -> Result<(Url, Vec<u8>), WebSeedError> {
+    let mut last = WebSeedError::NoWebSeeds;
+    for seed in &mi.url_list {
+        match fetch_piece(seed, mi, index) {
+            Ok(piece) => return Ok((seed.clone(), piece)),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+/// `length` bytes of `url` from `start`. Servers that ignore the Range and
+/// send the whole file are handled too.
+pub fn get_range(url: &Url, start: u64, length: u64) -> Result<Vec<u8>, WebSeedError> {
+    // there's no Range for nothing
+    if length == 0 {
+        return Ok(Vec::new());
+    }
+    if url.scheme != "http" {
+        return Err(WebSeedError::UnsupportedScheme(url.scheme.clone()));
+    }
+    let host = url.serialize_host().unwrap_or_default();
+    let port = url.port_or_default().unwrap_or(80);
+    let mut target = url.serialize_path().unwrap_or_else(|| "/".to_string());
+    if let Some(ref query) = url.query {
+        target.push('?');
+        target.push_str(query);
+    }
+
+    let timeout = Some(Duration::from_secs(TIMEOUT_SECS));
+    let mut stream = TcpStream::connect((host.trim_matches(|c| c == '[' || c == ']'), port))?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}:{}\r\nRange: bytes={}-{}\r\nConnection: close\r\n\r\n",
+           target, host, port, start, start + length - 1)?;
+    // just the headers first, to know how much of the body to read
+    let mut response = Vec::new();
+    let mut buf = [0; 4096];
+    let (code, chunked, body_at) = loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(WebSeedError::BadResponse("headers cut short".to_string()));
+        }
+        response.extend_from_slice(&buf[..n]);
+        let mut headers = vec![httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut head = httparse::Response::new(&mut headers);
+        match head.parse(&response) {
+            Ok(httparse::Status::Complete(n)) => {
+                let chunked = head.headers.iter().any(|h| {
+                    h.name.eq_ignore_ascii_case("transfer-encoding") && h.value.eq_ignore_ascii_case(b"chunked")
+                });
+                break (head.code, chunked, n);
+            },
+            Ok(httparse::Status::Partial) if response.len() > MAX_HEAD_BYTES =>
+                return Err(WebSeedError::BadResponse("headers too long".to_string())),
+            Ok(httparse::Status::Partial) => (),
+            Err(e) => return Err(WebSeedError::BadResponse(format!("{:?}", e))),
+        }
+    };
+    if chunked {
+        return Err(WebSeedError::BadResponse("chunked bodies aren't supported".to_string()));
+    }
+
+    // A 200 is the whole file, so stop once the range has arrived. One byte
+    // past a 206 is enough to tell it's too long.
+    let wanted = match code {
+        Some(206) => length + 1,
+        Some(200) => start + length,
+        Some(code) => return Err(WebSeedError::BadStatus(code)),
+        None => return Err(WebSeedError::BadResponse("no status".to_string())),
+    };
+    let mut body = response.split_off(body_at);
+    let have = body.len() as u64;
+    if have < wanted {
+        (&mut stream).take(wanted - have).read_to_end(&mut body)?;
+    }
+    body.truncate(wanted as usize);
+
+    let found = match code {
+        Some(206) => &body[..],
+        _ if (body.len() as u64) >= start => &body[start as usize..],
+        _ => &[],
+    };
+    if (found.len() as u64) < length || code == Some(206) && found.len() as u64 != length {
+        return Err(WebSeedError::WrongLength{ expected: length, found: found.len() as u64 });
+    }
+    Ok(found[..length as usize].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::str;
+    use std::thread;
+    use bencode::{BValue, Encode};
+    use bencode::arbitrary::dict;
+    use crypto::digest::Digest;
+    use crypto::sha1::Sha1;
+    use metainfo;
+
+    // Serves `files` by path until the test exits, honoring Range unless
+    // `ranges` is false.
+    fn serve(files: HashMap<String, Vec<u8>>, ranges: bool) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let mut headers = [httparse::EMPTY_HEADER; 16];
+                let mut req = httparse::Request::new(&mut headers);
+                req.parse(&request).unwrap();
+                let range = req.headers.iter()
+                    .find(|h| h.name == "Range")
+                    .map(|h| str::from_utf8(h.value).unwrap().trim_left_matches("bytes=").to_string());
+                let response = match files.get(req.path.unwrap()) {
+                    Some(body) if ranges => {
+                        let range = range.unwrap();
+                        let mut ends = range.split('-').map(|n| n.parse::<usize>().unwrap());
+                        let (from, to) = (ends.next().unwrap(), ends.next().unwrap());
+                        let mut r = format!("HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\n", to + 1 - from).into_bytes();
+                        r.extend_from_slice(&body[from..to + 1]);
+                        r
+                    },
+                    Some(body) => {
+                        let mut r = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+                        r.extend_from_slice(body);
+                        r
+                    },
+                    None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+        Url::parse(&format!("http://127.0.0.1:{}/seed/", port)).unwrap()
+    }
+
+    fn pieces(contents: &[u8], piece_length: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for piece in contents.chunks(piece_length) {
+            let mut hasher = Sha1::new();
+            hasher.input(piece);
+            let mut hash = vec![0; 20];
+            hasher.result(&mut hash);
+            out.extend(hash);
+        }
+        out
+    }
+
+    // "abcdefghij" split into files of 3, 0 and 7 bytes, in pieces of 4.
+    fn multi_torrent(seeds: &[&Url]) -> Vec<u8> {
+        let file = |length, path: &[&str]| dict(vec![
+            ("length", BValue::BInt(length)),
+            ("path", BValue::BList(path.iter().map(|&c| BValue::from(c)).collect())),
+        ]);
+        dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("info", dict(vec![
+                ("files", BValue::BList(vec![file(3, &["a"]), file(0, &["empty"]), file(7, &["sub", "b c"])])),
+                ("name", BValue::from("dir")),
+                ("piece length", BValue::BInt(4)),
+                ("pieces", BValue::BString(pieces(b"abcdefghij", 4))),
+            ])),
+            ("url-list", BValue::BList(seeds.iter().map(|s| BValue::from(s.to_string())).collect())),
+        ]).encode()
+    }
+
+    fn multi_files() -> HashMap<String, Vec<u8>> {
+        let mut files = HashMap::new();
+        files.insert("/seed/dir/a".to_string(), b"abc".to_vec());
+        files.insert("/seed/dir/empty".to_string(), Vec::new());
+        files.insert("/seed/dir/sub/b%20c".to_string(), b"defghij".to_vec());
+        files
+    }
+
+    #[test]
+    fn file_urls() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert_eq!(file_url(&url("http://m/x.iso"), "x", &[]).unwrap(), url("http://m/x.iso"));
+        assert_eq!(file_url(&url("http://m/pub/"), "x.iso", &[]).unwrap(), url("http://m/pub/x.iso"));
+        assert_eq!(file_url(&url("http://m/pub"), "dir", &["a b", "100%?#"]).unwrap(),
+                   url("http://m/pub/dir/a%20b/100%25%3F%23"));
+    }
+
+    #[test]
+    fn segments_across_files() {
+        let seed = Url::parse("http://m/").unwrap();
+        let bs = multi_torrent(&[&seed]);
+        let mi = metainfo::parse(&bs).unwrap();
+        let spans = |i| segments(&seed, &mi, i).unwrap().into_iter()
+            .map(|s| (s.url.to_string(), s.start, s.length, s.begin))
+            .collect::<Vec<_>>();
+        assert_eq!(spans(0), vec![("http://m/dir/a".to_string(), 0, 3, 0), ("http://m/dir/sub/b%20c".to_string(), 0, 1, 3)]);
+        assert_eq!(spans(1), vec![("http://m/dir/sub/b%20c".to_string(), 1, 4, 0)]);
+        // the last piece is short
+        assert_eq!(spans(2), vec![("http://m/dir/sub/b%20c".to_string(), 5, 2, 0)]);
+        match segments(&seed, &mi, 3) {
+            Err(WebSeedError::NoSuchPiece(3)) => (),
+            other => panic!("expected no such piece, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fetch_multi_file() {
+        let seed = serve(multi_files(), true);
+        let bs = multi_torrent(&[&seed]);
+        let mi = metainfo::parse(&bs).unwrap();
+        let got: Vec<Vec<u8>> = (0..3).map(|i| fetch_piece(&seed, &mi, i).unwrap()).collect();
+        assert_eq!(got, vec![b"abcd".to_vec(), b"efgh".to_vec(), b"ij".to_vec()]);
+    }
+
+    #[test]
+    fn padding_not_requested() {
+        // "abc", padded to a piece, then "defg"; the mirror has no padding
+        let mut files = HashMap::new();
+        files.insert("/seed/dir/a".to_string(), b"abc".to_vec());
+        files.insert("/seed/dir/b".to_string(), b"defg".to_vec());
+        let seed = serve(files, true);
+        let file = |length, path: &[&str], attrs: Vec<(&'static str, BValue)>| {
+            let mut pairs = vec![
+                ("length", BValue::BInt(length)),
+                ("path", BValue::BList(path.iter().map(|&c| BValue::from(c)).collect())),
+            ];
+            pairs.extend(attrs);
+            dict(pairs)
+        };
+        let bs = dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("info", dict(vec![
+                ("files", BValue::BList(vec![
+                    file(3, &["a"], vec![]),
+                    file(1, &[".pad", "1"], vec![("attr", BValue::from("p"))]),
+                    file(4, &["b"], vec![]),
+                ])),
+                ("name", BValue::from("dir")),
+                ("piece length", BValue::BInt(4)),
+                ("pieces", BValue::BString(pieces(b"abc\0defg", 4))),
+            ])),
+            ("url-list", BValue::from(seed.to_string())),
+        ]).encode();
+        let mi = metainfo::parse(&bs).unwrap();
+        let spans = segments(&seed, &mi, 0).unwrap().into_iter()
+            .map(|s| (s.url.path().unwrap().join("/"), s.start, s.length, s.begin))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![("seed/dir/a".to_string(), 0, 3, 0)]);
+        assert_eq!(fetch_piece(&seed, &mi, 0).unwrap(), b"abc\0".to_vec());
+        assert_eq!(fetch_piece(&seed, &mi, 1).unwrap(), b"defg".to_vec());
+    }
+
+    #[test]
+    fn fetch_single_file_without_ranges() {
+        let mut files = HashMap::new();
+        files.insert("/seed/x.bin".to_string(), b"0123456789".to_vec());
+        let seed = serve(files, false);
+        let bs = dict(vec![
+            ("announce", BValue::from("http://a/ann")),
+            ("info", dict(vec![
+                ("length", BValue::BInt(10)),
+                ("name", BValue::from("x.bin")),
+                ("piece length", BValue::BInt(4)),
+                ("pieces", BValue::BString(pieces(b"0123456789", 4))),
+            ])),
+            ("url-list", BValue::from(seed.to_string())),
+        ]).encode();
+        let mi = metainfo::parse(&bs).unwrap();
+        assert_eq!(fetch_piece(&seed, &mi, 1).unwrap(), b"4567".to_vec());
+        assert_eq!(fetch_piece(&seed, &mi, 2).unwrap(), b"89".to_vec());
+    }
+
+    #[test]
+    fn endless_body() {
+        // ignores Range and never stops sending
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut stream = listener.incoming().next().unwrap().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n");
+            let junk = [b'x'; 4096];
+            while stream.write_all(&junk).is_ok() {}
+        });
+        let url = Url::parse(&format!("http://127.0.0.1:{}/big", port)).unwrap();
+        assert_eq!(get_range(&url, 5000, 3).unwrap(), b"xxx".to_vec());
+    }
+
+    #[test]
+    fn empty_range() {
+        // nothing is listening, so this only passes without a request
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://127.0.0.1:{}/x", listener.local_addr().unwrap().port())).unwrap();
+        drop(listener);
+        assert_eq!(get_range(&url, 0, 0).unwrap(), Vec::<u8>::new());
+        assert_eq!(get_range(&url, 7, 0).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn bad_mirrors() {
+        let mut files = multi_files();
+        files.insert("/seed/dir/a".to_string(), b"abX".to_vec());
+        let corrupt = serve(files, true);
+        let missing = serve(HashMap::new(), true);
+        let good = serve(multi_files(), true);
+
+        let bs = multi_torrent(&[&corrupt, &missing]);
+        let mi = metainfo::parse(&bs).unwrap();
+        match fetch_piece(&corrupt, &mi, 0) {
+            Err(WebSeedError::HashMismatch(0)) => (),
+            other => panic!("expected a hash mismatch, got {:?}", other),
+        }
+        // the second piece doesn't touch the bad file
+        assert!(fetch_piece(&corrupt, &mi, 1).is_ok());
+        match fetch_piece_from_any(&mi, 0) {
+            Err(WebSeedError::BadStatus(404)) => (),
+            other => panic!("expected a 404, got {:?}", other),
+        }
+
+        let bs = multi_torrent(&[&corrupt, &missing, &good]);
+        let mi = metainfo::parse(&bs).unwrap();
+        assert_eq!(fetch_piece_from_any(&mi, 0).unwrap(), (good, b"abcd".to_vec()));
+    }
+}